@@ -0,0 +1,63 @@
+//! Measures the work `BlockSubscriber` does for every new head: pushing it to
+//! the replay ring, updating the latest head watch and broadcasting it to
+//! every subscriber. Run with `cargo bench -p block-subscriber`.
+
+use block_subscriber::HeadBroadcaster;
+use offchain_core::ethers::types::{Bloom, H256};
+use offchain_core::types::Block;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// At most the broadcast channel capacity, so no subscriber lags behind.
+const BLOCKS: usize = 1024;
+const RECEIVERS: [usize; 4] = [1, 16, 64, 256];
+const REPLAY_CAPACITIES: [usize; 3] = [0, 64, 1024];
+
+fn new_head(number: u64) -> Block {
+    Block {
+        hash: H256::from_low_u64_be(number),
+        number: number.into(),
+        parent_hash: H256::from_low_u64_be(number.saturating_sub(1)),
+        timestamp: number.into(),
+        logs_bloom: Bloom::repeat_byte(0xff),
+    }
+}
+
+/// Publishes `BLOCKS` heads to `receivers` subscribers, which read all of
+/// them.
+fn run(receivers: usize, replay_capacity: usize) -> Duration {
+    let heads = HeadBroadcaster::new(replay_capacity);
+    let mut rxs: Vec<_> =
+        (0..receivers).map(|_| heads.subscribe().unwrap()).collect();
+    let blocks: Vec<_> = (0..BLOCKS as u64).map(new_head).collect();
+
+    let start = Instant::now();
+    for block in blocks {
+        assert!(heads.publish(Arc::new(block)));
+    }
+    for rx in rxs.iter_mut() {
+        while rx.try_recv().is_ok() {}
+    }
+    start.elapsed()
+}
+
+fn main() {
+    print!("{:>10}", "receivers");
+    for replay_capacity in REPLAY_CAPACITIES.iter() {
+        print!(" {:>14}", format!("replay {}", replay_capacity));
+    }
+    println!("  (ns per head)");
+
+    // Warm up the allocator.
+    run(RECEIVERS[0], REPLAY_CAPACITIES[0]);
+
+    for &receivers in RECEIVERS.iter() {
+        print!("{:>10}", receivers);
+        for &replay_capacity in REPLAY_CAPACITIES.iter() {
+            let elapsed = run(receivers, replay_capacity);
+            print!(" {:>14}", elapsed.as_nanos() / BLOCKS as u128);
+        }
+        println!();
+    }
+}
@@ -1,20 +1,73 @@
+use crate::config::BSConfig;
 use crate::error::*;
-use middleware_factory::MiddlewareFactory;
+use crate::finality::FinalityTracker;
+use crate::heads::HeadBroadcaster;
+use crate::replay::ReplaySubscription;
+use middleware_factory::{MiddlewareFactory, Versioned};
+use offchain_core::ethers::types::U64;
 use offchain_core::types::Block;
 
+use arc_swap::ArcSwapOption;
 use async_trait::async_trait;
+use log::warn;
 use offchain_core::ethers::providers::{Middleware, PubsubClient};
-use snafu::ResultExt;
+use snafu::{OptionExt, ResultExt};
 use std::convert::TryInto;
 use std::sync::Arc;
-use tokio::sync::{broadcast, oneshot, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot, watch};
 use tokio_stream::{Stream, StreamExt};
 
 /// NewBlockSubscriber is an object responsible for listening to new block
 /// events from the blockchain and broadcasting them to whoever has subscribed.
+/// Blocks are shared between subscribers, instead of cloned for each of them.
 #[async_trait]
 pub trait NewBlockSubscriber {
-    async fn subscribe(&self) -> Option<broadcast::Receiver<Block>>;
+    async fn subscribe(&self) -> Option<broadcast::Receiver<Arc<Block>>>;
+
+    /// Subscribes to chain events, which include new heads and changes in
+    /// the health of the chain itself (as opposed to the connection).
+    async fn subscribe_events(&self)
+        -> Option<broadcast::Receiver<BlockEvent>>;
+
+    /// Watches the latest finalized block.
+    async fn subscribe_finalized(
+        &self,
+    ) -> Option<watch::Receiver<Option<Arc<Block>>>>;
+
+    /// Watches the latest head.
+    async fn latest(&self) -> Option<watch::Receiver<Option<Arc<Block>>>>;
+
+    /// Same as `subscribe`, first replaying up to `replay` of the most recent
+    /// blocks.
+    async fn subscribe_with_replay(
+        &self,
+        replay: usize,
+    ) -> Option<ReplaySubscription>;
+}
+
+/// Events broadcast by a `NewBlockSubscriber`.
+#[derive(Clone, Debug)]
+pub enum BlockEvent {
+    /// A new head was received from the subscription.
+    NewHead(Arc<Block>),
+
+    /// The node is reachable and the subscription is alive, but no new block
+    /// has been mined for longer than expected. Sent once per stall.
+    ChainStalled {
+        /// Last head received, if any.
+        last_block: Option<Arc<Block>>,
+        /// Head reported by the node through `eth_blockNumber`.
+        block_number: U64,
+        /// Time elapsed since the last new head.
+        elapsed: Duration,
+    },
+
+    /// The `safe` head changed.
+    Safe(Arc<Block>),
+
+    /// The `finalized` head changed.
+    Finalized(Arc<Block>),
 }
 
 pub struct BlockSubscriberHandle<M: Middleware + 'static> {
@@ -22,6 +75,9 @@ pub struct BlockSubscriberHandle<M: Middleware + 'static> {
     pub kill_switch: oneshot::Sender<()>,
 }
 
+type HandleOf<MF> =
+    BlockSubscriberHandle<<MF as MiddlewareFactory>::Middleware>;
+
 pub struct BlockSubscriber<MF>
 where
     MF: MiddlewareFactory,
@@ -29,10 +85,14 @@ where
         PubsubClient,
 {
     factory: Arc<MF>,
-    subscriber_timeout: std::time::Duration,
+    subscriber_timeout: Duration,
     max_retries: usize,
-    max_delay: std::time::Duration,
-    channel: Mutex<Option<broadcast::Sender<Block>>>,
+    max_delay: Duration,
+    stall_timeout: Duration,
+    heads: HeadBroadcaster,
+    // Loaded on every new head, so it must not be behind a lock.
+    events: ArcSwapOption<broadcast::Sender<BlockEvent>>,
+    finalized: watch::Receiver<Option<Arc<Block>>>,
 }
 
 impl<MF> BlockSubscriber<MF>
@@ -47,24 +107,65 @@ where
     /// will cause the `BlockSubscriber` to terminate.
     pub fn create_and_start(
         factory: Arc<MF>,
-        subscriber_timeout: std::time::Duration,
+        subscriber_timeout: Duration,
         max_retries: usize,
-        max_delay: std::time::Duration,
+        max_delay: Duration,
+    ) -> (
+        Arc<Self>,
+        BlockSubscriberHandle<<MF as MiddlewareFactory>::Middleware>,
+    ) {
+        let config = BSConfig {
+            subscriber_timeout,
+            max_retries,
+            max_delay,
+            ..Default::default()
+        };
+        let stall_timeout = config.block_time * config.stall_blocks;
+
+        Self::create(factory, &config, stall_timeout)
+    }
+
+    /// Same as `create_and_start`, taking every parameter from a `BSConfig`.
+    /// Fails if the stall timeout of `config` overflows.
+    pub fn create_and_start_with_config(
+        factory: Arc<MF>,
+        config: &BSConfig,
+    ) -> Result<(Arc<Self>, HandleOf<MF>), <MF as MiddlewareFactory>::Middleware>
+    {
+        let stall_timeout = config.stall_timeout().context(
+            StallTimeoutOverflow {
+                block_time: config.block_time,
+                stall_blocks: config.stall_blocks,
+            },
+        )?;
+
+        Ok(Self::create(factory, config, stall_timeout))
+    }
+
+    fn create(
+        factory: Arc<MF>,
+        config: &BSConfig,
+        stall_timeout: Duration,
     ) -> (
         Arc<Self>,
         BlockSubscriberHandle<<MF as MiddlewareFactory>::Middleware>,
     ) {
         let (kill_tx, kill_rx) = oneshot::channel();
 
-        let (tx, _) = broadcast::channel(1024);
+        let (events_tx, _) = broadcast::channel(1024);
+        let (finality, finalized_rx) =
+            FinalityTracker::new(config.safe_depth, config.finality_depth);
         let this = Arc::new(BlockSubscriber {
             factory,
-            subscriber_timeout,
-            max_retries,
-            max_delay,
-            channel: Mutex::new(Some(tx)),
+            subscriber_timeout: config.subscriber_timeout,
+            max_retries: config.max_retries,
+            max_delay: config.max_delay,
+            stall_timeout,
+            heads: HeadBroadcaster::new(config.replay_capacity),
+            events: ArcSwapOption::from_pointee(events_tx),
+            finalized: finalized_rx,
         });
-        let handle = BlockSubscriber::start(Arc::clone(&this), kill_rx);
+        let handle = BlockSubscriber::start(Arc::clone(&this), kill_rx, finality);
 
         (
             this,
@@ -83,11 +184,31 @@ where
     <<MF as MiddlewareFactory>::Middleware as Middleware>::Provider:
         PubsubClient + Send,
 {
-    async fn subscribe(&self) -> Option<broadcast::Receiver<Block>> {
-        match &*self.channel.lock().await {
-            Some(channel) => Some(channel.subscribe()),
-            None => None,
-        }
+    async fn subscribe(&self) -> Option<broadcast::Receiver<Arc<Block>>> {
+        self.heads.subscribe()
+    }
+
+    async fn subscribe_events(
+        &self,
+    ) -> Option<broadcast::Receiver<BlockEvent>> {
+        self.events.load().as_ref().map(|events| events.subscribe())
+    }
+
+    async fn subscribe_finalized(
+        &self,
+    ) -> Option<watch::Receiver<Option<Arc<Block>>>> {
+        Some(self.finalized.clone()).filter(|_| !self.heads.is_closed())
+    }
+
+    async fn latest(&self) -> Option<watch::Receiver<Option<Arc<Block>>>> {
+        self.heads.latest()
+    }
+
+    async fn subscribe_with_replay(
+        &self,
+        replay: usize,
+    ) -> Option<ReplaySubscription> {
+        self.heads.subscribe_with_replay(replay)
     }
 }
 
@@ -103,6 +224,7 @@ where
     fn start(
         self: Arc<Self>,
         kill_switch: oneshot::Receiver<()>,
+        finality: FinalityTracker,
     ) -> tokio::task::JoinHandle<
         Result<(), <MF as MiddlewareFactory>::Middleware>,
     > {
@@ -110,60 +232,46 @@ where
         tokio::spawn(async move {
             // Create future future of `background_process` main loop. This
             // future will run against the kill_switch.
-            let task = self.background_process();
+            let task = self.background_process(finality);
             tokio::pin!(task);
 
-            tokio::select! {
-                res = &mut task => {
-                    let mut channel = self.channel.lock().await;
-                    *channel = None;
-                    return res
-                },
-
-                _ = kill_switch => {
-                    let mut channel = self.channel.lock().await;
-                    *channel = None;
-                    return Ok(())
-                }
-            }
+            let res = tokio::select! {
+                res = &mut task => res,
+                _ = kill_switch => Ok(()),
+            };
+
+            self.heads.close();
+            self.events.store(None);
+            res
         })
     }
 
     async fn background_process(
         &self,
+        mut finality: FinalityTracker,
     ) -> Result<(), <MF as MiddlewareFactory>::Middleware> {
         let mut middleware = self.new_middleware(None).await?;
 
         // Loop and retry on error.
         loop {
             middleware = self.new_middleware(Some(&middleware)).await?;
+            finality.reset();
 
             // Subscribe to new blocks, retrying if it fails.
             let mut backoff =
                 backoff::Backoff::new(self.max_retries, self.max_delay);
             let subscription = loop {
                 let res = middleware
+                    .middleware
                     .subscribe_blocks()
                     .await
                     .context(EthersProviderError)
                     .map(|subscription| {
-                        Box::pin(
-                            subscription.timeout(self.subscriber_timeout).map(
-                                |x| {
-                                    let block_header = x
-                                        .map_err(|e| e.into())
-                                        .context(NewBlockSubscriberTimeout)?;
-
-                                    let block = block_header
-                                        .try_into()
-                                        .map_err(|err| {
-                                            BlockIncomplete { err }.build()
-                                        })?;
-
-                                    Ok(block)
-                                },
-                            ),
-                        )
+                        Box::pin(subscription.map(|block_header| {
+                            block_header.try_into().map_err(|err| {
+                                BlockIncomplete { err }.build()
+                            })
+                        }))
                     });
 
                 match res {
@@ -186,7 +294,13 @@ where
             };
 
             // Main loop. Retry on error.
-            let res = self.listen_and_broadcast(subscription).await;
+            let res = self
+                .listen_and_broadcast(
+                    &middleware.middleware,
+                    subscription,
+                    &mut finality,
+                )
+                .await;
             match res {
                 // The channel was dropped, break from loop.
                 Ok(()) => return Ok(()),
@@ -200,35 +314,134 @@ where
 
     async fn listen_and_broadcast(
         &self,
+        middleware: &<MF as MiddlewareFactory>::Middleware,
         mut subscription: impl Stream<Item = Result<Block, <MF as MiddlewareFactory>::Middleware>>
             + Send
             + Unpin,
+        finality: &mut FinalityTracker,
     ) -> Result<(), <MF as MiddlewareFactory>::Middleware> {
+        let mut last_block: Option<Arc<Block>> = None;
+        let mut last_number: Option<U64> = None;
+        let mut last_head_at = Instant::now();
+        let mut stalled = false;
+
         // Listen to new blocks and notify subscribers.
         loop {
-            // Block on waiting for new block.
-            let new_head = subscription
-                .next()
-                .await
-                .ok_or(snafu::NoneError)
-                .context(SubscriptionDropped)??;
+            // Block on waiting for new block. A timeout alone doesn't mean the
+            // connection is dead, the chain may just be slow.
+            let next =
+                tokio::time::timeout(self.subscriber_timeout, subscription.next())
+                    .await;
+
+            let new_head = match next {
+                Ok(item) => item
+                    .ok_or(snafu::NoneError)
+                    .context(SubscriptionDropped)??,
+
+                Err(_) => {
+                    let block_number = self
+                        .check_subscription(middleware, &mut last_number)
+                        .await?;
+
+                    let elapsed = last_head_at.elapsed();
+                    if !stalled && elapsed >= self.stall_timeout {
+                        stalled = true;
+                        let event = BlockEvent::ChainStalled {
+                            last_block: last_block.clone(),
+                            block_number,
+                            elapsed,
+                        };
+                        if !self.send_event(event) {
+                            return Ok(());
+                        }
+                    }
+
+                    continue;
+                }
+            };
+
+            let new_head = Arc::new(new_head);
+            last_number = Some(new_head.number);
+            last_block = Some(Arc::clone(&new_head));
+            last_head_at = Instant::now();
+            stalled = false;
+
+            if !self.send_event(BlockEvent::NewHead(Arc::clone(&new_head))) {
+                return Ok(());
+            }
 
             // Send new block to subscribers.
-            let res = match &*self.channel.lock().await {
-                Some(channel) => channel.send(new_head),
-                None => return Ok(()), // Channel dropped by kill_switch,
+            if !self.heads.publish(Arc::clone(&new_head)) {
+                return Ok(()); // Channel dropped by kill_switch,
+            }
+
+            // Failing to fetch the safe and finalized heads doesn't affect
+            // the subscription, they are fetched again on the next head.
+            let events = match finality.update(middleware, &new_head).await {
+                Ok(events) => events,
+                Err(err) => {
+                    warn!("Failed to update the safe and finalized heads: {}", err);
+                    Vec::new()
+                }
             };
-            if let Err(_) = res {
-                // TODO: warn there are no subscribers.
+            for event in events {
+                if !self.send_event(event) {
+                    return Ok(());
+                }
             }
         }
     }
 
+    /// Cross-checks the subscription against `eth_blockNumber`, sent as a
+    /// regular request. If the request fails the connection is dead, and if
+    /// the node is more than one block ahead of the subscription the
+    /// subscription is stale; both cases are errors that trigger a
+    /// reconnection. A single block ahead may just be a head still on its
+    /// way. Otherwise, the chain is just slow and the node head is returned.
+    async fn check_subscription(
+        &self,
+        middleware: &<MF as MiddlewareFactory>::Middleware,
+        last_number: &mut Option<U64>,
+    ) -> Result<U64, <MF as MiddlewareFactory>::Middleware> {
+        let block_number = middleware
+            .get_block_number()
+            .await
+            .context(EthersProviderError)?;
+
+        match *last_number {
+            Some(last) if block_number > last + 1 => SubscriptionStale {
+                last_received: last,
+                node_head: block_number,
+            }
+            .fail(),
+
+            Some(_) => Ok(block_number),
+
+            // Nothing received yet; the node head becomes the reference.
+            None => {
+                *last_number = Some(block_number);
+                Ok(block_number)
+            }
+        }
+    }
+
+    /// Returns false if the channel was dropped by the kill_switch.
+    fn send_event(&self, event: BlockEvent) -> bool {
+        match &*self.events.load() {
+            Some(events) => {
+                // Not having subscribers is not an error.
+                let _ = events.send(event);
+                true
+            }
+            None => false,
+        }
+    }
+
     async fn new_middleware(
         &self,
-        previous: Option<&<MF as MiddlewareFactory>::Middleware>,
+        previous: Option<&Versioned<<MF as MiddlewareFactory>::Middleware>>,
     ) -> Result<
-        <MF as MiddlewareFactory>::Middleware,
+        Versioned<<MF as MiddlewareFactory>::Middleware>,
         <MF as MiddlewareFactory>::Middleware,
     > {
         self.factory
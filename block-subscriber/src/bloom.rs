@@ -0,0 +1,147 @@
+use crate::block_subscriber::{BlockEvent, NewBlockSubscriber};
+use crate::replay::ReplaySubscription;
+use offchain_core::bloom::BloomFilterSpec;
+use offchain_core::types::Block;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch};
+
+/// BloomFilteredSubscriber wraps a `NewBlockSubscriber`, only forwarding the
+/// heads whose logs bloom may match its `BloomFilterSpec`. Blocks that are not
+/// forwarded certainly have no relevant logs, so consumers can skip
+/// `eth_getLogs` for them.
+///
+/// Events, the latest head and the finalized head are passed through
+/// unfiltered.
+pub struct BloomFilteredSubscriber<S> {
+    inner: Arc<S>,
+    spec: Arc<BloomFilterSpec>,
+}
+
+impl<S> BloomFilteredSubscriber<S>
+where
+    S: NewBlockSubscriber + Send + Sync,
+{
+    pub fn new(inner: Arc<S>, spec: BloomFilterSpec) -> Self {
+        Self {
+            inner,
+            spec: Arc::new(spec),
+        }
+    }
+
+    /// Each subscription is filtered by its own background task, which
+    /// terminates with either the inner subscription or the returned
+    /// receiver.
+    fn filter(
+        &self,
+        subscription: broadcast::Receiver<Arc<Block>>,
+    ) -> broadcast::Receiver<Arc<Block>> {
+        let (tx, rx) = broadcast::channel(1024);
+        tokio::spawn(forward(subscription, tx, Arc::clone(&self.spec)));
+        rx
+    }
+}
+
+#[async_trait]
+impl<S> NewBlockSubscriber for BloomFilteredSubscriber<S>
+where
+    S: NewBlockSubscriber + Send + Sync,
+{
+    async fn subscribe(&self) -> Option<broadcast::Receiver<Arc<Block>>> {
+        let subscription = self.inner.subscribe().await?;
+        Some(self.filter(subscription))
+    }
+
+    async fn subscribe_events(
+        &self,
+    ) -> Option<broadcast::Receiver<BlockEvent>> {
+        self.inner.subscribe_events().await
+    }
+
+    async fn subscribe_finalized(
+        &self,
+    ) -> Option<watch::Receiver<Option<Arc<Block>>>> {
+        self.inner.subscribe_finalized().await
+    }
+
+    async fn latest(&self) -> Option<watch::Receiver<Option<Arc<Block>>>> {
+        self.inner.latest().await
+    }
+
+    async fn subscribe_with_replay(
+        &self,
+        replay: usize,
+    ) -> Option<ReplaySubscription> {
+        let (subscription, replay) =
+            self.inner.subscribe_with_replay(replay).await?.into_parts();
+
+        let replay = replay
+            .into_iter()
+            .filter(|block| block.may_match(&self.spec))
+            .collect();
+
+        Some(ReplaySubscription::new(self.filter(subscription), replay))
+    }
+}
+
+/// Forwards the heads of `subscription` that may match `spec` to `channel`.
+async fn forward(
+    mut subscription: broadcast::Receiver<Arc<Block>>,
+    channel: broadcast::Sender<Arc<Block>>,
+    spec: Arc<BloomFilterSpec>,
+) {
+    loop {
+        let block = match subscription.recv().await {
+            Ok(block) => block,
+            // TODO: warn that heads were lost.
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+
+        if block.may_match(&spec) && channel.send(block).is_err() {
+            // Every subscriber was dropped.
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_block, FakeSubscriber};
+    use offchain_core::ethers::abi::ethereum_types::BloomInput;
+    use offchain_core::ethers::types::{Address, H256};
+
+    #[tokio::test]
+    async fn filter_test() {
+        let address = Address::from_low_u64_be(1);
+        let topic = H256::from_low_u64_be(2);
+
+        let inner = Arc::new(FakeSubscriber::new());
+        let spec =
+            BloomFilterSpec::new().with_event_signature(Some(address), topic);
+        let subscriber = BloomFilteredSubscriber::new(Arc::clone(&inner), spec);
+        let mut subscription = subscriber.subscribe().await.unwrap();
+
+        let mut relevant = new_block(11, 110);
+        relevant
+            .logs_bloom
+            .accrue(BloomInput::Raw(address.as_bytes()));
+        relevant
+            .logs_bloom
+            .accrue(BloomInput::Raw(topic.as_bytes()));
+
+        inner.channel.send(Arc::new(new_block(10, 100))).unwrap();
+        inner.channel.send(Arc::new(relevant)).unwrap();
+        inner.channel.send(Arc::new(new_block(12, 120))).unwrap();
+
+        let block = subscription.recv().await.unwrap();
+        assert_eq!(block.number, 11.into());
+
+        drop(subscriber);
+        drop(inner);
+        assert!(subscription.recv().await.is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::block_subscriber::NewBlockSubscriber;
+use crate::replay::ReplaySubscription;
+use offchain_core::ethers::types::{U256, U64};
+use offchain_core::types::Block;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+/// Number of recent heads used to estimate the block time.
+const ESTIMATOR_WINDOW: usize = 64;
+
+/// ChainClock follows the heads of a `NewBlockSubscriber`, offering the chain
+/// time and futures that resolve once a block number or a timestamp has been
+/// reached by the canonical head.
+///
+/// The clock always follows the latest head received, so a reorg to a lower
+/// head also moves the clock back. Waiting futures that have already resolved
+/// are not affected.
+pub struct ChainClock {
+    head: watch::Receiver<Option<Arc<Block>>>,
+    samples: Arc<Mutex<VecDeque<(U64, U256)>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ChainClock {
+    /// Returns `None` if the subscriber has already terminated. Recent heads
+    /// are replayed, so the clock is usually ready right away.
+    pub async fn start<S: NewBlockSubscriber>(subscriber: &S) -> Option<Self> {
+        let subscription =
+            subscriber.subscribe_with_replay(ESTIMATOR_WINDOW).await?;
+        let (head_tx, head) = watch::channel(None);
+        let samples = Arc::new(Mutex::new(VecDeque::new()));
+
+        let task = tokio::spawn(Self::follow(
+            subscription,
+            head_tx,
+            Arc::clone(&samples),
+        ));
+
+        Some(Self {
+            head,
+            samples,
+            task,
+        })
+    }
+
+    /// Latest head, if any was received.
+    pub fn head(&self) -> Option<Arc<Block>> {
+        self.head.borrow().clone()
+    }
+
+    /// Timestamp of the latest head, if any was received.
+    pub fn now(&self) -> Option<U256> {
+        self.head.borrow().as_ref().map(|head| head.timestamp)
+    }
+
+    /// Waits until the head number is at least `number`, returning that head.
+    /// Returns `None` if the subscriber terminates first.
+    pub async fn wait_for_block(&self, number: U64) -> Option<Arc<Block>> {
+        self.wait_for(|head| head.number >= number).await
+    }
+
+    /// Waits until the head timestamp is at least `timestamp`, returning that
+    /// head. Returns `None` if the subscriber terminates first.
+    pub async fn wait_for_timestamp(
+        &self,
+        timestamp: U256,
+    ) -> Option<Arc<Block>> {
+        self.wait_for(|head| head.timestamp >= timestamp).await
+    }
+
+    /// Average time between the recent heads. Returns `None` until at least
+    /// two different heads were received.
+    pub fn block_time(&self) -> Option<Duration> {
+        let samples = self.samples.lock().unwrap();
+        let (first_number, first_timestamp) = samples.front()?;
+        let (last_number, last_timestamp) = samples.back()?;
+
+        let blocks = (*last_number - *first_number).as_u64();
+        if blocks == 0 {
+            return None;
+        }
+
+        let secs = last_timestamp.saturating_sub(*first_timestamp).as_u64();
+        Some(Duration::from_secs(secs) / blocks as u32)
+    }
+
+    async fn wait_for<F>(&self, reached: F) -> Option<Arc<Block>>
+    where
+        F: Fn(&Block) -> bool,
+    {
+        let mut head = self.head.clone();
+
+        loop {
+            if let Some(block) = head.borrow().as_ref().filter(|b| reached(b)) {
+                return Some(Arc::clone(block));
+            }
+
+            head.changed().await.ok()?;
+        }
+    }
+
+    async fn follow(
+        mut subscription: ReplaySubscription,
+        head: watch::Sender<Option<Arc<Block>>>,
+        samples: Arc<Mutex<VecDeque<(U64, U256)>>>,
+    ) {
+        loop {
+            let block = match subscription.recv().await {
+                Ok(block) => block,
+                // Missing some heads is fine, the latest is what matters.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+
+            {
+                let mut samples = samples.lock().unwrap();
+
+                // On reorgs, forget the heads that are no longer canonical.
+                while matches!(samples.back(), Some((n, _)) if *n >= block.number)
+                {
+                    samples.pop_back();
+                }
+
+                samples.push_back((block.number, block.timestamp));
+                if samples.len() > ESTIMATOR_WINDOW {
+                    samples.pop_front();
+                }
+            }
+
+            if head.send(Some(block)).is_err() {
+                // Clock was dropped.
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for ChainClock {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_block, FakeSubscriber};
+
+    #[tokio::test]
+    async fn wait_for_block_test() {
+        let subscriber = FakeSubscriber::new();
+        let clock = ChainClock::start(&subscriber).await.unwrap();
+        assert!(clock.now().is_none());
+
+        let waiting = clock.wait_for_block(12.into());
+        let waiting_time = clock.wait_for_timestamp(130.into());
+        let send = async {
+            for (number, timestamp) in [(10, 100), (11, 110), (12, 120)] {
+                subscriber
+                    .channel
+                    .send(Arc::new(new_block(number, timestamp)))
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        let (block, _) = tokio::join!(waiting, send);
+        assert_eq!(block.unwrap().number, 12.into());
+        assert_eq!(clock.block_time(), Some(Duration::from_secs(10)));
+
+        // Reorg back to block 11, with a different timestamp.
+        subscriber
+            .channel
+            .send(Arc::new(new_block(11, 140)))
+            .unwrap();
+        let block = waiting_time.await.unwrap();
+        assert_eq!(block.number, 11.into());
+        assert_eq!(clock.now(), Some(140.into()));
+        assert_eq!(clock.block_time(), Some(Duration::from_secs(40)));
+
+        drop(subscriber);
+        assert!(clock.wait_for_block(13.into()).await.is_none());
+    }
+}
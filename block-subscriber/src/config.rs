@@ -1,6 +1,7 @@
 use configuration::error as config_error;
 
 use serde::Deserialize;
+use snafu::ensure;
 use std::time::Duration;
 use structopt::StructOpt;
 
@@ -19,6 +20,23 @@ pub struct BSEnvCLIConfig {
     /// Timeout value (secs) for block subscriber
     #[structopt(long, env)]
     pub bs_timeout: Option<u64>,
+    /// Expected block time (secs) of the chain
+    #[structopt(long, env)]
+    pub bs_block_time: Option<u64>,
+    /// Number of block times without a new block before the chain is
+    /// considered stalled
+    #[structopt(long, env)]
+    pub bs_stall_blocks: Option<u32>,
+    /// Depth of the safe head, for chains without the `safe` block tag
+    #[structopt(long, env)]
+    pub bs_safe_depth: Option<u64>,
+    /// Depth of the finalized head, for chains without the `finalized`
+    /// block tag
+    #[structopt(long, env)]
+    pub bs_finality_depth: Option<u64>,
+    /// Number of recent blocks kept for replaying to new subscribers
+    #[structopt(long, env)]
+    pub bs_replay_capacity: Option<usize>,
 }
 
 #[derive(Clone, Debug, Deserialize, Default)]
@@ -26,6 +44,11 @@ pub struct BSFileConfig {
     pub max_delay: Option<u64>,
     pub max_retries: Option<usize>,
     pub timeout: Option<u64>,
+    pub block_time: Option<u64>,
+    pub stall_blocks: Option<u32>,
+    pub safe_depth: Option<u64>,
+    pub finality_depth: Option<u64>,
+    pub replay_capacity: Option<usize>,
 }
 
 #[derive(Clone, Debug, Deserialize, Default)]
@@ -38,14 +61,45 @@ pub struct BSConfig {
     pub max_delay: Duration,
     pub max_retries: usize,
     pub subscriber_timeout: Duration,
+    pub block_time: Duration,
+    pub stall_blocks: u32,
+    pub safe_depth: u64,
+    pub finality_depth: u64,
+    pub replay_capacity: usize,
 }
 
 // default values
 const DEFAULT_MAX_DELAY: u64 = 1;
 const DEFAULT_MAX_RETRIES: usize = 5;
 const DEFAULT_TIMEOUT: u64 = 5;
+const DEFAULT_BLOCK_TIME: u64 = 15;
+const DEFAULT_STALL_BLOCKS: u32 = 4;
+const DEFAULT_SAFE_DEPTH: u64 = 6;
+const DEFAULT_FINALITY_DEPTH: u64 = 12;
+const DEFAULT_REPLAY_CAPACITY: usize = 64;
+
+impl Default for BSConfig {
+    fn default() -> Self {
+        BSConfig {
+            max_delay: Duration::from_secs(DEFAULT_MAX_DELAY),
+            max_retries: DEFAULT_MAX_RETRIES,
+            subscriber_timeout: Duration::from_secs(DEFAULT_TIMEOUT),
+            block_time: Duration::from_secs(DEFAULT_BLOCK_TIME),
+            stall_blocks: DEFAULT_STALL_BLOCKS,
+            safe_depth: DEFAULT_SAFE_DEPTH,
+            finality_depth: DEFAULT_FINALITY_DEPTH,
+            replay_capacity: DEFAULT_REPLAY_CAPACITY,
+        }
+    }
+}
 
 impl BSConfig {
+    /// Time without a new block before the chain is considered stalled, or
+    /// `None` if it overflows.
+    pub fn stall_timeout(&self) -> Option<Duration> {
+        self.block_time.checked_mul(self.stall_blocks)
+    }
+
     pub fn initialize(
         env_cli_config: BSEnvCLIConfig,
     ) -> config_error::Result<Self> {
@@ -71,10 +125,75 @@ impl BSConfig {
                 .unwrap_or(DEFAULT_TIMEOUT),
         );
 
-        Ok(BSConfig {
+        let block_time = Duration::from_secs(
+            env_cli_config
+                .bs_block_time
+                .or(file_config.block_subscriber.block_time)
+                .unwrap_or(DEFAULT_BLOCK_TIME),
+        );
+
+        let stall_blocks = env_cli_config
+            .bs_stall_blocks
+            .or(file_config.block_subscriber.stall_blocks)
+            .unwrap_or(DEFAULT_STALL_BLOCKS);
+
+        let safe_depth = env_cli_config
+            .bs_safe_depth
+            .or(file_config.block_subscriber.safe_depth)
+            .unwrap_or(DEFAULT_SAFE_DEPTH);
+
+        let finality_depth = env_cli_config
+            .bs_finality_depth
+            .or(file_config.block_subscriber.finality_depth)
+            .unwrap_or(DEFAULT_FINALITY_DEPTH);
+
+        let replay_capacity = env_cli_config
+            .bs_replay_capacity
+            .or(file_config.block_subscriber.replay_capacity)
+            .unwrap_or(DEFAULT_REPLAY_CAPACITY);
+
+        let config = BSConfig {
             max_delay,
             max_retries,
             subscriber_timeout,
-        })
+            block_time,
+            stall_blocks,
+            safe_depth,
+            finality_depth,
+            replay_capacity,
+        };
+
+        ensure!(
+            config.stall_timeout().is_some(),
+            config_error::ParseError {
+                err: format!(
+                    "Stall timeout of {} blocks of {:?} overflows",
+                    stall_blocks, block_time
+                ),
+            }
+        );
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_timeout_test() {
+        let config = BSConfig {
+            block_time: Duration::from_secs(2),
+            stall_blocks: 3,
+            ..Default::default()
+        };
+        assert_eq!(config.stall_timeout(), Some(Duration::from_secs(6)));
+
+        let config = BSConfig {
+            block_time: Duration::MAX,
+            ..config
+        };
+        assert_eq!(config.stall_timeout(), None);
     }
 }
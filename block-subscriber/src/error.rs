@@ -6,21 +6,44 @@ pub enum Error<M: offchain_core::ethers::providers::Middleware + 'static> {
     #[snafu(display("Ethers provider error: {}", source))]
     EthersProviderError { source: M::Error },
 
+    #[snafu(display("Failed to fetch {} block: {}", tag, source))]
+    BlockTagError {
+        tag: &'static str,
+        source: offchain_core::ethers::providers::ProviderError,
+    },
+
     #[snafu(display("Got incomplete block"))]
     BlockIncomplete { err: String },
 
-    #[snafu(display("New block subscriber timeout: {}", source))]
-    NewBlockSubscriberTimeout { source: std::io::Error },
-
     #[snafu(display("Web3 subscription dropped"))]
     SubscriptionDropped {},
 
+    #[snafu(display(
+        "Web3 subscription stale, last received block {} but node head is {}",
+        last_received,
+        node_head
+    ))]
+    SubscriptionStale {
+        last_received: offchain_core::ethers::types::U64,
+        node_head: offchain_core::ethers::types::U64,
+    },
+
     #[snafu(display("Retry limit of {} reached", retries))]
     RetryLimitReached {
         retries: usize,
         last_error: Box<Error<M>>,
     },
 
+    #[snafu(display(
+        "Stall timeout of {} blocks of {:?} overflows",
+        stall_blocks,
+        block_time
+    ))]
+    StallTimeoutOverflow {
+        block_time: std::time::Duration,
+        stall_blocks: u32,
+    },
+
     #[snafu(display("Factory error: {}", source))]
     FactoryError { source: middleware_factory::Error },
 }
@@ -0,0 +1,244 @@
+use crate::block_subscriber::BlockEvent;
+use crate::error::*;
+use offchain_core::types::Block;
+
+use middleware_factory::json_rpc_error;
+use offchain_core::ethers::providers::{
+    JsonRpcClient, Middleware, ProviderError,
+};
+use offchain_core::ethers::types::{self, H256};
+use snafu::ResultExt;
+use std::convert::TryInto;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Keeps track of the `safe` and `finalized` heads of the chain. When the node
+/// supports the `safe` and `finalized` block tags, they are queried on every
+/// new head. Otherwise, they are derived from the head using a fixed depth.
+pub(crate) struct FinalityTracker {
+    safe_depth: u64,
+    finality_depth: u64,
+    tags_supported: bool,
+    safe: Option<Arc<Block>>,
+    finalized: watch::Sender<Option<Arc<Block>>>,
+}
+
+impl FinalityTracker {
+    pub fn new(
+        safe_depth: u64,
+        finality_depth: u64,
+    ) -> (Self, watch::Receiver<Option<Arc<Block>>>) {
+        let (finalized, finalized_rx) = watch::channel(None);
+
+        (
+            Self {
+                safe_depth,
+                finality_depth,
+                tags_supported: true,
+                safe: None,
+                finalized,
+            },
+            finalized_rx,
+        )
+    }
+
+    /// Support for block tags is checked again on every new connection, since
+    /// we may be talking to a different node.
+    pub fn reset(&mut self) {
+        self.tags_supported = true;
+    }
+
+    /// Updates the `safe` and `finalized` heads after receiving a new head,
+    /// returning the events for the ones that changed.
+    pub async fn update<M: Middleware + 'static>(
+        &mut self,
+        middleware: &M,
+        head: &Block,
+    ) -> Result<Vec<BlockEvent>, M> {
+        let (safe, finalized) = match self.fetch_tags(middleware).await? {
+            Some(heads) => heads,
+            None => (
+                block_at_depth(middleware, head, self.safe_depth).await?,
+                block_at_depth(middleware, head, self.finality_depth).await?,
+            ),
+        };
+
+        let mut events = Vec::new();
+
+        if let Some(safe) = safe {
+            if !same_block(self.safe.as_deref(), &safe) {
+                let safe = Arc::new(safe);
+                self.safe = Some(Arc::clone(&safe));
+                events.push(BlockEvent::Safe(safe));
+            }
+        }
+
+        if let Some(finalized) = finalized {
+            if !same_block(self.finalized.borrow().as_deref(), &finalized) {
+                let finalized = Arc::new(finalized);
+                // The receiver kept by the `BlockSubscriber` is never
+                // dropped, so this can't fail.
+                let _ = self.finalized.send(Some(Arc::clone(&finalized)));
+                events.push(BlockEvent::Finalized(finalized));
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Returns `None` if the node doesn't support the block tags, in which
+    /// case the tags won't be queried again until the next `reset`.
+    async fn fetch_tags<M: Middleware + 'static>(
+        &mut self,
+        middleware: &M,
+    ) -> Result<Option<(Option<Block>, Option<Block>)>, M> {
+        if !self.tags_supported {
+            return Ok(None);
+        }
+
+        let heads = match (
+            fetch_tag(middleware, "safe").await?,
+            fetch_tag(middleware, "finalized").await?,
+        ) {
+            (Some(safe), Some(finalized)) => {
+                Some((Some(safe), Some(finalized)))
+            }
+            _ => None,
+        };
+
+        self.tags_supported = heads.is_some();
+        Ok(heads)
+    }
+}
+
+/// Nodes that don't support the tag either return no block or reject it as
+/// an invalid or unknown block, so these cases are treated the same way and
+/// return `None`. Any other error, such as a dropped connection, is returned.
+async fn fetch_tag<M: Middleware + 'static>(
+    middleware: &M,
+    tag: &'static str,
+) -> Result<Option<Block>, M> {
+    let res: std::result::Result<Option<types::Block<H256>>, _> = middleware
+        .provider()
+        .as_ref()
+        .request("eth_getBlockByNumber", (tag, false))
+        .await;
+
+    let block = match res.map_err(Into::<ProviderError>::into) {
+        Ok(block) => block,
+        Err(err) if is_unsupported(&err) => None,
+        Err(err) => return Err(err).context(BlockTagError { tag }),
+    };
+
+    Ok(block.and_then(|block| block.try_into().ok()))
+}
+
+fn is_unsupported(err: &ProviderError) -> bool {
+    match json_rpc_error(err) {
+        // Invalid params.
+        Some((-32602, _)) => true,
+        Some((_, message)) => message.to_lowercase().contains("unknown block"),
+        None => false,
+    }
+}
+
+async fn block_at_depth<M: Middleware + 'static>(
+    middleware: &M,
+    head: &Block,
+    depth: u64,
+) -> Result<Option<Block>, M> {
+    if depth == 0 {
+        return Ok(Some(head.clone()));
+    }
+
+    if head.number.as_u64() < depth {
+        return Ok(None);
+    }
+
+    let block = middleware
+        .get_block(head.number - depth)
+        .await
+        .context(EthersProviderError)?;
+
+    block
+        .map(|block| {
+            block
+                .try_into()
+                .map_err(|err| BlockIncomplete { err }.build())
+        })
+        .transpose()
+}
+
+fn same_block(current: Option<&Block>, new: &Block) -> bool {
+    matches!(current, Some(current) if current.hash == new.hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use offchain_core::ethers::providers::Provider;
+    use offchain_core::ethers::types::{Bloom, U64};
+
+    fn new_block(number: u64) -> types::Block<H256> {
+        types::Block {
+            hash: Some(H256::from_low_u64_be(number + 1)),
+            number: Some(U64::from(number)),
+            logs_bloom: Some(Bloom::zero()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn depth_fallback_test() {
+        let (provider, mock) = Provider::mocked();
+        let (mut tracker, finalized_rx) = FinalityTracker::new(1, 2);
+
+        // Responses are popped from the back.
+        mock.push(new_block(8)).unwrap();
+        mock.push(new_block(9)).unwrap();
+        mock.push::<Option<types::Block<H256>>, _>(None).unwrap();
+        mock.push::<Option<types::Block<H256>>, _>(None).unwrap();
+
+        let head = new_block(10).try_into().unwrap();
+        let events = tracker.update(&provider, &head).await.unwrap();
+
+        assert!(matches!(
+            events.as_slice(),
+            [BlockEvent::Safe(safe), BlockEvent::Finalized(finalized)]
+                if safe.number == U64::from(9)
+                    && finalized.number == U64::from(8)
+        ));
+        assert_eq!(
+            finalized_rx.borrow().as_ref().unwrap().number,
+            U64::from(8)
+        );
+
+        // Tags are not queried again, and unchanged heads are not reported.
+        mock.push(new_block(8)).unwrap();
+        mock.push(new_block(9)).unwrap();
+
+        let events = tracker.update(&provider, &head).await.unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tag_error_test() {
+        let (provider, mock) = Provider::mocked();
+        let (mut tracker, _finalized_rx) = FinalityTracker::new(1, 2);
+        let head = new_block(10).try_into().unwrap();
+
+        // Failing to reach the node doesn't disable the tags.
+        let res = tracker.update(&provider, &head).await;
+        assert!(matches!(res, Err(Error::BlockTagError { tag: "safe", .. })));
+
+        mock.push(new_block(9)).unwrap();
+        mock.push(new_block(10)).unwrap();
+        let events = tracker.update(&provider, &head).await.unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [BlockEvent::Safe(safe), BlockEvent::Finalized(finalized)]
+                if safe.number == U64::from(10)
+                    && finalized.number == U64::from(9)
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::replay::{ReplayRing, ReplaySubscription};
+use offchain_core::types::Block;
+
+use arc_swap::ArcSwapOption;
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Publishes new heads to the replay ring, the latest head watch and the
+/// broadcast channel, which is the work `BlockSubscriber` does for every new
+/// head besides sending events and tracking finality.
+pub struct HeadBroadcaster {
+    // Loaded on every new head, so it must not be behind a lock.
+    channel: ArcSwapOption<broadcast::Sender<Arc<Block>>>,
+    latest: watch::Sender<Option<Arc<Block>>>,
+    // Kept so that updating `latest` never fails.
+    latest_rx: watch::Receiver<Option<Arc<Block>>>,
+    recent: ReplayRing,
+}
+
+impl HeadBroadcaster {
+    /// Keeps up to `replay_capacity` of the most recent heads to replay.
+    pub fn new(replay_capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (latest, latest_rx) = watch::channel(None);
+
+        Self {
+            channel: ArcSwapOption::from_pointee(tx),
+            latest,
+            latest_rx,
+            recent: ReplayRing::new(replay_capacity),
+        }
+    }
+
+    /// Returns false if the broadcaster was closed.
+    pub fn publish(&self, head: Arc<Block>) -> bool {
+        // The ring must be updated before broadcasting, so replaying
+        // subscribers can't miss the block.
+        self.recent.push(Arc::clone(&head));
+        let _ = self.latest.send(Some(Arc::clone(&head)));
+
+        match &*self.channel.load() {
+            Some(channel) => {
+                // Not having subscribers is not an error.
+                let _ = channel.send(head);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops publishing, dropping the channel so subscribers see it closed.
+    pub fn close(&self) {
+        self.channel.store(None);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.channel.load().is_none()
+    }
+
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<Arc<Block>>> {
+        self.channel
+            .load()
+            .as_ref()
+            .map(|channel| channel.subscribe())
+    }
+
+    pub fn latest(&self) -> Option<watch::Receiver<Option<Arc<Block>>>> {
+        self.channel.load().as_ref().map(|_| self.latest_rx.clone())
+    }
+
+    /// Same as `subscribe`, first replaying up to `replay` of the most recent
+    /// heads.
+    pub fn subscribe_with_replay(
+        &self,
+        replay: usize,
+    ) -> Option<ReplaySubscription> {
+        let subscription = self.subscribe()?;
+        Some(ReplaySubscription::new(
+            subscription,
+            self.recent.last(replay),
+        ))
+    }
+}
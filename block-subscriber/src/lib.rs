@@ -1,7 +1,19 @@
 pub mod block_subscriber;
+pub mod bloom;
+pub mod clock;
 pub mod config;
 pub mod error;
+mod finality;
+pub mod heads;
+pub mod replay;
+#[cfg(test)]
+mod test_utils;
 
+pub use crate::block_subscriber::BlockEvent;
 pub use crate::block_subscriber::BlockSubscriber;
 pub use crate::block_subscriber::BlockSubscriberHandle;
 pub use crate::block_subscriber::NewBlockSubscriber;
+pub use crate::bloom::BloomFilteredSubscriber;
+pub use crate::clock::ChainClock;
+pub use crate::heads::HeadBroadcaster;
+pub use crate::replay::ReplaySubscription;
@@ -0,0 +1,128 @@
+use offchain_core::ethers::types::H256;
+use offchain_core::types::Block;
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Subscription that first yields recent blocks, oldest first, and then the
+/// new heads. Blocks that are both replayed and received afterwards are only
+/// yielded once.
+pub struct ReplaySubscription {
+    replay: VecDeque<Arc<Block>>,
+    replayed: HashSet<H256>,
+    subscription: broadcast::Receiver<Arc<Block>>,
+}
+
+impl ReplaySubscription {
+    /// `subscription` must be created before taking the `replay` snapshot,
+    /// otherwise blocks broadcast in between are lost.
+    pub fn new(
+        subscription: broadcast::Receiver<Arc<Block>>,
+        replay: Vec<Arc<Block>>,
+    ) -> Self {
+        Self {
+            replayed: replay.iter().map(|block| block.hash).collect(),
+            replay: replay.into(),
+            subscription,
+        }
+    }
+
+    /// Same as `broadcast::Receiver::recv`, yielding the replayed blocks
+    /// first.
+    pub async fn recv(&mut self) -> Result<Arc<Block>, RecvError> {
+        if let Some(block) = self.replay.pop_front() {
+            return Ok(block);
+        }
+
+        loop {
+            let block = self.subscription.recv().await?;
+            if !self.replayed.remove(&block.hash) {
+                return Ok(block);
+            }
+        }
+    }
+
+    pub(crate) fn into_parts(
+        self,
+    ) -> (broadcast::Receiver<Arc<Block>>, Vec<Arc<Block>>) {
+        (self.subscription, self.replay.into())
+    }
+}
+
+/// Most recent canonical blocks, oldest first.
+pub(crate) struct ReplayRing {
+    capacity: usize,
+    blocks: Mutex<VecDeque<Arc<Block>>>,
+}
+
+impl ReplayRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, block: Arc<Block>) {
+        let mut blocks = self.blocks.lock().unwrap();
+
+        // On reorgs, forget the blocks that are no longer canonical.
+        while matches!(blocks.back(), Some(b) if b.number >= block.number) {
+            blocks.pop_back();
+        }
+
+        if self.capacity == 0 {
+            return;
+        }
+        if blocks.len() == self.capacity {
+            blocks.pop_front();
+        }
+        blocks.push_back(block);
+    }
+
+    /// Returns up to `count` of the most recent blocks.
+    pub fn last(&self, count: usize) -> Vec<Arc<Block>> {
+        let blocks = self.blocks.lock().unwrap();
+        let skip = blocks.len().saturating_sub(count);
+        blocks.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::new_block;
+
+    #[tokio::test]
+    async fn replay_test() {
+        let ring = ReplayRing::new(3);
+        for number in 10..15 {
+            ring.push(Arc::new(new_block(number, number * 10)));
+        }
+
+        // Reorg replacing block 14.
+        ring.push(Arc::new(new_block(14, 141)));
+        let numbers = |blocks: &[Arc<Block>]| {
+            blocks.iter().map(|b| b.number.as_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(numbers(&ring.last(2)), vec![13, 14]);
+        assert_eq!(numbers(&ring.last(5)), vec![12, 13, 14]);
+
+        let (tx, rx) = broadcast::channel(16);
+        let mut subscription = ReplaySubscription::new(rx, ring.last(2));
+
+        // Block 14 was broadcast after subscribing but before the snapshot.
+        tx.send(ring.last(1).remove(0)).unwrap();
+        tx.send(Arc::new(new_block(15, 150))).unwrap();
+
+        for number in 13..16 {
+            let block = subscription.recv().await.unwrap();
+            assert_eq!(block.number, number.into());
+        }
+
+        drop(tx);
+        assert!(subscription.recv().await.is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use crate::block_subscriber::{BlockEvent, NewBlockSubscriber};
+use crate::replay::ReplaySubscription;
+use offchain_core::ethers::types::{Bloom, H256};
+use offchain_core::types::Block;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+
+/// Subscriber whose heads are sent by the test itself.
+pub struct FakeSubscriber {
+    pub channel: broadcast::Sender<Arc<Block>>,
+}
+
+impl FakeSubscriber {
+    pub fn new() -> Self {
+        let (channel, _) = broadcast::channel(16);
+        Self { channel }
+    }
+}
+
+#[async_trait]
+impl NewBlockSubscriber for FakeSubscriber {
+    async fn subscribe(&self) -> Option<broadcast::Receiver<Arc<Block>>> {
+        Some(self.channel.subscribe())
+    }
+
+    async fn subscribe_events(
+        &self,
+    ) -> Option<broadcast::Receiver<BlockEvent>> {
+        None
+    }
+
+    async fn subscribe_finalized(
+        &self,
+    ) -> Option<watch::Receiver<Option<Arc<Block>>>> {
+        None
+    }
+
+    async fn latest(&self) -> Option<watch::Receiver<Option<Arc<Block>>>> {
+        None
+    }
+
+    async fn subscribe_with_replay(
+        &self,
+        _replay: usize,
+    ) -> Option<ReplaySubscription> {
+        Some(ReplaySubscription::new(
+            self.channel.subscribe(),
+            Vec::new(),
+        ))
+    }
+}
+
+pub fn new_block(number: u64, timestamp: u64) -> Block {
+    Block {
+        hash: H256::from_low_u64_be(number + timestamp),
+        number: number.into(),
+        parent_hash: H256::zero(),
+        timestamp: timestamp.into(),
+        logs_bloom: Bloom::zero(),
+    }
+}
@@ -1,5 +1,9 @@
-use block_subscriber::{BlockSubscriber, NewBlockSubscriber};
-use middleware_factory::WsProviderFactory;
+use block_subscriber::config::BSConfig;
+use block_subscriber::{BlockEvent, BlockSubscriber, NewBlockSubscriber};
+use configuration::Config;
+#[cfg(unix)]
+use middleware_factory::IpcProviderFactory;
+use middleware_factory::{FactoryStack, WsProviderFactory};
 use offchain_core::ethers::core::utils::Geth;
 
 #[tokio::test]
@@ -35,3 +39,182 @@ async fn subscribe_test() {
     assert!(block_subscriber.subscribe().await.is_none());
     assert!(subscription.recv().await.is_err());
 }
+
+#[tokio::test]
+async fn chain_stalled_test() {
+    // Without a block time, geth in dev mode only mines on new transactions.
+    let geth = Geth::new().spawn();
+    let factory = WsProviderFactory::new(
+        geth.ws_endpoint(),
+        0,
+        std::time::Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    let config = BSConfig {
+        subscriber_timeout: std::time::Duration::from_secs(1),
+        max_retries: 0,
+        max_delay: std::time::Duration::from_secs(1),
+        block_time: std::time::Duration::from_secs(1),
+        stall_blocks: 2,
+        ..Default::default()
+    };
+    let (block_subscriber, handle) =
+        BlockSubscriber::create_and_start_with_config(factory, &config)
+            .unwrap();
+
+    let mut events = block_subscriber.subscribe_events().await.unwrap();
+    match events.recv().await.unwrap() {
+        BlockEvent::ChainStalled { elapsed, .. } => {
+            assert!(elapsed >= std::time::Duration::from_secs(2))
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    // The subscription was kept alive through the stall.
+    handle.kill_switch.send(()).unwrap();
+    handle.handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn finalized_test() {
+    let geth = Geth::new().block_time(1u64).spawn();
+    let factory = WsProviderFactory::new(
+        geth.ws_endpoint(),
+        0,
+        std::time::Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    let config = BSConfig {
+        subscriber_timeout: std::time::Duration::from_secs(15),
+        max_retries: 0,
+        max_delay: std::time::Duration::from_secs(1),
+        safe_depth: 1,
+        finality_depth: 2,
+        ..Default::default()
+    };
+    let (block_subscriber, handle) =
+        BlockSubscriber::create_and_start_with_config(factory, &config)
+            .unwrap();
+
+    let mut subscription = block_subscriber.subscribe().await.unwrap();
+    let mut finalized = block_subscriber.subscribe_finalized().await.unwrap();
+
+    finalized.changed().await.unwrap();
+    let finalized_number = finalized.borrow().as_ref().unwrap().number;
+    let head = subscription.recv().await.unwrap();
+    assert!(finalized_number < head.number);
+
+    handle.kill_switch.send(()).unwrap();
+    handle.handle.await.unwrap().unwrap();
+
+    assert!(block_subscriber.subscribe_finalized().await.is_none());
+    assert!(finalized.changed().await.is_err());
+}
+
+#[tokio::test]
+async fn replay_test() {
+    let geth = Geth::new().block_time(1u64).spawn();
+    let factory = WsProviderFactory::new(
+        geth.ws_endpoint(),
+        0,
+        std::time::Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    let (block_subscriber, handle) = BlockSubscriber::create_and_start(
+        factory,
+        std::time::Duration::from_secs(15),
+        0,
+        std::time::Duration::from_secs(1),
+    );
+
+    let mut latest = block_subscriber.latest().await.unwrap();
+    for _ in 0..3 {
+        latest.changed().await.unwrap();
+    }
+    let head = latest.borrow().clone().unwrap();
+
+    // Late subscribers get the recent blocks right away, in order.
+    let mut subscription =
+        block_subscriber.subscribe_with_replay(3).await.unwrap();
+    let first = subscription.recv().await.unwrap();
+    assert!(first.number <= head.number);
+    let mut current_block = first.number;
+    for _ in 0..4 {
+        let block = subscription.recv().await.unwrap();
+        assert_eq!(current_block + 1, block.number);
+        current_block = block.number;
+    }
+
+    handle.kill_switch.send(()).unwrap();
+    handle.handle.await.unwrap().unwrap();
+
+    assert!(block_subscriber.latest().await.is_none());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn subscribe_ipc_test() {
+    let path = std::env::temp_dir()
+        .join(format!("subscriber-test-{}.ipc", std::process::id()));
+    let geth = Geth::new().block_time(1u64).ipc_path(&path).spawn();
+    let factory = IpcProviderFactory::new(
+        geth.ipc_path().clone().unwrap(),
+        None,
+        0,
+        std::time::Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    let (block_subscriber, handle) = BlockSubscriber::create_and_start(
+        factory,
+        std::time::Duration::from_secs(15),
+        0,
+        std::time::Duration::from_secs(1),
+    );
+
+    let mut subscription = block_subscriber.subscribe().await.unwrap();
+    let current_block = subscription.recv().await.unwrap().number;
+    let head = subscription.recv().await.unwrap();
+    assert_eq!(current_block + 1, head.number);
+
+    handle.kill_switch.send(()).unwrap();
+    handle.handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn factory_stack_test() {
+    let geth = Geth::new().block_time(1u64).spawn();
+    let config = Config {
+        contracts: Default::default(),
+        url: geth.endpoint(),
+        ws_url: Some(geth.ws_endpoint()),
+        ipc_path: None,
+        expected_chain_id: None,
+        http: Default::default(),
+        ws: Default::default(),
+        wallet: None,
+    };
+    let factory = FactoryStack::from_config(&config).await.unwrap();
+
+    let (block_subscriber, handle) = BlockSubscriber::create_and_start(
+        factory,
+        std::time::Duration::from_secs(15),
+        0,
+        std::time::Duration::from_secs(1),
+    );
+
+    let mut subscription = block_subscriber.subscribe().await.unwrap();
+    let current_block = subscription.recv().await.unwrap().number;
+    let head = subscription.recv().await.unwrap();
+    assert_eq!(current_block + 1, head.number);
+
+    handle.kill_switch.send(()).unwrap();
+    handle.handle.await.unwrap().unwrap();
+}
@@ -1,4 +1,6 @@
 use crate::error::*;
+use crate::http::HttpOptions;
+use crate::ws::WsOptions;
 
 use offchain_core::ethers;
 
@@ -34,6 +36,12 @@ pub struct EnvCLIConfig {
     /// Provider websocket endpoint
     #[structopt(long, env)]
     pub ws_url: Option<String>,
+    /// Path to the provider IPC socket
+    #[structopt(long, env)]
+    pub ipc_path: Option<String>,
+    /// Chain id the provider endpoints must be on
+    #[structopt(long, env)]
+    pub expected_chain_id: Option<u64>,
     /// Create the wallet if file doesn't exist
     #[structopt(long, env)]
     pub wallet_create: Option<bool>,
@@ -49,8 +57,12 @@ pub struct OffchainFileConfig {
     pub seed: Option<String>,
     pub url: Option<String>,
     pub ws_url: Option<String>,
+    pub ipc_path: Option<String>,
+    pub expected_chain_id: Option<u64>,
     pub wallet_create: Option<bool>,
     pub wallet_path: Option<String>,
+    pub http: Option<HttpOptions>,
+    pub ws: Option<WsOptions>,
 }
 
 #[derive(Clone, Debug, Deserialize, Default)]
@@ -64,6 +76,11 @@ pub struct Config {
     pub contracts: HashMap<String, Address>,
     pub url: String,
     pub ws_url: Option<String>,
+    pub ipc_path: Option<String>,
+    // Endpoints on any other chain are refused
+    pub expected_chain_id: Option<u64>,
+    pub http: HttpOptions,
+    pub ws: WsOptions,
     pub wallet: Option<LocalWallet>,
 }
 
@@ -183,6 +200,16 @@ impl Config {
 
         let ws_url = env_cli_config.ws_url.or(file_config.offchain.ws_url);
 
+        let ipc_path =
+            env_cli_config.ipc_path.or(file_config.offchain.ipc_path);
+
+        let expected_chain_id = env_cli_config
+            .expected_chain_id
+            .or(file_config.offchain.expected_chain_id);
+
+        let http = file_config.offchain.http.unwrap_or_default();
+        let ws = file_config.offchain.ws.unwrap_or_default();
+
         let mnemonic =
             env_cli_config.mnemonic.or(file_config.offchain.mnemonic);
 
@@ -215,7 +242,7 @@ impl Config {
                         .build()
                     })?;
                     Some(
-                        LocalWallet::decrypt_keystore(&path, &password)
+                        LocalWallet::decrypt_keystore(path, &password)
                             .map_err(|e| {
                                 FileError {
                                     err: format!(
@@ -254,7 +281,7 @@ impl Config {
 
                     if new_password != retype_password {
                         return ParseError {
-                            err: format!("Passwords don't match"),
+                            err: "Passwords don't match".to_string(),
                         }
                         .fail();
                     }
@@ -275,7 +302,7 @@ impl Config {
 
                     Some(
                         LocalWallet::new_keystore(
-                            &dir,
+                            dir,
                             &mut thread_rng(),
                             &new_password,
                         )
@@ -292,7 +319,7 @@ impl Config {
                 }
                 _ => {
                     return FileError {
-                        err: format!("Wallet file doesn't exist, do you want to create one? Set --wallet_create to true"),
+                        err: "Wallet file doesn't exist, do you want to create one? Set --wallet_create to true".to_string(),
                     }
                     .fail();
                 }
@@ -320,6 +347,10 @@ impl Config {
             contracts,
             url,
             ws_url,
+            ipc_path,
+            expected_chain_id,
+            http,
+            ws,
             wallet,
         })
     }
@@ -1,4 +1,8 @@
 pub mod config;
 pub mod error;
+pub mod http;
+pub mod ws;
 
 pub use crate::config::Config;
+pub use crate::http::{HttpAuth, HttpOptions};
+pub use crate::ws::{TlsOptions, WsOptions};
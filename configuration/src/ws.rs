@@ -0,0 +1,40 @@
+use crate::http::{redact_headers, HttpAuth};
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Options of websocket connections to the provider, read from the
+/// `[offchain.ws]` section of the config file. They apply to every
+/// connection, including reconnections.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WsOptions {
+    /// Headers sent with the handshake.
+    pub headers: BTreeMap<String, String>,
+    pub auth: Option<HttpAuth>,
+    pub tls: TlsOptions,
+}
+
+/// TLS configuration of `wss` connections. The webpki roots are always
+/// trusted.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsOptions {
+    /// Paths to PEM files of additional CA certificates to trust.
+    pub ca_certs: Vec<String>,
+    /// Path to the PEM client certificate chain, for mutual TLS.
+    pub client_cert: Option<String>,
+    /// Path to the PEM private key of `client_cert`.
+    pub client_key: Option<String>,
+}
+
+impl fmt::Debug for WsOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsOptions")
+            .field("headers", &redact_headers(&self.headers))
+            .field("auth", &self.auth)
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
@@ -0,0 +1,99 @@
+use configuration::HttpAuth;
+
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::json;
+use sha2::Sha256;
+use snafu::{ensure, ResultExt, Snafu};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum AuthError {
+    #[snafu(display("Failed to read JWT secret {}: {}", path, source))]
+    ReadSecret {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("JWT secret {} is not a 32 byte hex string", path))]
+    InvalidSecret { path: String },
+
+    #[snafu(display("Credentials contain characters invalid in headers"))]
+    InvalidCredentials {},
+}
+
+/// Value of the `Authorization` header for `auth`, made of visible ASCII
+/// only. JWTs are issued anew on every call.
+pub fn authorization(auth: &HttpAuth) -> Result<String, AuthError> {
+    let value = match auth {
+        HttpAuth::Bearer { token } => format!("Bearer {}", token),
+        HttpAuth::Basic { username, password } => format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", username, password))
+        ),
+        HttpAuth::Jwt { secret_path } => {
+            let secret = read_secret(secret_path)?;
+            format!("Bearer {}", jwt(&secret, now()))
+        }
+    };
+
+    ensure!(
+        value.bytes().all(|b| (b' '..=b'~').contains(&b)),
+        InvalidCredentials
+    );
+    Ok(value)
+}
+
+fn read_secret(path: &str) -> Result<Vec<u8>, AuthError> {
+    let secret = std::fs::read_to_string(path).context(ReadSecret { path })?;
+    let secret = secret.trim();
+    let secret = secret.strip_prefix("0x").unwrap_or(secret);
+
+    match hex::decode(secret) {
+        Ok(secret) if secret.len() == 32 => Ok(secret),
+        _ => InvalidSecret { path }.fail(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before the unix epoch")
+        .as_secs()
+}
+
+/// HS256 token carrying only the issued-at claim.
+fn jwt(secret: &[u8], issued_at: u64) -> String {
+    let encode =
+        |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+
+    let header = encode(
+        json!({ "alg": "HS256", "typ": "JWT" })
+            .to_string()
+            .as_bytes(),
+    );
+    let claims = encode(json!({ "iat": issued_at }).to_string().as_bytes());
+    let message = format!("{}.{}", header, claims);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .expect("HMAC accepts keys of any size");
+    mac.update(message.as_bytes());
+    let signature = encode(&mac.finalize().into_bytes());
+
+    format!("{}.{}", message, signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jwt_test() {
+        assert_eq!(
+            jwt(&[0u8; 32], 1_600_000_000),
+            "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+             eyJpYXQiOjE2MDAwMDAwMDB9.\
+             J_opWmKHpxoJhVkh04qPWjD-qbsLE9lZWUsv3UNBNBA"
+        );
+    }
+}
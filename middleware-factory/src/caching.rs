@@ -0,0 +1,548 @@
+use crate::{
+    ErrorClass, LayerFactory, MiddlewareFactory, MiddlewareSlot, RebuildReason,
+    Result,
+};
+
+use async_trait::async_trait;
+use offchain_core::ethers::providers::{FromErr, Middleware};
+use offchain_core::ethers::types::{
+    transaction::eip2718::TypedTransaction, Block, BlockId, Bytes, Transaction,
+    TransactionReceipt, TxHash, U256, U64,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use snafu::Snafu;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Maximum number of cached responses.
+    pub capacity: usize,
+    /// Depth at which a receipt is considered final and may be cached.
+    pub confirmations: u64,
+    /// Minimum time between fetches of the head, to tell whether a receipt is
+    /// final, when it isn't final by the last head seen.
+    pub head_refresh: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            capacity: 1024,
+            confirmations: 12,
+            head_refresh: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Entry {
+    value: Value,
+    last_used: u64,
+}
+
+/// Least recently used cache of JSON responses.
+struct Lru {
+    entries: HashMap<String, Entry>,
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Value> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        let key = self.by_use.remove(&entry.last_used)?;
+        entry.last_used = self.clock;
+        self.by_use.insert(self.clock, key);
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: Value) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.clock += 1;
+        if let Some(old) = self.entries.remove(&key) {
+            self.by_use.remove(&old.last_used);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.by_use.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                last_used: self.clock,
+            },
+        );
+    }
+}
+
+/// Cached responses and counters, shared by every `CachingMiddleware` built
+/// by the same `CachingFactory`, so they survive middleware rebuilds.
+pub struct CacheState {
+    lru: std::sync::Mutex<Lru>,
+    confirmations: u64,
+    head: AtomicU64,
+    head_refresh: Duration,
+    head_fetched_at: std::sync::Mutex<Option<Instant>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheState {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            lru: std::sync::Mutex::new(Lru::new(config.capacity)),
+            confirmations: config.confirmations,
+            head: AtomicU64::new(0),
+            head_refresh: config.head_refresh,
+            head_fetched_at: std::sync::Mutex::new(None),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::SeqCst),
+            misses: self.misses.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the head may be fetched again, in which case it counts as
+    /// fetched now, so concurrent callers don't fetch it too.
+    fn take_head_refresh(&self) -> bool {
+        let mut fetched_at = self.head_fetched_at.lock().unwrap();
+        match *fetched_at {
+            Some(at) if at.elapsed() < self.head_refresh => false,
+            _ => {
+                *fetched_at = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self
+            .lru
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|value| serde_json::from_value(value).ok());
+
+        let counter = match value {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        value
+    }
+
+    fn insert<T: Serialize>(&self, key: String, value: &T) {
+        if let Ok(value) = serde_json::to_value(value) {
+            self.lru.lock().unwrap().insert(key, value);
+        }
+    }
+}
+
+impl std::fmt::Debug for CacheState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CacheState")
+            .field("len", &self.len())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+fn key(method: &str, params: Value) -> String {
+    format!("{}:{}", method, params)
+}
+
+/// Middleware caching the responses that can't change: the chain id, blocks
+/// by hash, `eth_call`s pinned to a block hash, and receipts once they are
+/// `confirmations` deep. Every other request goes straight to the inner
+/// middleware.
+///
+/// None of them need invalidating on reorgs: blocks and calls are keyed by
+/// block hash, so a reorg only makes them unreachable, and receipts are only
+/// cached past the depth taken as final, which reorgs don't reach.
+#[derive(Debug)]
+pub struct CachingMiddleware<M> {
+    inner: M,
+    state: Arc<CacheState>,
+}
+
+impl<M> CachingMiddleware<M> {
+    pub fn new(inner: M, state: Arc<CacheState>) -> Self {
+        Self { inner, state }
+    }
+
+    pub fn state(&self) -> &Arc<CacheState> {
+        &self.state
+    }
+}
+
+impl<M: Middleware + 'static> CachingMiddleware<M> {
+    /// Whether `block` is deep enough, fetching the head if the last one
+    /// seen says it isn't, at most once every `head_refresh`.
+    async fn is_final(
+        &self,
+        block: u64,
+    ) -> std::result::Result<bool, CachingError<M>> {
+        let confirmations = self.state.confirmations;
+        if block + confirmations <= self.state.head.load(Ordering::SeqCst) {
+            return Ok(true);
+        }
+
+        if !self.state.take_head_refresh() {
+            return Ok(false);
+        }
+        let head = self.get_block_number().await?.as_u64();
+        Ok(block + confirmations <= head)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum CachingError<M: Middleware + 'static> {
+    #[snafu(display("Middleware error: {}", source))]
+    MiddlewareError { source: M::Error },
+}
+
+impl<M: Middleware> FromErr<M::Error> for CachingError<M> {
+    fn from(src: M::Error) -> Self {
+        CachingError::MiddlewareError { source: src }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> Middleware for CachingMiddleware<M> {
+    type Error = CachingError<M>;
+    type Provider = M::Provider;
+    type Inner = M;
+
+    fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    async fn get_block_number(&self) -> std::result::Result<U64, Self::Error> {
+        let number =
+            self.inner.get_block_number().await.map_err(FromErr::from)?;
+        self.state.head.fetch_max(number.as_u64(), Ordering::SeqCst);
+        Ok(number)
+    }
+
+    async fn get_chainid(&self) -> std::result::Result<U256, Self::Error> {
+        let key = key("eth_chainId", Value::Null);
+        if let Some(chain_id) = self.state.get(&key) {
+            return Ok(chain_id);
+        }
+
+        let chain_id = self.inner.get_chainid().await.map_err(FromErr::from)?;
+        self.state.insert(key, &chain_id);
+        Ok(chain_id)
+    }
+
+    async fn get_block<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<Option<Block<TxHash>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let hash = match block_hash_or_number.into() {
+            BlockId::Hash(hash) => hash,
+            id => return self.inner.get_block(id).await.map_err(FromErr::from),
+        };
+
+        let key = key("eth_getBlockByHash", json!([hash, false]));
+        if let Some(block) = self.state.get(&key) {
+            return Ok(Some(block));
+        }
+
+        let block = self.inner.get_block(hash).await.map_err(FromErr::from)?;
+        if let Some(block) = &block {
+            self.state.insert(key, block);
+        }
+        Ok(block)
+    }
+
+    async fn get_block_with_txs<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<Option<Block<Transaction>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let hash = match block_hash_or_number.into() {
+            BlockId::Hash(hash) => hash,
+            id => {
+                return self
+                    .inner
+                    .get_block_with_txs(id)
+                    .await
+                    .map_err(FromErr::from)
+            }
+        };
+
+        let key = key("eth_getBlockByHash", json!([hash, true]));
+        if let Some(block) = self.state.get(&key) {
+            return Ok(Some(block));
+        }
+
+        let block = self
+            .inner
+            .get_block_with_txs(hash)
+            .await
+            .map_err(FromErr::from)?;
+        if let Some(block) = &block {
+            self.state.insert(key, block);
+        }
+        Ok(block)
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> std::result::Result<Bytes, Self::Error> {
+        let hash = match block {
+            Some(BlockId::Hash(hash)) => hash,
+            _ => {
+                return self.inner.call(tx, block).await.map_err(FromErr::from)
+            }
+        };
+
+        let key = key("eth_call", json!([tx, hash]));
+        if let Some(output) = self.state.get(&key) {
+            return Ok(output);
+        }
+
+        let output = self.inner.call(tx, block).await.map_err(FromErr::from)?;
+        self.state.insert(key, &output);
+        Ok(output)
+    }
+
+    async fn get_transaction_receipt<T>(
+        &self,
+        transaction_hash: T,
+    ) -> std::result::Result<Option<TransactionReceipt>, Self::Error>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        let hash = transaction_hash.into();
+        let key = key("eth_getTransactionReceipt", json!([hash]));
+        if let Some(receipt) = self.state.get(&key) {
+            return Ok(Some(receipt));
+        }
+
+        let receipt = self
+            .inner
+            .get_transaction_receipt(hash)
+            .await
+            .map_err(FromErr::from)?;
+
+        if let Some(block) = receipt.as_ref().and_then(|r| r.block_number) {
+            let block = block.as_u64();
+            if self.is_final(block).await? {
+                self.state.insert(key, &receipt);
+            }
+        }
+        Ok(receipt)
+    }
+}
+
+///
+/// Caching Middleware Factory
+pub struct CachingFactory<IF: MiddlewareFactory> {
+    current: MiddlewareSlot<Arc<CachingMiddleware<IF::Middleware>>>,
+    inner_factory: Arc<IF>,
+    state: Arc<CacheState>,
+}
+
+impl<IF: MiddlewareFactory + Send + Sync> CachingFactory<IF> {
+    pub async fn new(
+        inner_factory: Arc<IF>,
+        config: CacheConfig,
+    ) -> Result<Arc<Self>> {
+        let inner = inner_factory.current();
+        let state = Arc::new(CacheState::new(&config));
+
+        Ok(Arc::new(Self {
+            current: MiddlewareSlot::with_inner(
+                Arc::new(CachingMiddleware::new(
+                    inner.middleware,
+                    Arc::clone(&state),
+                )),
+                inner.generation,
+            ),
+            inner_factory,
+            state,
+        }))
+    }
+
+    pub fn state(&self) -> &Arc<CacheState> {
+        &self.state
+    }
+}
+
+#[async_trait]
+impl<IF> MiddlewareFactory for CachingFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type Middleware = Arc<CachingMiddleware<IF::Middleware>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.current
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        match err {
+            CachingError::MiddlewareError { source } => IF::classify(source),
+        }
+    }
+
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        self.rebuild_layer().await
+    }
+}
+
+#[async_trait]
+impl<IF> LayerFactory for CachingFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type InnerFactory = IF;
+
+    fn inner_factory(&self) -> &Self::InnerFactory {
+        &self.inner_factory
+    }
+
+    fn build(&self, inner_middleware: IF::Middleware) -> Self::Middleware {
+        Arc::new(CachingMiddleware::new(
+            inner_middleware,
+            Arc::clone(&self.state),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpProviderFactory;
+    use offchain_core::ethers::providers::Provider;
+    use offchain_core::ethers::types::{BlockNumber, H256};
+
+    #[tokio::test]
+    async fn caching_test() {
+        let state = Arc::new(CacheState::new(&CacheConfig {
+            capacity: 8,
+            confirmations: 2,
+            head_refresh: Duration::from_secs(3600),
+        }));
+        let (provider, mock) = Provider::mocked();
+        let m = CachingMiddleware::new(provider, Arc::clone(&state));
+
+        mock.push(U256::from(1337)).unwrap();
+        assert_eq!(m.get_chainid().await.unwrap(), 1337.into());
+        assert_eq!(m.get_chainid().await.unwrap(), 1337.into());
+
+        // Blocks by number may change, blocks by hash can't.
+        let block = Block::<TxHash> {
+            hash: Some(H256::from_low_u64_be(1)),
+            ..Default::default()
+        };
+        mock.push(block.clone()).unwrap();
+        mock.push(block.clone()).unwrap();
+        m.get_block(BlockNumber::Latest).await.unwrap();
+        m.get_block(H256::from_low_u64_be(1)).await.unwrap();
+        assert_eq!(
+            m.get_block(H256::from_low_u64_be(1)).await.unwrap(),
+            Some(block)
+        );
+
+        // The receipt is only cached once final, and the head is not fetched
+        // again before `head_refresh`, until a newer one is seen. Responses
+        // are popped from the back.
+        let tx = H256::from_low_u64_be(2);
+        let receipt = TransactionReceipt {
+            transaction_hash: tx,
+            block_number: Some(10.into()),
+            ..Default::default()
+        };
+        mock.push(receipt.clone()).unwrap();
+        mock.push(U64::from(12)).unwrap();
+        mock.push(receipt.clone()).unwrap();
+        mock.push(U64::from(11)).unwrap();
+        mock.push(receipt.clone()).unwrap();
+        m.get_transaction_receipt(tx).await.unwrap();
+        m.get_transaction_receipt(tx).await.unwrap();
+        m.get_block_number().await.unwrap();
+        m.get_transaction_receipt(tx).await.unwrap();
+        assert_eq!(m.get_transaction_receipt(tx).await.unwrap(), Some(receipt));
+
+        assert_eq!(state.stats(), CacheStats { hits: 3, misses: 5 });
+        assert_eq!(state.len(), 3);
+    }
+
+    #[test]
+    fn lru_test() {
+        let mut lru = Lru::new(2);
+        lru.insert("a".to_string(), json!(1));
+        lru.insert("b".to_string(), json!(2));
+        assert_eq!(lru.get("a"), Some(json!(1)));
+
+        // "b" is the least recently used.
+        lru.insert("c".to_string(), json!(3));
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(json!(1)));
+        assert_eq!(lru.get("c"), Some(json!(3)));
+    }
+
+    #[tokio::test]
+    async fn caching_factory_test() {
+        let root_factory =
+            HttpProviderFactory::new("http://localhost:8545".to_string())
+                .unwrap();
+        let factory = CachingFactory::new(root_factory, CacheConfig::default())
+            .await
+            .unwrap();
+
+        let m = factory.new_middleware(None).await.unwrap();
+        let m2 = factory.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+        assert!(Arc::ptr_eq(m.middleware.state(), m2.middleware.state()));
+    }
+}
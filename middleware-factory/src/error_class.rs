@@ -0,0 +1,330 @@
+use crate::http::HttpTransportError;
+use crate::quorum::QuorumError;
+
+use offchain_core::ethers::providers::{MockError, ProviderError};
+use std::error::Error as StdError;
+use std::fmt;
+
+/// What went wrong with a request, as far as deciding what to do next goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// The connection failed, timed out or was dropped.
+    Transport,
+    /// The node refuses to serve more requests for now.
+    RateLimited,
+    /// The node hasn't caught up with the chain yet, such as when it doesn't
+    /// know the block asked for.
+    NodeBehind,
+    /// The nonce of the transaction is out of sync with the node.
+    Nonce,
+    /// The node already has the transaction, so it was sent before.
+    AlreadyKnown,
+    /// The fees of the transaction are too low for the node, such as a
+    /// replacement that doesn't bump them enough.
+    Fee,
+    /// The execution reverted.
+    Revert,
+    /// Anything else. Trying again won't help.
+    Permanent,
+}
+
+impl ErrorClass {
+    /// Whether rebuilding the middleware and trying again may succeed.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorClass::Transport
+                | ErrorClass::RateLimited
+                | ErrorClass::NodeBehind
+        )
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorClass::Transport => "transport",
+            ErrorClass::RateLimited => "rate_limited",
+            ErrorClass::NodeBehind => "node_behind",
+            ErrorClass::Nonce => "nonce",
+            ErrorClass::AlreadyKnown => "already_known",
+            ErrorClass::Fee => "fee",
+            ErrorClass::Revert => "revert",
+            ErrorClass::Permanent => "permanent",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Classifies the errors of providers over any of the transports in this
+/// crate or in `ethers`.
+pub fn classify_provider_error(err: &ProviderError) -> ErrorClass {
+    match err {
+        ProviderError::JsonRpcClientError(err) => {
+            classify_client_error(err.as_ref())
+        }
+        _ => ErrorClass::Permanent,
+    }
+}
+
+/// Classifies the error of a `JsonRpcClient`. The transport errors of
+/// `ethers` are private, so they are told apart by their message, and taken
+/// as transport errors unless they hold a JSON-RPC error.
+pub fn classify_client_error(err: &(dyn StdError + 'static)) -> ErrorClass {
+    if let Some(err) = err.downcast_ref::<HttpTransportError>() {
+        return classify_http_error(err);
+    }
+    if let Some(err) = err.downcast_ref::<QuorumError>() {
+        return match err {
+            // Members may just be at different heights.
+            QuorumError::Disagreement { .. } => ErrorClass::NodeBehind,
+            QuorumError::NoQuorum { .. } => ErrorClass::Transport,
+            QuorumError::MemberError { source } => {
+                classify_provider_error(source)
+            }
+            QuorumError::SerdeJson { .. } => ErrorClass::Permanent,
+        };
+    }
+    if let Some(err) = err.downcast_ref::<ProviderError>() {
+        return classify_provider_error(err);
+    }
+    if let Some(err) = err.downcast_ref::<MockError>() {
+        return match err {
+            MockError::SerdeJson(_) => ErrorClass::Permanent,
+            _ => ErrorClass::Transport,
+        };
+    }
+
+    let message = err.to_string();
+    match parse_json_rpc_error(&message) {
+        Some((code, message)) => classify_json_rpc_error(code, message),
+        None => ErrorClass::Transport,
+    }
+}
+
+/// Code and message of the JSON-RPC error response behind `err`, if the node
+/// answered with one.
+pub fn json_rpc_error(err: &ProviderError) -> Option<(i64, String)> {
+    let err: &(dyn StdError + 'static) = match err {
+        ProviderError::JsonRpcClientError(err) => err.as_ref(),
+        _ => return None,
+    };
+
+    if let Some(err) = err.downcast_ref::<HttpTransportError>() {
+        return match err {
+            HttpTransportError::JsonRpcError { code, message, .. } => {
+                Some((*code, message.clone()))
+            }
+            _ => None,
+        };
+    }
+    if let Some(QuorumError::MemberError { source }) = err.downcast_ref() {
+        return json_rpc_error(source);
+    }
+    if let Some(err) = err.downcast_ref::<ProviderError>() {
+        return json_rpc_error(err);
+    }
+
+    parse_json_rpc_error(&err.to_string())
+        .map(|(code, message)| (code, message.to_string()))
+}
+
+fn classify_http_error(err: &HttpTransportError) -> ErrorClass {
+    match err {
+        HttpTransportError::AuthenticationError { .. } => ErrorClass::Permanent,
+        HttpTransportError::RequestError { source } => match source.status() {
+            Some(status) => classify_http_status(status.as_u16()),
+            None => ErrorClass::Transport,
+        },
+        HttpTransportError::StatusError { status, .. } => {
+            classify_http_status(*status)
+        }
+        // Likely a truncated response or a proxy error page.
+        HttpTransportError::DecodeError { .. } => ErrorClass::Transport,
+        HttpTransportError::JsonRpcError { code, message, .. } => {
+            classify_json_rpc_error(*code, message)
+        }
+    }
+}
+
+pub fn classify_http_status(status: u16) -> ErrorClass {
+    match status {
+        429 => ErrorClass::RateLimited,
+        408 | 500..=599 => ErrorClass::Transport,
+        _ => ErrorClass::Permanent,
+    }
+}
+
+/// Classifies a JSON-RPC error response. Nodes mostly tell errors apart by
+/// their message, so the code alone is seldom enough.
+pub fn classify_json_rpc_error(code: i64, message: &str) -> ErrorClass {
+    let message = message.to_lowercase();
+    let contains =
+        |patterns: &[&str]| patterns.iter().any(|p| message.contains(p));
+
+    if code == 429
+        || code == -32005
+        || contains(&["rate limit", "too many requests", "limit exceeded"])
+    {
+        ErrorClass::RateLimited
+    } else if is_known_message(&message) {
+        ErrorClass::AlreadyKnown
+    } else if is_nonce_message(&message) {
+        ErrorClass::Nonce
+    } else if is_underpriced_message(&message) {
+        ErrorClass::Fee
+    } else if code == 3 || contains(&["revert"]) {
+        ErrorClass::Revert
+    } else if contains(&[
+        "header not found",
+        "unknown block",
+        "block not found",
+        "missing trie node",
+        "syncing",
+    ]) {
+        ErrorClass::NodeBehind
+    } else if code == -32603 {
+        // Internal errors of the node.
+        ErrorClass::Transport
+    } else {
+        ErrorClass::Permanent
+    }
+}
+
+/// Whether a node error means the nonce of the transaction is out of sync.
+/// Nodes only report it through the error message, as they do for the other
+/// transaction errors below.
+pub fn is_nonce_message(message: &str) -> bool {
+    contains_any(
+        message,
+        &["nonce too low", "nonce too high", "invalid nonce"],
+    )
+}
+
+/// Whether a node error means it already has the transaction.
+pub fn is_known_message(message: &str) -> bool {
+    contains_any(message, &["already known", "known transaction"])
+}
+
+/// Whether a node error means the fees of the transaction are too low.
+pub fn is_underpriced_message(message: &str) -> bool {
+    contains_any(
+        message,
+        &["underpriced", "fee too low", "less than block base fee"],
+    )
+}
+
+fn contains_any(message: &str, patterns: &[&str]) -> bool {
+    let message = message.to_lowercase();
+    patterns.iter().any(|pattern| message.contains(pattern))
+}
+
+/// Code and message of a JSON-RPC error formatted by `ethers`, as in
+/// `(code: -32000, message: nonce too low, data: None)`.
+fn parse_json_rpc_error(text: &str) -> Option<(i64, &str)> {
+    let rest = text.strip_prefix("(code: ")?;
+    let (code, rest) = rest.split_once(", message: ")?;
+    let (message, _) = rest.rsplit_once(", data: ")?;
+
+    Some((code.parse().ok()?, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_rpc_error_test() {
+        let cases = [
+            (
+                -32005,
+                "daily request count exceeded",
+                ErrorClass::RateLimited,
+            ),
+            (-32000, "Nonce too low", ErrorClass::Nonce),
+            (-32000, "already known", ErrorClass::AlreadyKnown),
+            (-32000, "transaction underpriced", ErrorClass::Fee),
+            (3, "execution reverted: not owner", ErrorClass::Revert),
+            (-32000, "header not found", ErrorClass::NodeBehind),
+            (-32603, "internal error", ErrorClass::Transport),
+            (-32601, "method not found", ErrorClass::Permanent),
+        ];
+
+        for (code, message, class) in cases {
+            assert_eq!(classify_json_rpc_error(code, message), class);
+        }
+    }
+
+    #[test]
+    fn provider_error_test() {
+        let classify = |err: HttpTransportError| {
+            classify_provider_error(&ProviderError::from(err))
+        };
+
+        assert_eq!(
+            classify(HttpTransportError::StatusError {
+                status: 429,
+                text: "slow down".to_string(),
+            }),
+            ErrorClass::RateLimited
+        );
+        assert_eq!(
+            classify(HttpTransportError::JsonRpcError {
+                code: -32000,
+                message: "replacement transaction underpriced".to_string(),
+                data: None,
+            }),
+            ErrorClass::Fee
+        );
+
+        // Errors of the `ethers` transports only expose their message.
+        let err = ProviderError::JsonRpcClientError(
+            "(code: -32000, message: missing trie node 1f2e, data: None)"
+                .into(),
+        );
+        assert_eq!(classify_provider_error(&err), ErrorClass::NodeBehind);
+
+        let err = ProviderError::JsonRpcClientError(
+            "Websocket closed with info: None".into(),
+        );
+        assert_eq!(classify_provider_error(&err), ErrorClass::Transport);
+
+        let err = ProviderError::CustomError("invalid address".to_string());
+        assert_eq!(classify_provider_error(&err), ErrorClass::Permanent);
+
+        let err = ProviderError::from(HttpTransportError::JsonRpcError {
+            code: -32602,
+            message: "invalid argument 0".to_string(),
+            data: None,
+        });
+        assert_eq!(
+            json_rpc_error(&err),
+            Some((-32602, "invalid argument 0".to_string()))
+        );
+        let err = ProviderError::JsonRpcClientError(
+            "Websocket closed with info: None".into(),
+        );
+        assert_eq!(json_rpc_error(&err), None);
+    }
+
+    #[test]
+    fn transaction_message_test() {
+        assert!(is_nonce_message("Nonce too low"));
+        assert!(!is_nonce_message("(code: -32000, message: already known)"));
+        assert!(!is_nonce_message(
+            "insufficient funds for gas * price + value"
+        ));
+
+        assert!(is_known_message("(code: -32000, message: already known)"));
+        assert!(is_known_message("Known transaction: 0x12"));
+        assert!(!is_known_message("nonce too low"));
+
+        assert!(is_underpriced_message(
+            "replacement transaction underpriced"
+        ));
+        assert!(!is_underpriced_message("nonce too low"));
+    }
+}
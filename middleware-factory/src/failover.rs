@@ -0,0 +1,540 @@
+use crate::{
+    classify_provider_error, verify_chain_id, Error, ErrorClass,
+    MiddlewareFactory, MiddlewareSlot, NoEndpoints, ParseError, ProviderError,
+    RebuildReason, Result, RetryLimitReached, RetryPreferredTooShort,
+};
+
+use async_trait::async_trait;
+use configuration::WsOptions;
+#[cfg(unix)]
+use offchain_core::ethers::providers::Ipc;
+use offchain_core::ethers::providers::{
+    Http, JsonRpcClient, Middleware, Provider, Ws,
+};
+use snafu::{ensure, ResultExt};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// Transports that `FailoverProviderFactory` can connect to.
+#[async_trait]
+pub trait Connect: JsonRpcClient + Sized {
+    /// Options applied to every connection.
+    type Options: Clone + Default + Send + Sync;
+
+    async fn connect(
+        url: &str,
+        options: &Self::Options,
+    ) -> Result<Provider<Self>>;
+}
+
+#[async_trait]
+impl Connect for Ws {
+    type Options = WsOptions;
+
+    async fn connect(url: &str, options: &WsOptions) -> Result<Provider<Self>> {
+        crate::ws::connect(url, options).await.map(Provider::new)
+    }
+}
+
+/// The url is the path to the socket.
+#[cfg(unix)]
+#[async_trait]
+impl Connect for Ipc {
+    type Options = ();
+
+    async fn connect(url: &str, _: &()) -> Result<Provider<Self>> {
+        Provider::connect_ipc(url).await.context(ProviderError)
+    }
+}
+
+/// HTTP transports, which make requests on demand and so are created without
+/// reaching the endpoint.
+pub trait HttpConnect: JsonRpcClient + Sized {
+    /// Options applied to every request.
+    type Options: Clone + Default + Send + Sync;
+
+    fn new_client(url: &str, options: &Self::Options) -> Result<Self>;
+}
+
+impl HttpConnect for Http {
+    type Options = ();
+
+    fn new_client(url: &str, _: &()) -> Result<Self> {
+        Http::from_str(url).context(ParseError)
+    }
+}
+
+/// The endpoint is probed for a block number to know whether it's reachable.
+#[async_trait]
+impl<T: HttpConnect> Connect for T {
+    type Options = T::Options;
+
+    async fn connect(url: &str, options: &T::Options) -> Result<Provider<T>> {
+        let provider = Provider::new(T::new_client(url, options)?);
+        provider.get_block_number().await.context(ProviderError)?;
+        Ok(provider)
+    }
+}
+
+/// Health of a single endpoint, as seen by the factory.
+#[derive(Clone, Debug)]
+pub struct EndpointHealth {
+    pub url: String,
+    /// Failures since the last successful connection.
+    pub failures: usize,
+    pub last_failure: Option<Instant>,
+}
+
+impl EndpointHealth {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            failures: 0,
+            last_failure: None,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.failures == 0
+    }
+
+    /// Unhealthy endpoints are retried once `cooldown` has passed since
+    /// their last failure.
+    fn is_available(&self, cooldown: Duration) -> bool {
+        match self.last_failure {
+            Some(last_failure) if !self.is_healthy() => {
+                last_failure.elapsed() >= cooldown
+            }
+            _ => true,
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    fn failed(&mut self) {
+        self.failures += 1;
+        self.last_failure = Some(Instant::now());
+    }
+}
+
+/// Shortest `retry_preferred_after` accepted, as the background task tries
+/// the preferred endpoints that often.
+pub const MIN_RETRY_PREFERRED_AFTER: Duration = Duration::from_millis(100);
+
+struct State {
+    current: usize,
+    endpoints: Vec<EndpointHealth>,
+}
+
+///
+/// "Root" Failover Middleware Factory
+///
+/// Connects to the first available endpoint of an ordered list, the first one
+/// being the preferred. Rebuilding the middleware marks the current endpoint
+/// as failed and moves to the next available one. Failed endpoints become
+/// available again after `retry_preferred_after`, at which point the factory
+/// tries to go back to the more preferred ones, in a background task that
+/// stops when the factory is dropped. Endpoints on a chain other than the
+/// expected one count as failed. `retry_preferred_after` must be at least
+/// `MIN_RETRY_PREFERRED_AFTER`.
+pub struct FailoverProviderFactory<T: Connect> {
+    provider: MiddlewareSlot<Arc<Provider<T>>>,
+    // Only changed by the rebuild in flight, and never locked while
+    // connecting, so that health queries don't wait on it.
+    state: Mutex<State>,
+    options: T::Options,
+    expected_chain_id: Option<u64>,
+    retry_preferred_after: Duration,
+    max_retries: usize,
+    max_delay: Duration,
+    task: JoinHandle<()>,
+}
+
+impl<T: Connect + 'static> FailoverProviderFactory<T> {
+    pub async fn new(
+        urls: Vec<String>,
+        retry_preferred_after: Duration,
+        max_retries: usize,
+        max_delay: Duration,
+    ) -> Result<Arc<Self>> {
+        Self::new_with_options(
+            urls,
+            T::Options::default(),
+            None,
+            retry_preferred_after,
+            max_retries,
+            max_delay,
+        )
+        .await
+    }
+
+    pub async fn new_with_options(
+        urls: Vec<String>,
+        options: T::Options,
+        expected_chain_id: Option<u64>,
+        retry_preferred_after: Duration,
+        max_retries: usize,
+        max_delay: Duration,
+    ) -> Result<Arc<Self>> {
+        ensure!(!urls.is_empty(), NoEndpoints);
+        ensure!(
+            retry_preferred_after >= MIN_RETRY_PREFERRED_AFTER,
+            RetryPreferredTooShort {
+                min: MIN_RETRY_PREFERRED_AFTER,
+                given: retry_preferred_after,
+            }
+        );
+
+        let state = Mutex::new(State {
+            current: 0,
+            endpoints: urls.into_iter().map(EndpointHealth::new).collect(),
+        });
+        let (current, provider) = connect_any(
+            &state,
+            &options,
+            expected_chain_id,
+            None,
+            retry_preferred_after,
+            max_retries,
+            max_delay,
+        )
+        .await?;
+        let mut state = state.into_inner();
+        state.current = current;
+
+        Ok(Arc::new_cyclic(|factory: &Weak<Self>| Self {
+            provider: MiddlewareSlot::new(Arc::new(provider)),
+            state: Mutex::new(state),
+            options,
+            expected_chain_id,
+            retry_preferred_after,
+            max_retries,
+            max_delay,
+            task: tokio::spawn(fail_back_every(
+                Weak::clone(factory),
+                retry_preferred_after,
+            )),
+        }))
+    }
+
+    /// Url of the endpoint the current middleware is connected to.
+    pub async fn current_endpoint(&self) -> String {
+        let state = self.state.lock().await;
+        state.endpoints[state.current].url.clone()
+    }
+
+    /// Health of every endpoint, in order of preference.
+    pub async fn health(&self) -> Vec<EndpointHealth> {
+        self.state.lock().await.endpoints.clone()
+    }
+
+    /// Tries to connect to an endpoint more preferred than the current one,
+    /// if any of them is available.
+    async fn try_preferred(&self) -> Option<(Arc<Provider<T>>, RebuildReason)> {
+        let (index, url) = {
+            let state = self.state.lock().await;
+            let index = (0..state.current).find(|&i| {
+                state.endpoints[i].is_available(self.retry_preferred_after)
+            })?;
+            (index, state.endpoints[index].url.clone())
+        };
+
+        let result = connect(&url, &self.options, self.expected_chain_id).await;
+        let mut state = self.state.lock().await;
+        match result {
+            Ok(provider) => {
+                state.endpoints[index].succeeded();
+                state.current = index;
+                let reason = RebuildReason::EndpointChanged { endpoint: url };
+                Some((Arc::new(provider), reason))
+            }
+
+            // Keep the current endpoint until the next cooldown.
+            Err(_) => {
+                state.endpoints[index].failed();
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Connect + 'static> MiddlewareFactory for FailoverProviderFactory<T> {
+    type Middleware = Arc<Provider<T>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.provider
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        classify_provider_error(err)
+    }
+
+    /// Marks the current endpoint as failed and moves to the next available
+    /// one.
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        let current = {
+            let mut state = self.state.lock().await;
+            let current = state.current;
+            state.endpoints[current].failed();
+            current
+        };
+
+        let (index, provider) = connect_any(
+            &self.state,
+            &self.options,
+            self.expected_chain_id,
+            Some(current),
+            self.retry_preferred_after,
+            self.max_retries,
+            self.max_delay,
+        )
+        .await?;
+
+        let mut state = self.state.lock().await;
+        state.current = index;
+        let reason = if index == current {
+            RebuildReason::Stale
+        } else {
+            RebuildReason::EndpointChanged {
+                endpoint: state.endpoints[index].url.clone(),
+            }
+        };
+        Ok((Arc::new(provider), reason))
+    }
+}
+
+impl<T: Connect> Drop for FailoverProviderFactory<T> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Tries to go back to a more preferred endpoint every `cooldown`, until the
+/// factory is dropped.
+async fn fail_back_every<T: Connect + 'static>(
+    factory: Weak<FailoverProviderFactory<T>>,
+    cooldown: Duration,
+) {
+    loop {
+        tokio::time::sleep(cooldown).await;
+
+        let factory = match factory.upgrade() {
+            Some(factory) => factory,
+            None => return,
+        };
+        factory
+            .provider
+            .try_update(|| factory.try_preferred())
+            .await;
+    }
+}
+
+async fn connect<T: Connect>(
+    url: &str,
+    options: &T::Options,
+    expected_chain_id: Option<u64>,
+) -> Result<Provider<T>> {
+    let provider = T::connect(url, options).await?;
+    verify_chain_id(provider, expected_chain_id).await
+}
+
+/// Connects to the first available endpoint in order of preference, followed
+/// by the unavailable ones in rotation order after `current`. Retries with
+/// backoff if all of them fail. `state` is only locked to pick the candidates
+/// and to record each outcome; its current endpoint is left to the caller.
+async fn connect_any<T: Connect>(
+    state: &Mutex<State>,
+    options: &T::Options,
+    expected_chain_id: Option<u64>,
+    current: Option<usize>,
+    cooldown: Duration,
+    max_retries: usize,
+    max_delay: Duration,
+) -> Result<(usize, Provider<T>)> {
+    let mut backoff = backoff::Backoff::new(max_retries, max_delay);
+
+    loop {
+        let mut last_error = None;
+        let candidates: Vec<_> = {
+            let state = state.lock().await;
+            candidates(&state.endpoints, current, cooldown)
+                .into_iter()
+                .map(|i| (i, state.endpoints[i].url.clone()))
+                .collect()
+        };
+
+        for (index, url) in candidates {
+            let result = connect::<T>(&url, options, expected_chain_id).await;
+            let endpoint = &mut state.lock().await.endpoints[index];
+            match result {
+                Ok(provider) => {
+                    endpoint.succeeded();
+                    return Ok((index, provider));
+                }
+
+                Err(e) => {
+                    endpoint.failed();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        if backoff.wait().await.is_err() {
+            // There is at least one candidate, so there is an error.
+            let last_error: Error = last_error.unwrap();
+            return RetryLimitReached {
+                retries: max_retries,
+                last_error: Box::new(last_error),
+            }
+            .fail();
+        }
+    }
+}
+
+fn candidates(
+    endpoints: &[EndpointHealth],
+    current: Option<usize>,
+    cooldown: Duration,
+) -> Vec<usize> {
+    let count = endpoints.len();
+    let start = current.map(|current| current + 1).unwrap_or(0);
+
+    let (mut available, unavailable): (Vec<_>, Vec<_>) = (0..count)
+        .filter(|&i| Some(i) != current)
+        .partition(|&i| endpoints[i].is_available(cooldown));
+
+    let mut rotation = unavailable;
+    rotation.sort_by_key(|&i| (i + count - start) % count);
+
+    available.extend(rotation);
+    // The current endpoint is the last resort.
+    available.extend(current);
+    available
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_server, Rebuild};
+    use serde_json::json;
+
+    /// Urls of endpoints answering every request.
+    async fn urls() -> Vec<String> {
+        let mut urls = Vec::new();
+        for _ in 0..3 {
+            let url = test_server::serve(|request| async move {
+                Some(request.result(json!("0x1")))
+            })
+            .await;
+            urls.push(url);
+        }
+        urls
+    }
+
+    #[tokio::test]
+    async fn rotation_test() {
+        let urls = urls().await;
+        let factory = FailoverProviderFactory::<Http>::new(
+            urls.clone(),
+            Duration::from_secs(3600),
+            0,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        let mut m = factory.new_middleware(None).await.unwrap();
+        for expected in [1, 2, 0, 1] {
+            m = factory.new_middleware(Some(&m)).await.unwrap();
+            assert_eq!(factory.current_endpoint().await, urls[expected]);
+        }
+
+        assert_eq!(
+            factory.generation().latest(),
+            Rebuild {
+                generation: 4,
+                reason: RebuildReason::EndpointChanged {
+                    endpoint: urls[1].clone(),
+                },
+            }
+        );
+
+        let health = factory.health().await;
+        assert_eq!(health[0].failures, 1);
+        assert!(health[1].is_healthy());
+        assert!(!health[2].is_healthy());
+
+        let m_same = factory.new_middleware(None).await.unwrap();
+        assert!(Arc::ptr_eq(&m.middleware, &m_same.middleware));
+    }
+
+    #[tokio::test]
+    async fn preferred_test() {
+        let urls = urls().await;
+        let factory = FailoverProviderFactory::<Http>::new(
+            urls.clone(),
+            Duration::from_millis(500),
+            0,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        let mut rebuilds = factory.subscribe_rebuilds();
+        let m = factory.new_middleware(None).await.unwrap();
+        let m2 = factory.new_middleware(Some(&m)).await.unwrap();
+        assert_eq!(factory.current_endpoint().await, urls[1]);
+        rebuilds.changed().await.unwrap();
+
+        // The preferred endpoint is not retried until the cooldown passes,
+        // then the background task goes back to it.
+        let m_same = factory.new_middleware(None).await.unwrap();
+        assert!(Arc::ptr_eq(&m2.middleware, &m_same.middleware));
+
+        rebuilds.changed().await.unwrap();
+        let m3 = factory.new_middleware(None).await.unwrap();
+        assert!(!Arc::ptr_eq(&m2.middleware, &m3.middleware));
+        assert_eq!(factory.generation().current(), 2);
+        assert_eq!(factory.current_endpoint().await, urls[0]);
+        assert!(factory.health().await[0].is_healthy());
+    }
+
+    #[tokio::test]
+    async fn unreachable_test() {
+        // Closes every connection without answering.
+        let down = test_server::serve(|_| async { None }).await;
+        let live = urls().await.remove(0);
+
+        let factory = FailoverProviderFactory::<Http>::new(
+            vec![down, live.clone()],
+            Duration::from_secs(3600),
+            0,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(factory.current_endpoint().await, live);
+        let health = factory.health().await;
+        assert_eq!(health[0].failures, 1);
+        assert!(health[1].is_healthy());
+    }
+
+    #[tokio::test]
+    async fn retry_preferred_after_test() {
+        let result = FailoverProviderFactory::<Http>::new(
+            urls().await,
+            Duration::from_millis(0),
+            0,
+            Duration::from_secs(1),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::RetryPreferredTooShort { .. })));
+    }
+}
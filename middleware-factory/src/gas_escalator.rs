@@ -0,0 +1,645 @@
+use crate::error_class::{
+    is_known_message, is_nonce_message, is_underpriced_message,
+};
+use crate::gas_oracle::Fees;
+use crate::{
+    ErrorClass, LayerFactory, MiddlewareFactory, MiddlewareSlot, RebuildReason,
+    Result,
+};
+
+use async_trait::async_trait;
+use offchain_core::ethers::providers::{
+    FromErr, Middleware, PendingTransaction,
+};
+use offchain_core::ethers::types::{
+    transaction::eip2718::TypedTransaction, BlockId, H256, U256,
+};
+use snafu::Snafu;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// How pending transactions are escalated.
+#[derive(Clone, Debug)]
+pub struct EscalatorConfig {
+    /// Multiplier applied to the fees on every bump. Nodes reject
+    /// replacements bumped by less than 10%, so a bump capped below that is
+    /// not sent.
+    pub coefficient: f64,
+    /// Time between bumps of the same transaction.
+    pub every: Duration,
+    /// Fees are never bumped past these.
+    pub max_fees: Option<Fees>,
+}
+
+impl Default for EscalatorConfig {
+    fn default() -> Self {
+        EscalatorConfig {
+            coefficient: 1.125,
+            every: Duration::from_secs(60),
+            max_fees: None,
+        }
+    }
+}
+
+/// Least bump of every fee nodes accept for a replacement, in permille.
+const MIN_BUMP_PERMILLE: u64 = 1100;
+
+impl EscalatorConfig {
+    /// Bumped fees, or `None` if they can't be bumped enough for nodes to
+    /// accept the replacement.
+    fn bump(&self, fees: &Fees) -> Option<Fees> {
+        let permille = U256::from((self.coefficient * 1000.0) as u64);
+        let scale = |fee: U256| fee * permille / 1000;
+
+        let mut bumped = Fees {
+            max_fee_per_gas: scale(fees.max_fee_per_gas),
+            max_priority_fee_per_gas: scale(fees.max_priority_fee_per_gas),
+            gas_price: scale(fees.gas_price),
+        };
+        if let Some(max_fees) = &self.max_fees {
+            bumped = bumped.min(max_fees);
+        }
+
+        let enough = |new: U256, old: U256| {
+            new * 1000 >= old * MIN_BUMP_PERMILLE && new > old
+        };
+        let accepted = enough(bumped.max_fee_per_gas, fees.max_fee_per_gas)
+            && enough(bumped.gas_price, fees.gas_price)
+            && (bumped.max_priority_fee_per_gas.is_zero()
+                || enough(
+                    bumped.max_priority_fee_per_gas,
+                    fees.max_priority_fee_per_gas,
+                ));
+
+        if accepted {
+            Some(bumped)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PendingTx {
+    /// Hashes of every broadcast, the latest last. Any of them may be the
+    /// one mined.
+    hashes: Vec<H256>,
+    tx: TypedTransaction,
+    block: Option<BlockId>,
+    last_bump: Instant,
+    /// Whether the node refused a bump, so it is no longer bumped.
+    exhausted: bool,
+}
+
+impl PendingTx {
+    fn new(hash: H256, tx: TypedTransaction, block: Option<BlockId>) -> Self {
+        Self {
+            hashes: vec![hash],
+            tx,
+            block,
+            last_bump: Instant::now(),
+            exhausted: false,
+        }
+    }
+
+    /// Hash of the first broadcast, identifying the entry.
+    fn id(&self) -> H256 {
+        self.hashes[0]
+    }
+
+    /// Hash of the latest broadcast.
+    fn hash(&self) -> H256 {
+        self.hashes[self.hashes.len() - 1]
+    }
+}
+
+/// Transactions waiting to be mined, shared by every `GasEscalator` built by
+/// the same `GasEscalatorFactory`, so they survive middleware rebuilds.
+#[derive(Debug, Default)]
+pub struct EscalatorState {
+    pending: Mutex<Vec<PendingTx>>,
+    /// Held through a pass of `escalate`, so passes don't overlap.
+    escalating: Mutex<()>,
+}
+
+impl EscalatorState {
+    /// Number of transactions still being escalated.
+    pub async fn pending_count(&self) -> usize {
+        self.pending.lock().await.len()
+    }
+}
+
+/// Middleware that keeps track of the transactions it sends, so they can be
+/// rebroadcast with bumped fees until they are mined. It must sit right above
+/// the signer, below the gas oracle and the nonce manager, so transactions
+/// reach it with their fees and nonce already set.
+///
+/// Transactions without a nonce after being filled are sent but not tracked,
+/// since they can't be replaced.
+#[derive(Debug)]
+pub struct GasEscalator<M> {
+    inner: M,
+    config: EscalatorConfig,
+    state: Arc<EscalatorState>,
+}
+
+impl<M> GasEscalator<M> {
+    pub fn new(
+        inner: M,
+        config: EscalatorConfig,
+        state: Arc<EscalatorState>,
+    ) -> Self {
+        Self {
+            inner,
+            config,
+            state,
+        }
+    }
+}
+
+/// Outcome of escalating one pending transaction.
+enum Escalation {
+    Waiting,
+    Mined,
+    Exhausted,
+    Bumped(Box<PendingTx>),
+}
+
+impl<M: Middleware + 'static> GasEscalator<M> {
+    /// Drops the mined transactions and rebroadcasts the ones due for a bump.
+    /// Returns the number of rebroadcasts, or the first error once every
+    /// transaction has been tried.
+    ///
+    /// Works on a snapshot of the pending transactions, so new ones can be
+    /// sent meanwhile. Passes run one at a time, so an entry is never bumped
+    /// twice at once.
+    pub async fn escalate(
+        &self,
+    ) -> std::result::Result<usize, GasEscalatorError<M>> {
+        let _pass = self.state.escalating.lock().await;
+        let snapshot = self.state.pending.lock().await.clone();
+        let mut mined = Vec::new();
+        let mut exhausted = Vec::new();
+        let mut bumped = Vec::new();
+        let mut error = None;
+
+        for entry in snapshot {
+            match self.escalate_one(&entry).await {
+                Ok(Escalation::Waiting) => {}
+                Ok(Escalation::Mined) => mined.push(entry.id()),
+                Ok(Escalation::Exhausted) => exhausted.push(entry.id()),
+                Ok(Escalation::Bumped(new)) => bumped.push(new),
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+
+        let rebroadcasts = bumped.len();
+        let mut pending = self.state.pending.lock().await;
+        pending.retain(|entry| !mined.contains(&entry.id()));
+        for entry in pending.iter_mut() {
+            if exhausted.contains(&entry.id()) {
+                entry.exhausted = true;
+            }
+        }
+        for new in bumped {
+            if let Some(entry) = pending.iter_mut().find(|e| e.id() == new.id())
+            {
+                *entry = *new;
+            }
+        }
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(rebroadcasts),
+        }
+    }
+
+    async fn escalate_one(
+        &self,
+        entry: &PendingTx,
+    ) -> std::result::Result<Escalation, GasEscalatorError<M>> {
+        if self.is_mined(entry.hash()).await? {
+            return Ok(Escalation::Mined);
+        }
+
+        if entry.last_bump.elapsed() < self.config.every {
+            return Ok(Escalation::Waiting);
+        }
+
+        let bumped = match Fees::of(&entry.tx)
+            .filter(|_| !entry.exhausted)
+            .and_then(|fees| self.config.bump(&fees))
+        {
+            Some(bumped) => bumped,
+
+            // No more bumps, so no send will tell an earlier broadcast was
+            // mined.
+            None => return self.settle_earlier(entry).await,
+        };
+
+        let mut tx = entry.tx.clone();
+        bumped.set(&mut tx);
+        match self.inner.send_transaction(tx.clone(), entry.block).await {
+            Ok(sent) => {
+                let mut new = entry.clone();
+                new.hashes.push(*sent);
+                new.tx = tx;
+                new.last_bump = Instant::now();
+                Ok(Escalation::Bumped(Box::new(new)))
+            }
+
+            Err(err) => match rejected_bump(&err.to_string()) {
+                Some(escalation) => Ok(escalation),
+                None => Err(FromErr::from(err)),
+            },
+        }
+    }
+
+    /// Mined if any broadcast before the latest one was.
+    async fn settle_earlier(
+        &self,
+        entry: &PendingTx,
+    ) -> std::result::Result<Escalation, GasEscalatorError<M>> {
+        let earlier = &entry.hashes[..entry.hashes.len() - 1];
+        for hash in earlier.iter().rev() {
+            if self.is_mined(*hash).await? {
+                return Ok(Escalation::Mined);
+            }
+        }
+
+        Ok(Escalation::Waiting)
+    }
+
+    async fn is_mined(
+        &self,
+        hash: H256,
+    ) -> std::result::Result<bool, GasEscalatorError<M>> {
+        let receipt = self
+            .inner
+            .get_transaction_receipt(hash)
+            .await
+            .map_err(FromErr::from)?;
+
+        Ok(receipt.is_some())
+    }
+}
+
+/// Outcome of a bump the node rejected, if the rejection is expected: an
+/// earlier broadcast of the same nonce was mined, the node already has the
+/// bumped one, or it wants a larger bump than the cap allows.
+fn rejected_bump(message: &str) -> Option<Escalation> {
+    if is_nonce_message(message) {
+        Some(Escalation::Mined)
+    } else if is_known_message(message) {
+        Some(Escalation::Waiting)
+    } else if is_underpriced_message(message) {
+        Some(Escalation::Exhausted)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum GasEscalatorError<M: Middleware + 'static> {
+    #[snafu(display("Middleware error: {}", source))]
+    MiddlewareError { source: M::Error },
+}
+
+impl<M: Middleware> FromErr<M::Error> for GasEscalatorError<M> {
+    fn from(src: M::Error) -> Self {
+        GasEscalatorError::MiddlewareError { source: src }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> Middleware for GasEscalator<M> {
+    type Error = GasEscalatorError<M>;
+    type Provider = M::Provider;
+    type Inner = M;
+
+    fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    async fn send_transaction<'a, T>(
+        &'a self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        let mut tx = tx.into();
+        self.inner
+            .fill_transaction(&mut tx, block)
+            .await
+            .map_err(FromErr::from)?;
+
+        let sent = self
+            .inner
+            .send_transaction(tx.clone(), block)
+            .await
+            .map_err(FromErr::from)?;
+
+        if tx.nonce().is_some() {
+            let entry = PendingTx::new(*sent, tx, block);
+            self.state.pending.lock().await.push(entry);
+        }
+
+        Ok(sent)
+    }
+}
+
+///
+/// Gas Escalator Middleware Factory
+///
+/// Escalates the pending transactions of the current middleware every
+/// `config.every` in a background task, which stops when the factory is
+/// dropped.
+pub struct GasEscalatorFactory<IF: MiddlewareFactory> {
+    current: MiddlewareSlot<Arc<GasEscalator<IF::Middleware>>>,
+    inner_factory: Arc<IF>,
+    config: EscalatorConfig,
+    state: Arc<EscalatorState>,
+    task: JoinHandle<()>,
+}
+
+impl<IF> GasEscalatorFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    pub async fn new(
+        inner_factory: Arc<IF>,
+        config: EscalatorConfig,
+    ) -> Result<Arc<Self>> {
+        let inner = inner_factory.current();
+        let state = Arc::new(EscalatorState::default());
+
+        Ok(Arc::new_cyclic(|factory: &Weak<Self>| Self {
+            current: MiddlewareSlot::with_inner(
+                Arc::new(GasEscalator::new(
+                    inner.middleware,
+                    config.clone(),
+                    Arc::clone(&state),
+                )),
+                inner.generation,
+            ),
+            inner_factory,
+            task: tokio::spawn(escalate_every(
+                Weak::clone(factory),
+                config.every,
+            )),
+            config,
+            state,
+        }))
+    }
+
+    pub fn state(&self) -> &Arc<EscalatorState> {
+        &self.state
+    }
+
+    /// Escalates the pending transactions once. The middleware is rebuilt
+    /// on retryable errors, for the next call to use.
+    pub async fn escalate_once(
+        &self,
+    ) -> std::result::Result<usize, GasEscalatorError<IF::Middleware>> {
+        let current = self.current();
+        let res = current.middleware.escalate().await;
+
+        if let Err(err) = &res {
+            if Self::should_retry(err) {
+                // Failing to rebuild leaves the current middleware in place.
+                let _ = self.new_middleware_if_stale(current.generation).await;
+            }
+        }
+
+        res
+    }
+}
+
+impl<IF: MiddlewareFactory> Drop for GasEscalatorFactory<IF> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn escalate_every<IF>(
+    factory: Weak<GasEscalatorFactory<IF>>,
+    every: Duration,
+) where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    loop {
+        tokio::time::sleep(every).await;
+
+        let factory = match factory.upgrade() {
+            Some(factory) => factory,
+            None => return,
+        };
+
+        // Failures are retried on the next tick.
+        let _ = factory.escalate_once().await;
+    }
+}
+
+#[async_trait]
+impl<IF> MiddlewareFactory for GasEscalatorFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type Middleware = Arc<GasEscalator<IF::Middleware>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.current
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        match err {
+            GasEscalatorError::MiddlewareError { source } => {
+                IF::classify(source)
+            }
+        }
+    }
+
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        self.rebuild_layer().await
+    }
+}
+
+#[async_trait]
+impl<IF> LayerFactory for GasEscalatorFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type InnerFactory = IF;
+
+    fn inner_factory(&self) -> &Self::InnerFactory {
+        &self.inner_factory
+    }
+
+    fn build(&self, inner_middleware: IF::Middleware) -> Self::Middleware {
+        Arc::new(GasEscalator::new(
+            inner_middleware,
+            self.config.clone(),
+            Arc::clone(&self.state),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpProviderFactory;
+    use offchain_core::ethers::providers::Provider;
+    use offchain_core::ethers::types::{
+        Address, TransactionReceipt, TransactionRequest,
+    };
+
+    #[tokio::test]
+    async fn escalate_test() {
+        let (provider, mock) = Provider::mocked();
+        let config = EscalatorConfig {
+            coefficient: 1.2,
+            every: Duration::from_secs(0),
+            max_fees: Some(Fees {
+                max_fee_per_gas: 150.into(),
+                max_priority_fee_per_gas: 150.into(),
+                gas_price: 150.into(),
+            }),
+        };
+        let state = Arc::new(EscalatorState::default());
+        let m = GasEscalator::new(provider, config, Arc::clone(&state));
+
+        let address = Address::from_low_u64_be(1);
+        let tx = TransactionRequest::new()
+            .from(address)
+            .to(address)
+            .gas(21000)
+            .gas_price(100)
+            .nonce(3);
+
+        // Responses are popped from the back.
+        mock.push(H256::from_low_u64_be(1)).unwrap();
+        m.send_transaction(tx.clone(), None).await.unwrap();
+
+        // Bumped by 20% twice.
+        mock.push(H256::from_low_u64_be(3)).unwrap();
+        mock.push(()).unwrap();
+        mock.push(H256::from_low_u64_be(2)).unwrap();
+        mock.push(()).unwrap();
+        assert_eq!(m.escalate().await.unwrap(), 1);
+        assert_eq!(m.escalate().await.unwrap(), 1);
+
+        // The cap leaves less than a 10% bump, so it isn't sent, and the
+        // earlier broadcasts are checked instead: the first one was mined.
+        mock.push(TransactionReceipt::default()).unwrap();
+        mock.push(()).unwrap();
+        mock.push(()).unwrap();
+        assert_eq!(m.escalate().await.unwrap(), 0);
+        assert_eq!(state.pending_count().await, 0);
+
+        let sent = |gas_price: u64| -> [TypedTransaction; 1] {
+            [tx.clone().gas_price(gas_price).into()]
+        };
+        let receipt = |hash: u64| [H256::from_low_u64_be(hash)];
+        mock.assert_request("eth_sendTransaction", sent(100))
+            .unwrap();
+        mock.assert_request("eth_getTransactionReceipt", receipt(1))
+            .unwrap();
+        mock.assert_request("eth_sendTransaction", sent(120))
+            .unwrap();
+        mock.assert_request("eth_getTransactionReceipt", receipt(2))
+            .unwrap();
+        mock.assert_request("eth_sendTransaction", sent(144))
+            .unwrap();
+        for hash in [3, 2, 1] {
+            mock.assert_request("eth_getTransactionReceipt", receipt(hash))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn rejected_bump_test() {
+        assert!(matches!(
+            rejected_bump("nonce too low"),
+            Some(Escalation::Mined)
+        ));
+        assert!(matches!(
+            rejected_bump("already known"),
+            Some(Escalation::Waiting)
+        ));
+        assert!(matches!(
+            rejected_bump("replacement transaction underpriced"),
+            Some(Escalation::Exhausted)
+        ));
+        assert!(rejected_bump("insufficient funds").is_none());
+    }
+
+    #[tokio::test]
+    async fn escalate_error_test() {
+        let (provider, mock) = Provider::mocked();
+        let config = EscalatorConfig {
+            every: Duration::from_secs(0),
+            ..Default::default()
+        };
+        let state = Arc::new(EscalatorState::default());
+        let m = GasEscalator::new(provider, config, Arc::clone(&state));
+
+        let address = Address::from_low_u64_be(1);
+        let tx = |nonce: u64| {
+            TransactionRequest::new()
+                .from(address)
+                .to(address)
+                .gas(21000)
+                .gas_price(100)
+                .nonce(nonce)
+        };
+        mock.push(H256::from_low_u64_be(2)).unwrap();
+        mock.push(H256::from_low_u64_be(1)).unwrap();
+        m.send_transaction(tx(1), None).await.unwrap();
+        m.send_transaction(tx(2), None).await.unwrap();
+
+        // The first receipt fails to parse, the second transaction is still
+        // bumped.
+        mock.push(H256::from_low_u64_be(3)).unwrap();
+        mock.push(()).unwrap();
+        mock.push::<&str, _>("not a receipt").unwrap();
+        assert!(m.escalate().await.is_err());
+        assert_eq!(state.pending_count().await, 2);
+
+        let hashes: Vec<_> = state
+            .pending
+            .lock()
+            .await
+            .iter()
+            .map(|entry| entry.hash())
+            .collect();
+        assert_eq!(
+            hashes,
+            [H256::from_low_u64_be(1), H256::from_low_u64_be(3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn gas_escalator_factory_test() {
+        let root_factory =
+            HttpProviderFactory::new("http://localhost:8545".to_string())
+                .unwrap();
+        let factory =
+            GasEscalatorFactory::new(root_factory, EscalatorConfig::default())
+                .await
+                .unwrap();
+
+        let m = factory.new_middleware(None).await.unwrap();
+        let m2 = factory.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+        assert!(Arc::ptr_eq(&m.middleware.state, &m2.middleware.state));
+
+        // Nothing pending, so no requests are made.
+        assert_eq!(factory.escalate_once().await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,417 @@
+use crate::{
+    ErrorClass, LayerFactory, MiddlewareFactory, MiddlewareSlot, RebuildReason,
+    Result,
+};
+
+use async_trait::async_trait;
+use offchain_core::ethers::providers::{
+    FromErr, Middleware, PendingTransaction,
+};
+use offchain_core::ethers::types::{
+    transaction::eip2718::TypedTransaction, BlockId, BlockNumber, U256,
+};
+use snafu::Snafu;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Fees of a transaction. EIP-1559 transactions use the max fees, while
+/// legacy and EIP-2930 transactions use the gas price.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fees {
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub gas_price: U256,
+}
+
+impl Fees {
+    /// Fees already set in `tx`, if all of them are.
+    pub fn of(tx: &TypedTransaction) -> Option<Self> {
+        match tx {
+            TypedTransaction::Legacy(inner) => {
+                inner.gas_price.map(|price| Fees {
+                    max_fee_per_gas: price,
+                    max_priority_fee_per_gas: price,
+                    gas_price: price,
+                })
+            }
+            TypedTransaction::Eip2930(inner) => {
+                inner.tx.gas_price.map(|price| Fees {
+                    max_fee_per_gas: price,
+                    max_priority_fee_per_gas: price,
+                    gas_price: price,
+                })
+            }
+            TypedTransaction::Eip1559(inner) => Some(Fees {
+                max_fee_per_gas: inner.max_fee_per_gas?,
+                max_priority_fee_per_gas: inner.max_priority_fee_per_gas?,
+                gas_price: inner.max_fee_per_gas?,
+            }),
+        }
+    }
+
+    /// Sets the fees of `tx`, overwriting the existing ones.
+    pub fn set(&self, tx: &mut TypedTransaction) {
+        match tx {
+            TypedTransaction::Legacy(inner) => {
+                inner.gas_price = Some(self.gas_price)
+            }
+            TypedTransaction::Eip2930(inner) => {
+                inner.tx.gas_price = Some(self.gas_price)
+            }
+            TypedTransaction::Eip1559(inner) => {
+                inner.max_fee_per_gas = Some(self.max_fee_per_gas);
+                inner.max_priority_fee_per_gas =
+                    Some(self.max_priority_fee_per_gas);
+            }
+        }
+    }
+
+    /// Each fee capped by the same fee of `max`, with the priority fee also
+    /// capped by the resulting max fee, as nodes reject it otherwise.
+    pub fn min(&self, max: &Fees) -> Self {
+        let max_fee_per_gas = self.max_fee_per_gas.min(max.max_fee_per_gas);
+
+        Fees {
+            max_fee_per_gas,
+            max_priority_fee_per_gas: self
+                .max_priority_fee_per_gas
+                .min(max.max_priority_fee_per_gas)
+                .min(max_fee_per_gas),
+            gas_price: self.gas_price.min(max.gas_price),
+        }
+    }
+}
+
+/// GasOracle estimates the fees of new transactions.
+#[async_trait]
+pub trait GasOracle: Debug + Send + Sync {
+    async fn fees<M>(
+        &self,
+        middleware: &M,
+    ) -> std::result::Result<Fees, M::Error>
+    where
+        M: Middleware;
+}
+
+/// Fixed fees, usually from configuration.
+#[derive(Clone, Debug)]
+pub struct StaticOracle {
+    pub fees: Fees,
+}
+
+#[async_trait]
+impl GasOracle for StaticOracle {
+    async fn fees<M>(&self, _: &M) -> std::result::Result<Fees, M::Error>
+    where
+        M: Middleware,
+    {
+        Ok(self.fees)
+    }
+}
+
+/// Estimates fees from `eth_feeHistory`. The priority fee is the median of
+/// the `percentile` rewards over the last `block_count` blocks, and the max fee
+/// leaves room for the base fee to double.
+#[derive(Clone, Debug)]
+pub struct FeeHistoryOracle {
+    pub block_count: u64,
+    pub percentile: f64,
+}
+
+impl Default for FeeHistoryOracle {
+    fn default() -> Self {
+        FeeHistoryOracle {
+            block_count: 10,
+            percentile: 50.0,
+        }
+    }
+}
+
+#[async_trait]
+impl GasOracle for FeeHistoryOracle {
+    async fn fees<M>(
+        &self,
+        middleware: &M,
+    ) -> std::result::Result<Fees, M::Error>
+    where
+        M: Middleware,
+    {
+        let history = middleware
+            .fee_history(
+                self.block_count,
+                BlockNumber::Latest,
+                &[self.percentile],
+            )
+            .await?;
+
+        // The last base fee is the one of the next block.
+        let base_fee =
+            history.base_fee_per_gas.last().copied().unwrap_or_default();
+
+        let mut rewards: Vec<U256> = history
+            .reward
+            .iter()
+            .filter_map(|reward| reward.first().copied())
+            .collect();
+        rewards.sort();
+        let priority_fee =
+            rewards.get(rewards.len() / 2).copied().unwrap_or_default();
+
+        Ok(Fees {
+            max_fee_per_gas: base_fee * 2 + priority_fee,
+            max_priority_fee_per_gas: priority_fee,
+            gas_price: base_fee + priority_fee,
+        })
+    }
+}
+
+/// Caps the fees estimated by another oracle.
+#[derive(Clone, Debug)]
+pub struct CappedOracle<O> {
+    pub oracle: O,
+    pub max: Fees,
+}
+
+#[async_trait]
+impl<O: GasOracle> GasOracle for CappedOracle<O> {
+    async fn fees<M>(
+        &self,
+        middleware: &M,
+    ) -> std::result::Result<Fees, M::Error>
+    where
+        M: Middleware,
+    {
+        let fees = self.oracle.fees(middleware).await?;
+        Ok(fees.min(&self.max))
+    }
+}
+
+/// Middleware that fills the fees of transactions that don't have them with
+/// its `GasOracle`. It must sit above the signer.
+#[derive(Debug)]
+pub struct GasOracleMiddleware<M, O> {
+    inner: M,
+    oracle: Arc<O>,
+}
+
+impl<M, O> GasOracleMiddleware<M, O> {
+    pub fn new(inner: M, oracle: Arc<O>) -> Self {
+        Self { inner, oracle }
+    }
+}
+
+impl<M: Middleware + 'static, O: GasOracle> GasOracleMiddleware<M, O> {
+    async fn fill_fees(
+        &self,
+        tx: &mut TypedTransaction,
+    ) -> std::result::Result<(), GasOracleError<M>> {
+        if Fees::of(tx).is_none() {
+            let fees =
+                self.oracle.fees(&self.inner).await.map_err(FromErr::from)?;
+            fees.set(tx);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum GasOracleError<M: Middleware + 'static> {
+    #[snafu(display("Middleware error: {}", source))]
+    MiddlewareError { source: M::Error },
+}
+
+impl<M: Middleware> FromErr<M::Error> for GasOracleError<M> {
+    fn from(src: M::Error) -> Self {
+        GasOracleError::MiddlewareError { source: src }
+    }
+}
+
+#[async_trait]
+impl<M, O> Middleware for GasOracleMiddleware<M, O>
+where
+    M: Middleware + 'static,
+    O: GasOracle + 'static,
+{
+    type Error = GasOracleError<M>;
+    type Provider = M::Provider;
+    type Inner = M;
+
+    fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    async fn fill_transaction(
+        &self,
+        tx: &mut TypedTransaction,
+        block: Option<BlockId>,
+    ) -> std::result::Result<(), Self::Error> {
+        self.fill_fees(tx).await?;
+        self.inner
+            .fill_transaction(tx, block)
+            .await
+            .map_err(FromErr::from)
+    }
+
+    async fn send_transaction<'a, T>(
+        &'a self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        let mut tx = tx.into();
+        self.fill_fees(&mut tx).await?;
+        self.inner
+            .send_transaction(tx, block)
+            .await
+            .map_err(FromErr::from)
+    }
+
+    async fn get_gas_price(&self) -> std::result::Result<U256, Self::Error> {
+        let fees =
+            self.oracle.fees(&self.inner).await.map_err(FromErr::from)?;
+        Ok(fees.gas_price)
+    }
+}
+
+///
+/// Gas Oracle Middleware Factory
+pub struct GasOracleFactory<IF: MiddlewareFactory, O> {
+    current: MiddlewareSlot<Arc<GasOracleMiddleware<IF::Middleware, O>>>,
+    inner_factory: Arc<IF>,
+    oracle: Arc<O>,
+}
+
+impl<IF, O> GasOracleFactory<IF, O>
+where
+    IF: MiddlewareFactory + Send + Sync,
+    O: GasOracle,
+{
+    pub async fn new(inner_factory: Arc<IF>, oracle: O) -> Result<Arc<Self>> {
+        let inner = inner_factory.current();
+        let oracle = Arc::new(oracle);
+
+        Ok(Arc::new(Self {
+            current: MiddlewareSlot::with_inner(
+                Arc::new(GasOracleMiddleware::new(
+                    inner.middleware,
+                    Arc::clone(&oracle),
+                )),
+                inner.generation,
+            ),
+            inner_factory,
+            oracle,
+        }))
+    }
+}
+
+#[async_trait]
+impl<IF, O> MiddlewareFactory for GasOracleFactory<IF, O>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+    O: GasOracle + 'static,
+{
+    type Middleware = Arc<GasOracleMiddleware<IF::Middleware, O>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.current
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        match err {
+            GasOracleError::MiddlewareError { source } => IF::classify(source),
+        }
+    }
+
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        self.rebuild_layer().await
+    }
+}
+
+#[async_trait]
+impl<IF, O> LayerFactory for GasOracleFactory<IF, O>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+    O: GasOracle + 'static,
+{
+    type InnerFactory = IF;
+
+    fn inner_factory(&self) -> &Self::InnerFactory {
+        &self.inner_factory
+    }
+
+    fn build(&self, inner_middleware: IF::Middleware) -> Self::Middleware {
+        Arc::new(GasOracleMiddleware::new(
+            inner_middleware,
+            Arc::clone(&self.oracle),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use offchain_core::ethers::providers::{FeeHistory, Provider};
+    use offchain_core::ethers::types::TransactionRequest;
+
+    fn fees(max_fee: u64, priority_fee: u64, gas_price: u64) -> Fees {
+        Fees {
+            max_fee_per_gas: max_fee.into(),
+            max_priority_fee_per_gas: priority_fee.into(),
+            gas_price: gas_price.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn fee_history_test() {
+        let (provider, mock) = Provider::mocked();
+        let history = FeeHistory {
+            base_fee_per_gas: vec![100.into(), 110.into(), 120.into()],
+            gas_used_ratio: vec![0.5, 0.5],
+            oldest_block: 1.into(),
+            reward: vec![vec![10.into()], vec![30.into()], vec![20.into()]],
+        };
+
+        mock.push(history.clone()).unwrap();
+        let oracle = FeeHistoryOracle::default();
+        assert_eq!(oracle.fees(&provider).await.unwrap(), fees(260, 20, 140));
+
+        mock.push(history).unwrap();
+        let oracle = CappedOracle {
+            oracle,
+            max: fees(200, 200, 200),
+        };
+        assert_eq!(oracle.fees(&provider).await.unwrap(), fees(200, 20, 140));
+    }
+
+    #[test]
+    fn min_test() {
+        // The priority fee never exceeds the capped max fee.
+        let capped = fees(100, 80, 100).min(&fees(50, 200, 200));
+        assert_eq!(capped, fees(50, 50, 100));
+    }
+
+    #[tokio::test]
+    async fn fill_test() {
+        let (provider, _) = Provider::mocked();
+        let oracle = Arc::new(StaticOracle {
+            fees: fees(30, 2, 20),
+        });
+        let m = GasOracleMiddleware::new(provider, oracle);
+
+        let mut tx: TypedTransaction =
+            TransactionRequest::new().gas(21000).into();
+        m.fill_transaction(&mut tx, None).await.unwrap();
+        assert_eq!(Fees::of(&tx).unwrap().gas_price, 20.into());
+
+        // Existing fees are kept.
+        let mut tx: TypedTransaction =
+            TransactionRequest::new().gas(21000).gas_price(7).into();
+        m.fill_transaction(&mut tx, None).await.unwrap();
+        assert_eq!(Fees::of(&tx).unwrap().gas_price, 7.into());
+
+        assert_eq!(m.get_gas_price().await.unwrap(), 20.into());
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// Why a factory rebuilt its middleware.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RebuildReason {
+    /// The first middleware of the factory.
+    Initial,
+    /// A holder of the middleware reported it as stale, usually after an
+    /// error.
+    Stale,
+    /// The root moved to another endpoint, either because the current one
+    /// failed or to go back to a more preferred one.
+    EndpointChanged { endpoint: String },
+}
+
+/// A rebuild of the middleware of a factory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rebuild {
+    /// Generation of the middleware built.
+    pub generation: u64,
+    pub reason: RebuildReason,
+}
+
+/// A middleware along with its generation, kept together so that one is
+/// never seen without the other.
+#[derive(Clone, Debug)]
+pub struct Versioned<M> {
+    pub generation: u64,
+    pub middleware: M,
+}
+
+/// Generation of the middleware of a factory, starting at zero and increased
+/// on every rebuild. Rebuilds are published on a watch channel, so holders
+/// of the middleware learn that it was replaced and why.
+///
+/// Layers rebuild along with their inner factory, reporting the reason of
+/// the root.
+#[derive(Debug)]
+pub struct Generation {
+    sender: Mutex<watch::Sender<Rebuild>>,
+    // Keeps the channel open while nobody is subscribed.
+    receiver: watch::Receiver<Rebuild>,
+}
+
+impl Generation {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(Rebuild {
+            generation: 0,
+            reason: RebuildReason::Initial,
+        });
+
+        Self {
+            sender: Mutex::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn current(&self) -> u64 {
+        self.receiver.borrow().generation
+    }
+
+    /// Latest rebuild, or the initial build if there was none.
+    pub fn latest(&self) -> Rebuild {
+        self.receiver.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Rebuild> {
+        self.receiver.clone()
+    }
+
+    /// Moves to the next generation, publishing the rebuild. Returns the new
+    /// generation. Only advanced by `MiddlewareSlot`, along with the
+    /// middleware it stores.
+    pub(crate) fn advance(&self, reason: RebuildReason) -> u64 {
+        let sender = self.sender.lock().unwrap();
+        let generation = self.current() + 1;
+
+        // The channel is open while `self.receiver` lives.
+        let _ = sender.send(Rebuild { generation, reason });
+        generation
+    }
+}
+
+impl Default for Generation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
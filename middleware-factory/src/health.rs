@@ -0,0 +1,187 @@
+use crate::MiddlewareFactory;
+
+use offchain_core::ethers::providers::Middleware;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+
+/// Health of a factory's current middleware, as last checked.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Health {
+    /// Not checked yet.
+    Unknown,
+    Healthy,
+    /// The last check failed. Holds the error of the first failed check
+    /// since the middleware was last healthy.
+    Unhealthy {
+        reason: String,
+    },
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Health::Healthy)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HealthCheckConfig {
+    /// Time between checks.
+    pub interval: Duration,
+    /// Checks not answered within `timeout` fail.
+    pub timeout: Duration,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// HealthChecker periodically pings the current middleware of a factory with
+/// `eth_blockNumber`. When the ping times out or fails with an error the
+/// factory would retry, it rebuilds the middleware right away, so that
+/// callers get a working one instead of finding the dead connection first.
+///
+/// Meant for root factories, but works on any factory, since rebuilds reach
+/// the root through the chain of factories. The check stops when the checker
+/// is dropped.
+pub struct HealthChecker {
+    health: watch::Receiver<Health>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl HealthChecker {
+    /// Starts checking `factory`, the first check being done right away.
+    pub fn start<F>(factory: Arc<F>, config: HealthCheckConfig) -> Self
+    where
+        F: MiddlewareFactory + Send + Sync + 'static,
+        F::Middleware: Send + Sync,
+    {
+        let (health_tx, health) = watch::channel(Health::Unknown);
+        let task = tokio::spawn(Self::check(factory, config, health_tx));
+
+        Self { health, task }
+    }
+
+    /// Health as of the last check.
+    pub fn health(&self) -> Health {
+        self.health.borrow().clone()
+    }
+
+    /// Watches the health, which only changes on transitions between healthy
+    /// and unhealthy.
+    pub fn subscribe(&self) -> watch::Receiver<Health> {
+        self.health.clone()
+    }
+
+    async fn check<F>(
+        factory: Arc<F>,
+        config: HealthCheckConfig,
+        health: watch::Sender<Health>,
+    ) where
+        F: MiddlewareFactory + Send + Sync + 'static,
+        F::Middleware: Send + Sync,
+    {
+        let mut interval = tokio::time::interval(config.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let current = factory.current();
+            let ping = tokio::time::timeout(
+                config.timeout,
+                current.middleware.get_block_number(),
+            )
+            .await;
+
+            let (reason, rebuild) = match ping {
+                Ok(Ok(_)) => {
+                    if !health.borrow().is_healthy() {
+                        let _ = health.send(Health::Healthy);
+                    }
+                    continue;
+                }
+                Ok(Err(e)) => (e.to_string(), F::should_retry(&e)),
+                Err(_) => {
+                    (format!("No response within {:?}", config.timeout), true)
+                }
+            };
+
+            set_unhealthy(&health, reason);
+            if rebuild {
+                // A failed rebuild is reported on the next check.
+                let _ =
+                    factory.new_middleware_if_stale(current.generation).await;
+            }
+        }
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn set_unhealthy(health: &watch::Sender<Health>, reason: String) {
+    if !matches!(*health.borrow(), Health::Unhealthy { .. }) {
+        let _ = health.send(Health::Unhealthy { reason });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use crate::HttpProviderFactory;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Stand-in node answering every request with block 42, or closing the
+    /// connection while `up` is false.
+    async fn serve(up: Arc<AtomicBool>) -> String {
+        test_server::serve(move |request| {
+            let up = up.load(Ordering::SeqCst);
+            async move { Some(request.result(json!("0x2a"))).filter(|_| up) }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn health_checker_test() {
+        let up = Arc::new(AtomicBool::new(true));
+        let factory =
+            HttpProviderFactory::new(serve(Arc::clone(&up)).await).unwrap();
+        let m = factory.new_middleware(None).await.unwrap();
+
+        let checker = HealthChecker::start(
+            Arc::clone(&factory),
+            HealthCheckConfig {
+                interval: Duration::from_millis(50),
+                timeout: Duration::from_secs(5),
+            },
+        );
+        let mut health = checker.subscribe();
+
+        health.changed().await.unwrap();
+        assert_eq!(*health.borrow(), Health::Healthy);
+
+        up.store(false, Ordering::SeqCst);
+        health.changed().await.unwrap();
+        assert!(matches!(*health.borrow(), Health::Unhealthy { .. }));
+
+        up.store(true, Ordering::SeqCst);
+        health.changed().await.unwrap();
+        assert!(checker.health().is_healthy());
+
+        // The dead connection was replaced without any caller noticing.
+        let m2 = factory.new_middleware(None).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+    }
+}
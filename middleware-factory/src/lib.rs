@@ -1,93 +1,197 @@
 use async_trait::async_trait;
-use offchain_core::ethers::middleware::{
-    signer::SignerMiddlewareError, SignerMiddleware,
+use configuration::{HttpOptions, WsOptions};
+#[cfg(unix)]
+use offchain_core::ethers::providers::Ipc;
+use offchain_core::ethers::providers::{
+    self, Http, JsonRpcClient, Middleware, Provider, Ws,
 };
-use offchain_core::ethers::providers::{self, Http, Middleware, Provider, Ws};
-use offchain_core::ethers::signers::LocalWallet;
-use snafu::{ResultExt, Snafu};
-use std::convert::TryFrom;
+use offchain_core::ethers::types::U256;
+use snafu::{ensure, ResultExt, Snafu};
+use std::future::Future;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+
+pub mod auth;
+pub mod caching;
+pub mod error_class;
+pub mod failover;
+pub mod gas_escalator;
+pub mod gas_oracle;
+pub mod generation;
+pub mod health;
+pub mod http;
+pub mod metrics;
+pub mod nonce;
+pub mod quorum;
+pub mod rate_limit;
+pub mod remote_signer;
+pub mod retry;
+pub mod signer;
+pub mod slot;
+pub mod stack;
+#[cfg(test)]
+mod test_server;
+pub mod transport;
+pub mod ws;
+
+pub use caching::CachingFactory;
+pub use error_class::{classify_provider_error, json_rpc_error, ErrorClass};
+pub use failover::{Connect, FailoverProviderFactory, HttpConnect};
+pub use gas_escalator::GasEscalatorFactory;
+pub use gas_oracle::GasOracleFactory;
+pub use generation::{Generation, Rebuild, RebuildReason, Versioned};
+pub use health::{Health, HealthCheckConfig, HealthChecker};
+pub use http::HttpTransport;
+pub use metrics::{MetricsFactory, MetricsSink, PrometheusSink};
+pub use nonce::NonceManagerFactory;
+pub use quorum::QuorumFactory;
+pub use rate_limit::{RateLimitFactory, RateLimiter};
+pub use remote_signer::RemoteSigner;
+pub use retry::RetryMiddleware;
+pub use signer::{LocalSignerFactory, SignerFactory};
+pub use slot::MiddlewareSlot;
+pub use stack::{DynMiddleware, FactoryStack};
+pub use transport::{DynTransport, TransportOptions};
 
 ///
 /// Middleware Factory
+///
+/// Builds middleware, and rebuilds it when its holders find it stale.
+/// Factories form a chain: a root factory builds its middleware from scratch,
+/// usually by connecting to a node; a layer builds its middleware over the
+/// one of its inner factory, and implements `LayerFactory`. Either keeps its
+/// middleware in a `MiddlewareSlot`, which rebuilds it single-flight.
+///
+/// Every middleware has a generation, increased on every rebuild. A
+/// middleware is stale once the factory moved past its generation.
 #[async_trait]
 pub trait MiddlewareFactory {
-    ///
-    /// User Implementation
+    //
+    // User Implementation
 
     /// Middleware that this factory creates.
-    type Middleware: Middleware;
+    type Middleware: Middleware + Clone;
 
-    /// The next MiddlewareFactory in the chain of factories.
-    type InnerFactory: MiddlewareFactory<Middleware = <Self::Middleware as Middleware>::Inner>
-        + Send
-        + Sync;
+    /// Slot holding the current middleware, along with its generation.
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware>;
 
-    /// Get current middleware.
-    async fn current(&self) -> Self::Middleware;
+    /// Classifies an error of this factory's middleware. Layers classify
+    /// errors coming from their inner middleware as their inner factory
+    /// does.
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass;
 
-    /// Compares middleware parameter with current middleware.
-    async fn middleware_eq(&self, other: &Self::Middleware) -> bool;
+    /// Builds a new middleware, returning it along with the reason for the
+    /// rebuild. Called by `new_middleware_if_stale`, at most once at a time.
+    /// Layers implement it with `LayerFactory::rebuild_layer`.
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)>;
 
-    /// Get inner factory.
-    async fn inner_factory(&self) -> &Self::InnerFactory;
+    //
+    // Automatic Implementation
+
+    /// Current middleware, along with its generation.
+    fn current(&self) -> Versioned<Self::Middleware> {
+        self.slot().load()
+    }
+
+    /// Generation of the current middleware, and the channel its rebuilds are
+    /// published on.
+    fn generation(&self) -> &Generation {
+        self.slot().generation()
+    }
 
-    /// Builds this factory's middleware from inner middleware, and set it as
-    /// current.
-    async fn build_and_set_middleware(
+    /// Rebuilds the middleware if `generation` is still the current one,
+    /// calling on the chain of factories to build it. Returns the current
+    /// middleware along with its generation, which is always newer than
+    /// `generation`, unless `generation` is newer than the current one.
+    async fn new_middleware_if_stale(
         &self,
-        inner_middleware: <Self::InnerFactory as MiddlewareFactory>::Middleware,
-    ) -> Self::Middleware;
+        generation: u64,
+    ) -> Result<Versioned<Self::Middleware>> {
+        self.slot().rebuild(generation, || self.rebuild()).await
+    }
 
-    /// Returns if this error should trigger a retry.
-    fn should_retry(err: &<Self::Middleware as Middleware>::Error) -> bool;
+    /// Returns if this error should trigger a retry, that is, if its class is
+    /// retryable.
+    fn should_retry(err: &<Self::Middleware as Middleware>::Error) -> bool {
+        Self::classify(err).is_retryable()
+    }
 
-    ///
-    /// Automatic Implementation
+    /// Compares middleware parameter with current middleware, by
+    /// generation.
+    async fn middleware_eq(&self, other: &Versioned<Self::Middleware>) -> bool {
+        other.generation == self.current().generation
+    }
 
-    /// Automatic implementation of `new_middleware`. This function receives a
-    /// optional middleware. If it is `None`, it will return the current
-    /// internal middleware. Otherwise, it will compare the given middleware
-    /// with the current internal middleware. If they are different, it will
-    /// return the current internal middleware. Otherwise, it will create a new
-    /// one, calling on the chain of factories to build a middleware. In all
+    /// This function receives a optional middleware, along with its
+    /// generation. If it is `None`, it will return the current internal
+    /// middleware. Otherwise, if the given middleware is stale, it will
+    /// return the current internal middleware, and if it is the current one,
+    /// it will create a new one, as `new_middleware_if_stale` does. In all
     /// cases, the returned middleware is always different than the given one.
     async fn new_middleware(
         &self,
-        previous: Option<&Self::Middleware>,
-    ) -> Result<Self::Middleware> {
-        let current = self.current().await;
-
-        if let Some(previous) = previous {
-            // if std::ptr::eq(current.as_ref(), previous) {
-            if self.middleware_eq(previous).await {
-                // Get inner middleware and inner factory
-                let current_inner = current.inner();
-                let inner_factory = self.inner_factory().await;
-
-                // Recursively call `new_middleware` on inner factory.
-                let new_inner =
-                    inner_factory.new_middleware(Some(current_inner)).await?;
-
-                // Now that we have the new inner middleware, we create out own,
-                // setting the current to it.
-                let new_middleware =
-                    self.build_and_set_middleware(new_inner).await;
-
-                return Ok(new_middleware);
+        previous: Option<&Versioned<Self::Middleware>>,
+    ) -> Result<Versioned<Self::Middleware>> {
+        match previous {
+            Some(previous) => {
+                self.new_middleware_if_stale(previous.generation).await
             }
+            None => Ok(self.current()),
         }
+    }
+
+    /// Watches the rebuilds of this factory's middleware.
+    fn subscribe_rebuilds(&self) -> tokio::sync::watch::Receiver<Rebuild> {
+        self.generation().subscribe()
+    }
+}
+
+///
+/// Layer Middleware Factory
+///
+/// Factory building its middleware over the middleware of an inner factory,
+/// which is rebuilt along with it.
+#[async_trait]
+pub trait LayerFactory: MiddlewareFactory {
+    //
+    // User Implementation
+
+    /// The next MiddlewareFactory in the chain of factories.
+    type InnerFactory: MiddlewareFactory<Middleware = <Self::Middleware as Middleware>::Inner>
+        + Send
+        + Sync;
+
+    /// Get inner factory.
+    fn inner_factory(&self) -> &Self::InnerFactory;
+
+    /// Builds this factory's middleware from inner middleware.
+    fn build(
+        &self,
+        inner_middleware: <Self::InnerFactory as MiddlewareFactory>::Middleware,
+    ) -> Self::Middleware;
+
+    //
+    // Automatic Implementation
 
-        Ok(current)
+    /// Rebuild of layers, implementing `MiddlewareFactory::rebuild`. Calls on
+    /// the chain of factories to build a new inner middleware first, unless
+    /// it was already rebuilt, then builds this factory's middleware over it,
+    /// reporting the reason of the inner rebuild.
+    async fn rebuild_layer(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        let (inner, reason) =
+            self.slot().rebuild_inner(self.inner_factory()).await?;
+        Ok((self.build(inner), reason))
     }
 }
 
 ///
 /// "Root" Websocket Middleware Factory
 pub struct WsProviderFactory {
-    provider: Mutex<Arc<Provider<Ws>>>,
+    provider: MiddlewareSlot<Arc<Provider<Ws>>>,
     url: String,
+    options: WsOptions,
+    expected_chain_id: Option<u64>,
     max_retries: usize,
     max_delay: std::time::Duration,
 }
@@ -98,290 +202,267 @@ impl WsProviderFactory {
         max_retries: usize,
         max_delay: std::time::Duration,
     ) -> Result<Arc<Self>> {
-        let provider =
-            WsProviderFactory::new_web3_ws(&url, max_retries, max_delay)
-                .await?;
-
-        Ok(Arc::new(Self {
-            provider: Mutex::new(Arc::new(provider)),
+        Self::new_with_options(
             url,
+            WsOptions::default(),
+            None,
             max_retries,
             max_delay,
-        }))
+        )
+        .await
     }
 
-    async fn new_web3_ws(
-        url: &str,
+    /// The options apply to the first connection and to every reconnection.
+    /// If `expected_chain_id` is set, every connection is checked to be on
+    /// that chain.
+    pub async fn new_with_options(
+        url: String,
+        options: WsOptions,
+        expected_chain_id: Option<u64>,
         max_retries: usize,
         max_delay: std::time::Duration,
-    ) -> Result<Provider<Ws>> {
-        let mut backoff = backoff::Backoff::new(max_retries, max_delay);
-        loop {
-            let p_res = Provider::connect(url)
-                .await
-                .map_err(providers::ProviderError::from)
-                .context(ProviderError);
-
-            match p_res {
-                Ok(p) => break Ok(p),
-                Err(e) => {
-                    if backoff.wait().await.is_err() {
-                        break RetryLimitReached {
-                            retries: max_retries,
-                            last_error: Box::new(e),
-                        }
-                        .fail();
-                    }
-                }
-            }
-        }
-    }
-}
-
-pub struct PhantomFactory<T: Middleware> {
-    _marker: std::marker::PhantomData<T>,
-}
-
-#[async_trait]
-impl<T: Middleware<Inner = T>> MiddlewareFactory for PhantomFactory<T> {
-    type Middleware = T;
-    type InnerFactory = Self;
-
-    async fn current(&self) -> Self::Middleware {
-        unreachable!()
-    }
-
-    async fn middleware_eq(&self, _: &Self::Middleware) -> bool {
-        unreachable!()
-    }
-
-    async fn inner_factory(&self) -> &Self::InnerFactory {
-        unreachable!()
-    }
-
-    async fn build_and_set_middleware(
-        &self,
-        _: <Self::InnerFactory as MiddlewareFactory>::Middleware,
-    ) -> Self::Middleware {
-        unreachable!()
-    }
-
-    fn should_retry(_: &<Self::Middleware as Middleware>::Error) -> bool {
-        unreachable!()
-    }
+    ) -> Result<Arc<Self>> {
+        let provider = connect_with_backoff(
+            || ws::connect(&url, &options),
+            expected_chain_id,
+            max_retries,
+            max_delay,
+        )
+        .await?;
 
-    async fn new_middleware(
-        &self,
-        _: Option<&Self::Middleware>,
-    ) -> Result<Self::Middleware> {
-        unreachable!()
+        Ok(Arc::new(Self {
+            provider: MiddlewareSlot::new(Arc::new(provider)),
+            url,
+            options,
+            expected_chain_id,
+            max_retries,
+            max_delay,
+        }))
     }
 }
 
 #[async_trait]
 impl MiddlewareFactory for WsProviderFactory {
     type Middleware = Arc<Provider<Ws>>;
-    type InnerFactory = PhantomFactory<Provider<Ws>>;
 
-    /// User implemented methods
-    async fn current(&self) -> Self::Middleware {
-        unreachable!("WsProviderFactory `current` unreachable")
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.provider
     }
 
-    async fn middleware_eq(&self, other: &Self::Middleware) -> bool {
-        std::ptr::eq(self.provider.lock().await.as_ref(), other.as_ref())
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        classify_provider_error(err)
     }
 
-    async fn inner_factory(&self) -> &Self::InnerFactory {
-        unreachable!("WsProviderFactory `inner_factory` unreachable")
-    }
-
-    async fn build_and_set_middleware(
-        &self,
-        _: <Self::InnerFactory as MiddlewareFactory>::Middleware,
-    ) -> Self::Middleware {
-        unreachable!("WsProviderFactory `build_and_set_middleware` unreachable")
-    }
-
-    fn should_retry(err: &<Self::Middleware as Middleware>::Error) -> bool {
-        // TODO: Improve this retry policy. We may need to change `ethers`
-        // to expose inner error types.
-        matches!(err, providers::ProviderError::JsonRpcClientError(_))
-    }
-
-    /// Default method
-    async fn new_middleware(
-        &self,
-        previous: Option<&Self::Middleware>,
-    ) -> Result<Self::Middleware> {
-        let mut current = self.provider.lock().await;
-
-        if let Some(previous) = previous {
-            if std::ptr::eq(current.as_ref(), previous.as_ref()) {
-                let new_provider = Arc::new(
-                    WsProviderFactory::new_web3_ws(
-                        &self.url,
-                        self.max_retries,
-                        self.max_delay,
-                    )
-                    .await?,
-                );
-                *current = Arc::clone(&new_provider);
-
-                return Ok(new_provider);
-            }
-        }
-
-        Ok(Arc::clone(&current))
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        let provider = connect_with_backoff(
+            || ws::connect(&self.url, &self.options),
+            self.expected_chain_id,
+            self.max_retries,
+            self.max_delay,
+        )
+        .await?;
+        Ok((Arc::new(provider), RebuildReason::Stale))
     }
 }
 
 ///
 /// "Root" Http Middleware Factory
-pub struct HttpProviderFactory {
-    provider: Mutex<Arc<Provider<Http>>>,
+///
+/// Built over ethers' `Http` by `new`, or over `HttpTransport` by
+/// `new_with_options`, for timeouts, headers, auth and a proxy.
+pub struct HttpProviderFactory<T: HttpConnect = Http> {
+    provider: MiddlewareSlot<Arc<Provider<T>>>,
     url: String,
+    options: T::Options,
+    expected_chain_id: Option<u64>,
 }
 
 impl HttpProviderFactory {
     pub fn new(url: String) -> Result<Arc<Self>> {
-        let provider =
-            Provider::<Http>::try_from(url.clone()).context(ParseError {})?;
+        let provider = Provider::new(Http::new_client(&url, &())?);
 
         Ok(Arc::new(Self {
-            provider: Mutex::new(Arc::new(provider)),
+            provider: MiddlewareSlot::new(Arc::new(provider)),
             url,
+            options: (),
+            expected_chain_id: None,
         }))
     }
 }
 
-#[async_trait]
-impl MiddlewareFactory for HttpProviderFactory {
-    type Middleware = Arc<Provider<Http>>;
-    type InnerFactory = PhantomFactory<Provider<Http>>;
-
-    /// User implemented methods
-    async fn current(&self) -> Self::Middleware {
-        unreachable!("HttpProviderFactory `current` unreachable")
-    }
+impl HttpProviderFactory<HttpTransport> {
+    /// If `expected_chain_id` is set, the endpoint is checked to be on that
+    /// chain now and on every rebuild.
+    pub async fn new_with_options(
+        url: String,
+        options: HttpOptions,
+        expected_chain_id: Option<u64>,
+    ) -> Result<Arc<Self>> {
+        let provider = Provider::new(HttpTransport::new(&url, &options)?);
+        let provider = verify_chain_id(provider, expected_chain_id).await?;
 
-    async fn middleware_eq(&self, other: &Self::Middleware) -> bool {
-        std::ptr::eq(self.provider.lock().await.as_ref(), other.as_ref())
+        Ok(Arc::new(Self {
+            provider: MiddlewareSlot::new(Arc::new(provider)),
+            url,
+            options,
+            expected_chain_id,
+        }))
     }
+}
 
-    async fn inner_factory(&self) -> &Self::InnerFactory {
-        unreachable!("HttpProviderFactory `inner_factory` unreachable")
-    }
+#[async_trait]
+impl<T: HttpConnect + 'static> MiddlewareFactory for HttpProviderFactory<T> {
+    type Middleware = Arc<Provider<T>>;
 
-    async fn build_and_set_middleware(
-        &self,
-        _: <Self::InnerFactory as MiddlewareFactory>::Middleware,
-    ) -> Self::Middleware {
-        unreachable!(
-            "HttpProviderFactory `build_and_set_middleware` unreachable"
-        )
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.provider
     }
 
-    fn should_retry(err: &<Self::Middleware as Middleware>::Error) -> bool {
-        // TODO: Improve this retry policy. We may need to change `ethers`
-        // to expose inner error types.
-        matches!(err, providers::ProviderError::JsonRpcClientError(_))
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        classify_provider_error(err)
     }
 
-    /// Default method
-    async fn new_middleware(
-        &self,
-        previous: Option<&Self::Middleware>,
-    ) -> Result<Self::Middleware> {
-        let mut current = self.provider.lock().await;
-
-        if let Some(previous) = previous {
-            if std::ptr::eq(current.as_ref(), previous.as_ref()) {
-                let new_provider = Arc::new(
-                    Provider::<Http>::try_from(self.url.clone())
-                        .context(ParseError {})?,
-                );
-                *current = Arc::clone(&new_provider);
-
-                return Ok(new_provider);
-            }
-        }
-
-        Ok(Arc::clone(&current))
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        let provider = Provider::new(T::new_client(&self.url, &self.options)?);
+        let provider =
+            verify_chain_id(provider, self.expected_chain_id).await?;
+        Ok((Arc::new(provider), RebuildReason::Stale))
     }
 }
 
 ///
-/// "Root" Local Signer Middleware Factory
-pub struct LocalSignerFactory<IF: MiddlewareFactory> {
-    signer: Mutex<Arc<SignerMiddleware<IF::Middleware, LocalWallet>>>,
-    inner_factory: Arc<IF>,
-    wallet: LocalWallet,
+/// "Root" IPC Middleware Factory
+///
+/// Only available on unix, where IPC sockets are unix domain sockets.
+#[cfg(unix)]
+pub struct IpcProviderFactory {
+    provider: MiddlewareSlot<Arc<Provider<Ipc>>>,
+    path: PathBuf,
+    expected_chain_id: Option<u64>,
+    max_retries: usize,
+    max_delay: std::time::Duration,
 }
 
-impl<IF: MiddlewareFactory + Sync + Send> LocalSignerFactory<IF> {
+#[cfg(unix)]
+impl IpcProviderFactory {
+    /// If `expected_chain_id` is set, every connection is checked to be on
+    /// that chain.
     pub async fn new(
-        inner_factory: Arc<IF>,
-        wallet: LocalWallet,
+        path: PathBuf,
+        expected_chain_id: Option<u64>,
+        max_retries: usize,
+        max_delay: std::time::Duration,
     ) -> Result<Arc<Self>> {
-        let provider = inner_factory.new_middleware(None).await?;
+        let provider = connect_with_backoff(
+            || ipc_connect(&path),
+            expected_chain_id,
+            max_retries,
+            max_delay,
+        )
+        .await?;
 
         Ok(Arc::new(Self {
-            signer: Mutex::new(Arc::new(SignerMiddleware::new(
-                provider,
-                wallet.clone(),
-            ))),
-            inner_factory,
-            wallet,
+            provider: MiddlewareSlot::new(Arc::new(provider)),
+            path,
+            expected_chain_id,
+            max_retries,
+            max_delay,
         }))
     }
 }
 
-#[async_trait]
-impl<IF: MiddlewareFactory + Sync + Send> MiddlewareFactory
-    for LocalSignerFactory<IF>
-{
-    type Middleware = Arc<SignerMiddleware<IF::Middleware, LocalWallet>>;
-    type InnerFactory = IF;
+#[cfg(unix)]
+async fn ipc_connect(path: &std::path::Path) -> Result<Ipc> {
+    Ipc::connect(path)
+        .await
+        .map_err(Into::into)
+        .context(ProviderError)
+}
 
-    /// User implemented methods
-    async fn current(&self) -> Self::Middleware {
-        self.signer.lock().await.clone()
-    }
+#[cfg(unix)]
+#[async_trait]
+impl MiddlewareFactory for IpcProviderFactory {
+    type Middleware = Arc<Provider<Ipc>>;
 
-    async fn middleware_eq(&self, other: &Self::Middleware) -> bool {
-        std::ptr::eq(self.signer.lock().await.as_ref(), other.as_ref())
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.provider
     }
 
-    async fn inner_factory(&self) -> &Self::InnerFactory {
-        &self.inner_factory
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        classify_provider_error(err)
     }
 
-    async fn build_and_set_middleware(
-        &self,
-        inner_middleware: IF::Middleware,
-    ) -> Self::Middleware {
-        let new = Arc::new(SignerMiddleware::new(
-            inner_middleware,
-            self.wallet.clone(),
-        ));
-
-        *self.signer.lock().await = Arc::clone(&new);
-        new
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        let provider = connect_with_backoff(
+            || ipc_connect(&self.path),
+            self.expected_chain_id,
+            self.max_retries,
+            self.max_delay,
+        )
+        .await?;
+        Ok((Arc::new(provider), RebuildReason::Stale))
     }
+}
 
-    fn should_retry(err: &<Self::Middleware as Middleware>::Error) -> bool {
-        // TODO: Improve this retry policy. We may need to change `ethers`
-        // to expose inner error types.
-        match err {
-            SignerMiddlewareError::MiddlewareError(m_err) => {
-                IF::should_retry(m_err)
+/// Connects to a single endpoint with `connect`, checking the chain id of
+/// every connection, and retries with backoff if it fails. Shared by the root
+/// factories of a single endpoint.
+async fn connect_with_backoff<T, F, Fut>(
+    connect: F,
+    expected_chain_id: Option<u64>,
+    max_retries: usize,
+    max_delay: std::time::Duration,
+) -> Result<Provider<T>>
+where
+    T: JsonRpcClient,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = backoff::Backoff::new(max_retries, max_delay);
+    loop {
+        let p_res = match connect().await {
+            Ok(transport) => {
+                verify_chain_id(Provider::new(transport), expected_chain_id)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+
+        match p_res {
+            Ok(p) => break Ok(p),
+            // Reconnecting to the same endpoint won't change its chain.
+            Err(e @ Error::ChainIdMismatch { .. }) => break Err(e),
+            Err(e) => {
+                if backoff.wait().await.is_err() {
+                    break RetryLimitReached {
+                        retries: max_retries,
+                        last_error: Box::new(e),
+                    }
+                    .fail();
+                }
             }
-            _ => false,
         }
     }
 }
 
+/// Checks that `provider` is on chain `expected`, when given. Root factories
+/// call it on every connection, so that a misconfigured endpoint is never
+/// used on the wrong network.
+pub async fn verify_chain_id<T: JsonRpcClient>(
+    provider: Provider<T>,
+    expected: Option<u64>,
+) -> Result<Provider<T>> {
+    if let Some(expected) = expected {
+        let actual = provider.get_chainid().await.context(ProviderError)?;
+        ensure!(
+            actual == U256::from(expected),
+            ChainIdMismatch { expected, actual }
+        );
+    }
+
+    Ok(provider)
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub")]
 pub enum Error {
@@ -391,6 +472,63 @@ pub enum Error {
     #[snafu(display("Provider error: {}", source))]
     ProviderError { source: providers::ProviderError },
 
+    #[snafu(display("HTTP client error: {}", source))]
+    HttpClientError { source: reqwest::Error },
+
+    #[snafu(display("Invalid value for header {}", name))]
+    InvalidHeader { name: String },
+
+    #[snafu(display("Authentication error: {}", source))]
+    AuthenticationError { source: auth::AuthError },
+
+    #[snafu(display("Websocket error: {}", source))]
+    WsError {
+        source: Box<tokio_tungstenite::tungstenite::Error>,
+    },
+
+    #[snafu(display("TLS configuration error: {}", reason))]
+    TlsConfigError { reason: String },
+
+    #[snafu(display("No endpoints given"))]
+    NoEndpoints {},
+
+    #[snafu(display(
+        "Retrying preferred endpoints after {:?} is below the minimum {:?}",
+        given,
+        min
+    ))]
+    RetryPreferredTooShort {
+        min: std::time::Duration,
+        given: std::time::Duration,
+    },
+
+    #[snafu(display("Unsupported endpoint {}", url))]
+    UnsupportedEndpoint { url: String },
+
+    #[snafu(display("No websocket or IPC endpoints given for subscriptions"))]
+    NoPubsubEndpoints {},
+
+    #[snafu(display(
+        "Expected chain id {}, endpoint is on chain {}",
+        expected,
+        actual
+    ))]
+    ChainIdMismatch { expected: u64, actual: U256 },
+
+    #[snafu(display(
+        "Quorum of {} unreachable with weight {}",
+        quorum,
+        weight
+    ))]
+    QuorumUnreachable { weight: u64, quorum: u64 },
+
+    #[snafu(display(
+        "Quorum of {} is not more than half of weight {}",
+        quorum,
+        weight
+    ))]
+    QuorumTooLow { weight: u64, quorum: u64 },
+
     #[snafu(display(
         "Retry limit of {} reached, last error: {}",
         retries,
@@ -400,6 +538,8 @@ pub enum Error {
         retries: usize,
         last_error: Box<Error>,
     },
+    #[snafu(display("Rebuild in flight failed: {}", reason))]
+    RebuildFailed { reason: String },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -408,6 +548,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 mod tests {
     use super::*;
     use offchain_core::ethers::providers::{FromErr, Middleware};
+    use offchain_core::ethers::signers::LocalWallet;
     use snafu::Snafu;
     use std::sync::Arc;
 
@@ -439,17 +580,19 @@ mod tests {
     }
 
     pub struct IdFactory<IF: MiddlewareFactory> {
-        current:
-            Mutex<Arc<IdMiddleware<<IF as MiddlewareFactory>::Middleware>>>,
+        current: MiddlewareSlot<Arc<IdMiddleware<IF::Middleware>>>,
         inner_factory: Arc<IF>,
     }
 
     impl<IF: MiddlewareFactory + Send + Sync> IdFactory<IF> {
         async fn new(inner_factory: Arc<IF>) -> Result<Self> {
-            let inner_middleware = inner_factory.new_middleware(None).await?;
-            let current = Mutex::new(Arc::new(IdMiddleware {
-                inner: inner_middleware,
-            }));
+            let inner = inner_factory.current();
+            let current = MiddlewareSlot::with_inner(
+                Arc::new(IdMiddleware {
+                    inner: inner.middleware,
+                }),
+                inner.generation,
+            );
 
             Ok(Self {
                 current,
@@ -464,36 +607,35 @@ mod tests {
         IF: MiddlewareFactory + Send + Sync + 'static,
     {
         type Middleware = Arc<IdMiddleware<IF::Middleware>>;
-        type InnerFactory = IF;
 
-        /// User implemented methods, only to be called internally.
-        async fn current(&self) -> Self::Middleware {
-            self.current.lock().await.clone()
+        fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+            &self.current
         }
 
-        async fn middleware_eq(&self, other: &Self::Middleware) -> bool {
-            std::ptr::eq(self.current.lock().await.as_ref(), other.as_ref())
+        fn classify(_: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+            ErrorClass::Permanent
         }
 
-        async fn inner_factory(&self) -> &Self::InnerFactory {
-            &self.inner_factory
+        async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+            self.rebuild_layer().await
         }
+    }
 
-        async fn build_and_set_middleware(
-            &self,
-            inner_middleware:
-                <Self::InnerFactory as MiddlewareFactory>::Middleware,
-        ) -> Self::Middleware {
-            let new = Arc::new(IdMiddleware {
-                inner: inner_middleware,
-            });
+    #[async_trait]
+    impl<IF> LayerFactory for IdFactory<IF>
+    where
+        IF: MiddlewareFactory + Send + Sync + 'static,
+    {
+        type InnerFactory = IF;
 
-            *self.current.lock().await = Arc::clone(&new);
-            new
+        fn inner_factory(&self) -> &Self::InnerFactory {
+            &self.inner_factory
         }
 
-        fn should_retry(_: &<Self::Middleware as Middleware>::Error) -> bool {
-            false
+        fn build(&self, inner_middleware: IF::Middleware) -> Self::Middleware {
+            Arc::new(IdMiddleware {
+                inner: inner_middleware,
+            })
         }
     }
 
@@ -507,10 +649,100 @@ mod tests {
 
         let m = id_factory.new_middleware(None).await.unwrap();
         let m_same = id_factory.new_middleware(None).await.unwrap();
-        assert!(Arc::ptr_eq(&m, &m_same));
+        assert!(Arc::ptr_eq(&m.middleware, &m_same.middleware));
 
         let m2 = id_factory.new_middleware(Some(&m)).await.unwrap();
-        assert!(!Arc::ptr_eq(&m, &m2));
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+    }
+
+    #[tokio::test]
+    async fn generation_test() {
+        let root_factory =
+            HttpProviderFactory::new("http://localhost:8545".to_string())
+                .unwrap();
+        let id_factory =
+            IdFactory::new(Arc::clone(&root_factory)).await.unwrap();
+        let mut rebuilds = id_factory.subscribe_rebuilds();
+        assert_eq!(id_factory.generation().current(), 0);
+
+        let Versioned {
+            generation,
+            middleware: m,
+        } = id_factory.new_middleware_if_stale(0).await.unwrap();
+        assert_eq!(generation, 1);
+        assert_eq!(root_factory.generation().current(), 1);
+
+        rebuilds.changed().await.unwrap();
+        assert_eq!(
+            *rebuilds.borrow(),
+            Rebuild {
+                generation: 1,
+                reason: RebuildReason::Stale,
+            }
+        );
+
+        // Already rebuilt since generation 0.
+        let Versioned {
+            generation,
+            middleware: m_same,
+        } = id_factory.new_middleware_if_stale(0).await.unwrap();
+        assert_eq!(generation, 1);
+        assert!(Arc::ptr_eq(&m, &m_same));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn ipc_factory_test() {
+        let path = std::env::temp_dir()
+            .join(format!("ipc-factory-{}.ipc", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let missing = IpcProviderFactory::new(
+            path.clone(),
+            None,
+            0,
+            std::time::Duration::from_secs(1),
+        )
+        .await;
+        assert!(matches!(missing, Err(Error::RetryLimitReached { .. })));
+
+        // Connections wait in the listener backlog, no need to accept them.
+        let _listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let factory = IpcProviderFactory::new(
+            path.clone(),
+            None,
+            0,
+            std::time::Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        let m = factory.new_middleware(None).await.unwrap();
+        let m_same = factory.new_middleware(None).await.unwrap();
+        assert!(Arc::ptr_eq(&m.middleware, &m_same.middleware));
+
+        let m2 = factory.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chain_id_test() {
+        let (provider, mock) = Provider::mocked();
+
+        // Nothing to check, so nothing is requested.
+        let provider = verify_chain_id(provider, None).await.unwrap();
+
+        mock.push(U256::from(5)).unwrap();
+        let provider = verify_chain_id(provider, Some(5)).await.unwrap();
+
+        mock.push(U256::from(1)).unwrap();
+        let err = verify_chain_id(provider, Some(5)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ChainIdMismatch { expected: 5, actual } if actual == 1.into()
+        ));
     }
 
     #[tokio::test]
@@ -527,9 +759,9 @@ mod tests {
 
         let m = signer_factory.new_middleware(None).await.unwrap();
         let m_same = signer_factory.new_middleware(None).await.unwrap();
-        assert!(Arc::ptr_eq(&m, &m_same));
+        assert!(Arc::ptr_eq(&m.middleware, &m_same.middleware));
 
         let m2 = signer_factory.new_middleware(Some(&m)).await.unwrap();
-        assert!(!Arc::ptr_eq(&m, &m2));
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
     }
 }
@@ -0,0 +1,618 @@
+use crate::{
+    ErrorClass, LayerFactory, MiddlewareFactory, MiddlewareSlot, RebuildReason,
+    Result,
+};
+
+use async_trait::async_trait;
+use offchain_core::ethers::providers::{
+    FeeHistory, FromErr, Middleware, PendingTransaction,
+};
+use offchain_core::ethers::types::{
+    transaction::eip2718::TypedTransaction, Address, Block, BlockId,
+    BlockNumber, Bytes, Filter, Log, NameOrAddress, Signature, Transaction,
+    TransactionReceipt, TxHash, H256, U256, U64,
+};
+use snafu::Snafu;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Write};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Destination of the metrics recorded by `MetricsMiddleware` and
+/// `MetricsFactory`.
+pub trait MetricsSink: Send + Sync {
+    /// Called after every request, with the class of its error if it failed.
+    fn record_request(
+        &self,
+        method: &'static str,
+        latency: Duration,
+        error: Option<ErrorClass>,
+    );
+
+    /// Called whenever the factory rebuilds its middleware.
+    fn record_rebuild(&self);
+}
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    latencies: BTreeMap<&'static str, Histogram>,
+    errors: BTreeMap<(&'static str, &'static str), u64>,
+    rebuilds: u64,
+}
+
+/// Sink keeping the metrics in memory, to be exported in the Prometheus text
+/// format.
+pub struct PrometheusSink {
+    namespace: String,
+    metrics: std::sync::Mutex<Metrics>,
+}
+
+impl PrometheusSink {
+    /// Metric names are prefixed by `namespace`.
+    pub fn new(namespace: &str) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            metrics: std::sync::Mutex::new(Metrics::default()),
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn export(&self) -> String {
+        let metrics = self.metrics.lock().unwrap();
+        let ns = &self.namespace;
+        let mut out = String::new();
+
+        // Writing to a `String` can't fail.
+        let _ = writeln!(
+            out,
+            "# HELP {0}_rpc_requests_total Requests by method.\n\
+             # TYPE {0}_rpc_requests_total counter",
+            ns
+        );
+        for (method, histogram) in &metrics.latencies {
+            let _ = writeln!(
+                out,
+                "{}_rpc_requests_total{{method=\"{}\"}} {}",
+                ns, method, histogram.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP {0}_rpc_errors_total Failed requests by method and error \
+             class.\n# TYPE {0}_rpc_errors_total counter",
+            ns
+        );
+        for ((method, class), count) in &metrics.errors {
+            let _ = writeln!(
+                out,
+                "{}_rpc_errors_total{{method=\"{}\",class=\"{}\"}} {}",
+                ns, method, class, count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP {0}_rpc_request_duration_seconds Request latency by \
+             method.\n# TYPE {0}_rpc_request_duration_seconds histogram",
+            ns
+        );
+        for (method, histogram) in &metrics.latencies {
+            let name = format!("{}_rpc_request_duration_seconds", ns);
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    name, method, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{method=\"{}\",le=\"+Inf\"}} {}\n\
+                 {}_sum{{method=\"{}\"}} {}\n\
+                 {}_count{{method=\"{}\"}} {}",
+                name,
+                method,
+                histogram.count,
+                name,
+                method,
+                histogram.sum,
+                name,
+                method,
+                histogram.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP {0}_factory_rebuilds_total Middleware rebuilds.\n\
+             # TYPE {0}_factory_rebuilds_total counter\n\
+             {0}_factory_rebuilds_total {1}",
+            ns, metrics.rebuilds
+        );
+
+        out
+    }
+}
+
+impl MetricsSink for PrometheusSink {
+    fn record_request(
+        &self,
+        method: &'static str,
+        latency: Duration,
+        error: Option<ErrorClass>,
+    ) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics
+            .latencies
+            .entry(method)
+            .or_default()
+            .observe(latency.as_secs_f64());
+        if let Some(class) = error {
+            *metrics.errors.entry((method, class.as_str())).or_default() += 1;
+        }
+    }
+
+    fn record_rebuild(&self) {
+        self.metrics.lock().unwrap().rebuilds += 1;
+    }
+}
+
+impl Debug for PrometheusSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrometheusSink")
+            .field("namespace", &self.namespace)
+            .finish()
+    }
+}
+
+/// Middleware timing every request made through it, and reporting failures
+/// by their class according to the factory's classification.
+pub struct MetricsMiddleware<M: Middleware> {
+    inner: M,
+    sink: Arc<dyn MetricsSink>,
+    classify: fn(&M::Error) -> ErrorClass,
+}
+
+impl<M: Middleware> MetricsMiddleware<M> {
+    pub fn new(
+        inner: M,
+        sink: Arc<dyn MetricsSink>,
+        classify: fn(&M::Error) -> ErrorClass,
+    ) -> Self {
+        Self {
+            inner,
+            sink,
+            classify,
+        }
+    }
+}
+
+impl<M: Middleware + 'static> MetricsMiddleware<M> {
+    async fn observe<T, F>(
+        &self,
+        method: &'static str,
+        request: F,
+    ) -> std::result::Result<T, MetricsError<M>>
+    where
+        F: Future<Output = std::result::Result<T, M::Error>> + Send,
+    {
+        let start = Instant::now();
+        let result = request.await;
+
+        let error = result.as_ref().err().map(self.classify);
+        self.sink.record_request(method, start.elapsed(), error);
+
+        result.map_err(FromErr::from)
+    }
+}
+
+impl<M: Middleware> Debug for MetricsMiddleware<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsMiddleware")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum MetricsError<M: Middleware + 'static> {
+    #[snafu(display("Middleware error: {}", source))]
+    MiddlewareError { source: M::Error },
+}
+
+impl<M: Middleware> FromErr<M::Error> for MetricsError<M> {
+    fn from(src: M::Error) -> Self {
+        MetricsError::MiddlewareError { source: src }
+    }
+}
+
+fn block_method(block: &BlockId) -> &'static str {
+    match block {
+        BlockId::Hash(_) => "eth_getBlockByHash",
+        BlockId::Number(_) => "eth_getBlockByNumber",
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> Middleware for MetricsMiddleware<M> {
+    type Error = MetricsError<M>;
+    type Provider = M::Provider;
+    type Inner = M;
+
+    fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    async fn get_block_number(&self) -> std::result::Result<U64, Self::Error> {
+        self.observe("eth_blockNumber", self.inner.get_block_number())
+            .await
+    }
+
+    async fn get_block<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<Option<Block<TxHash>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let block = block_hash_or_number.into();
+        self.observe(block_method(&block), self.inner.get_block(block))
+            .await
+    }
+
+    async fn get_block_with_txs<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<Option<Block<Transaction>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let block = block_hash_or_number.into();
+        self.observe(block_method(&block), self.inner.get_block_with_txs(block))
+            .await
+    }
+
+    async fn get_transaction_count<T>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<U256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from = from.into();
+        self.observe(
+            "eth_getTransactionCount",
+            self.inner.get_transaction_count(from, block),
+        )
+        .await
+    }
+
+    async fn get_balance<T>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<U256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from = from.into();
+        self.observe("eth_getBalance", self.inner.get_balance(from, block))
+            .await
+    }
+
+    async fn get_chainid(&self) -> std::result::Result<U256, Self::Error> {
+        self.observe("eth_chainId", self.inner.get_chainid()).await
+    }
+
+    async fn get_transaction<T>(
+        &self,
+        transaction_hash: T,
+    ) -> std::result::Result<Option<Transaction>, Self::Error>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        let hash = transaction_hash.into();
+        self.observe(
+            "eth_getTransactionByHash",
+            self.inner.get_transaction(hash),
+        )
+        .await
+    }
+
+    async fn get_transaction_receipt<T>(
+        &self,
+        transaction_hash: T,
+    ) -> std::result::Result<Option<TransactionReceipt>, Self::Error>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        let hash = transaction_hash.into();
+        self.observe(
+            "eth_getTransactionReceipt",
+            self.inner.get_transaction_receipt(hash),
+        )
+        .await
+    }
+
+    async fn get_logs(
+        &self,
+        filter: &Filter,
+    ) -> std::result::Result<Vec<Log>, Self::Error> {
+        self.observe("eth_getLogs", self.inner.get_logs(filter))
+            .await
+    }
+
+    async fn get_code<T>(
+        &self,
+        at: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<Bytes, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let at = at.into();
+        self.observe("eth_getCode", self.inner.get_code(at, block))
+            .await
+    }
+
+    async fn get_storage_at<T>(
+        &self,
+        from: T,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> std::result::Result<H256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from = from.into();
+        self.observe(
+            "eth_getStorageAt",
+            self.inner.get_storage_at(from, location, block),
+        )
+        .await
+    }
+
+    async fn get_gas_price(&self) -> std::result::Result<U256, Self::Error> {
+        self.observe("eth_gasPrice", self.inner.get_gas_price())
+            .await
+    }
+
+    async fn fee_history<T>(
+        &self,
+        block_count: T,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> std::result::Result<FeeHistory, Self::Error>
+    where
+        T: Into<U256> + Send + Sync,
+    {
+        let block_count = block_count.into();
+        self.observe(
+            "eth_feeHistory",
+            self.inner
+                .fee_history(block_count, last_block, reward_percentiles),
+        )
+        .await
+    }
+
+    async fn estimate_gas(
+        &self,
+        tx: &TypedTransaction,
+    ) -> std::result::Result<U256, Self::Error> {
+        self.observe("eth_estimateGas", self.inner.estimate_gas(tx))
+            .await
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> std::result::Result<Bytes, Self::Error> {
+        self.observe("eth_call", self.inner.call(tx, block)).await
+    }
+
+    async fn send_transaction<'a, T>(
+        &'a self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        let tx = tx.into();
+        self.observe(
+            "eth_sendTransaction",
+            self.inner.send_transaction(tx, block),
+        )
+        .await
+    }
+
+    async fn send_raw_transaction<'a>(
+        &'a self,
+        tx: Bytes,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    {
+        self.observe(
+            "eth_sendRawTransaction",
+            self.inner.send_raw_transaction(tx),
+        )
+        .await
+    }
+
+    async fn sign<T>(
+        &self,
+        data: T,
+        from: &Address,
+    ) -> std::result::Result<Signature, Self::Error>
+    where
+        T: Into<Bytes> + Send + Sync,
+    {
+        let data = data.into();
+        self.observe("eth_sign", self.inner.sign(data, from)).await
+    }
+}
+
+///
+/// Metrics Middleware Factory
+pub struct MetricsFactory<IF: MiddlewareFactory> {
+    current: MiddlewareSlot<Arc<MetricsMiddleware<IF::Middleware>>>,
+    inner_factory: Arc<IF>,
+    sink: Arc<dyn MetricsSink>,
+}
+
+impl<IF> MetricsFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync,
+{
+    pub async fn new(
+        inner_factory: Arc<IF>,
+        sink: Arc<dyn MetricsSink>,
+    ) -> Result<Arc<Self>> {
+        let inner = inner_factory.current();
+
+        Ok(Arc::new(Self {
+            current: MiddlewareSlot::with_inner(
+                Arc::new(MetricsMiddleware::new(
+                    inner.middleware,
+                    Arc::clone(&sink),
+                    IF::classify,
+                )),
+                inner.generation,
+            ),
+            inner_factory,
+            sink,
+        }))
+    }
+}
+
+#[async_trait]
+impl<IF> MiddlewareFactory for MetricsFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type Middleware = Arc<MetricsMiddleware<IF::Middleware>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.current
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        match err {
+            MetricsError::MiddlewareError { source } => IF::classify(source),
+        }
+    }
+
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        self.rebuild_layer().await
+    }
+}
+
+#[async_trait]
+impl<IF> LayerFactory for MetricsFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type InnerFactory = IF;
+
+    fn inner_factory(&self) -> &Self::InnerFactory {
+        &self.inner_factory
+    }
+
+    fn build(&self, inner_middleware: IF::Middleware) -> Self::Middleware {
+        self.sink.record_rebuild();
+        Arc::new(MetricsMiddleware::new(
+            inner_middleware,
+            Arc::clone(&self.sink),
+            IF::classify,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify_provider_error;
+    use crate::HttpProviderFactory;
+    use offchain_core::ethers::providers::Provider;
+
+    #[tokio::test]
+    async fn metrics_test() {
+        let sink = Arc::new(PrometheusSink::new("offchain"));
+        let (provider, mock) = Provider::mocked();
+        let m = MetricsMiddleware::new(
+            provider,
+            Arc::clone(&sink) as Arc<dyn MetricsSink>,
+            classify_provider_error,
+        );
+
+        mock.push(U64::from(1)).unwrap();
+        m.get_block_number().await.unwrap();
+        // Nothing queued, so the mock fails.
+        assert!(m.get_block_number().await.is_err());
+        sink.record_rebuild();
+
+        let out = sink.export();
+        assert!(out.contains(
+            "offchain_rpc_requests_total{method=\"eth_blockNumber\"} 2"
+        ));
+        assert!(out.contains(
+            "offchain_rpc_errors_total{method=\"eth_blockNumber\",\
+             class=\"transport\"} 1"
+        ));
+        assert!(out.contains(
+            "offchain_rpc_request_duration_seconds_bucket\
+             {method=\"eth_blockNumber\",le=\"+Inf\"} 2"
+        ));
+        assert!(out.contains(
+            "offchain_rpc_request_duration_seconds_count\
+             {method=\"eth_blockNumber\"} 2"
+        ));
+        assert!(out.contains("offchain_factory_rebuilds_total 1"));
+    }
+
+    #[tokio::test]
+    async fn metrics_factory_test() {
+        let sink = Arc::new(PrometheusSink::new("offchain"));
+        let root_factory =
+            HttpProviderFactory::new("http://localhost:8545".to_string())
+                .unwrap();
+        let factory = MetricsFactory::new(root_factory, sink.clone())
+            .await
+            .unwrap();
+
+        let m = factory.new_middleware(None).await.unwrap();
+        let m2 = factory.new_middleware(Some(&m)).await.unwrap();
+        factory.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+
+        // Only actual rebuilds are counted.
+        assert!(sink.export().contains("offchain_factory_rebuilds_total 1"));
+    }
+}
@@ -0,0 +1,462 @@
+use crate::error_class::{is_known_message, is_nonce_message};
+use crate::{
+    ErrorClass, LayerFactory, MiddlewareFactory, MiddlewareSlot, RebuildReason,
+    Result,
+};
+
+use async_trait::async_trait;
+use offchain_core::ethers::providers::{
+    FromErr, Middleware, PendingTransaction,
+};
+use offchain_core::ethers::types::{
+    transaction::eip2718::TypedTransaction, Address, BlockId, BlockNumber,
+    H256, U256,
+};
+use snafu::Snafu;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Nonce of an account, shared by every `NonceManager` built by the same
+/// `NonceManagerFactory`, so it survives middleware rebuilds.
+///
+/// Nonces are handed out, synced and invalidated under the same lock, so a
+/// resync can't be undone by an invalidation racing with it.
+#[derive(Debug, Default)]
+pub struct NonceState {
+    /// Next nonce to hand out, or `None` if it must be synced first.
+    next: Mutex<Option<U256>>,
+}
+
+impl NonceState {
+    /// Forces a resync before the next nonce is handed out.
+    pub async fn invalidate(&self) {
+        *self.next.lock().await = None;
+    }
+}
+
+/// Middleware that assigns nonces locally, so consecutive transactions can be
+/// sent without waiting for the previous ones to reach the mempool. It must
+/// sit above the signer.
+///
+/// Nonces are synced from `eth_getTransactionCount` at the pending block
+/// when first needed, again (retrying once) when the node rejects a
+/// transaction because its nonce is too low, too high or invalid, and lazily
+/// after any other failed send. Other errors, such as underpriced fees, are
+/// returned unchanged. A transaction the node already knows counts as sent,
+/// its hash looked up in the pending block. Nodes without a pending block
+/// can't tell the hash, so the error is returned then, though the nonce
+/// still counts as used. Transactions that already have a nonce are sent
+/// untouched.
+#[derive(Debug)]
+pub struct NonceManager<M> {
+    inner: M,
+    address: Address,
+    state: Arc<NonceState>,
+}
+
+impl<M> NonceManager<M> {
+    pub fn new(inner: M, address: Address, state: Arc<NonceState>) -> Self {
+        Self {
+            inner,
+            address,
+            state,
+        }
+    }
+}
+
+impl<M: Middleware + 'static> NonceManager<M> {
+    async fn next_nonce(
+        &self,
+    ) -> std::result::Result<U256, NonceManagerError<M>> {
+        let mut next = self.state.next.lock().await;
+        let nonce = match *next {
+            Some(nonce) => nonce,
+            None => self.sync().await?,
+        };
+
+        *next = Some(nonce + 1);
+        Ok(nonce)
+    }
+
+    /// Syncs the nonce again, without going back to nonces already handed
+    /// out, which may still be on their way to the node.
+    async fn resync(&self) -> std::result::Result<U256, NonceManagerError<M>> {
+        let mut next = self.state.next.lock().await;
+        let synced = self.sync().await?;
+        let nonce = next.map_or(synced, |next| next.max(synced));
+
+        *next = Some(nonce + 1);
+        Ok(nonce)
+    }
+
+    async fn sync(&self) -> std::result::Result<U256, NonceManagerError<M>> {
+        self.inner
+            .get_transaction_count(
+                self.address,
+                Some(BlockId::Number(BlockNumber::Pending)),
+            )
+            .await
+            .map_err(FromErr::from)
+    }
+
+    /// Hash of the transaction of this account with `nonce` in the pending
+    /// block, if any. Nodes without a pending block return none, or the
+    /// latest block, where it isn't found either.
+    async fn pending_hash(
+        &self,
+        nonce: U256,
+    ) -> std::result::Result<Option<H256>, NonceManagerError<M>> {
+        let block = self
+            .inner
+            .get_block_with_txs(BlockNumber::Pending)
+            .await
+            .map_err(FromErr::from)?;
+
+        Ok(block.and_then(|block| {
+            block
+                .transactions
+                .into_iter()
+                .find(|tx| tx.from == self.address && tx.nonce == nonce)
+                .map(|tx| tx.hash)
+        }))
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum NonceManagerError<M: Middleware + 'static> {
+    #[snafu(display("Middleware error: {}", source))]
+    MiddlewareError { source: M::Error },
+}
+
+impl<M: Middleware> FromErr<M::Error> for NonceManagerError<M> {
+    fn from(src: M::Error) -> Self {
+        NonceManagerError::MiddlewareError { source: src }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> Middleware for NonceManager<M> {
+    type Error = NonceManagerError<M>;
+    type Provider = M::Provider;
+    type Inner = M;
+
+    fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    async fn send_transaction<'a, T>(
+        &'a self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        let mut tx = tx.into();
+
+        if tx.nonce().is_some() {
+            return self
+                .inner
+                .send_transaction(tx, block)
+                .await
+                .map_err(FromErr::from);
+        }
+
+        let nonce = self.next_nonce().await?;
+        tx.set_nonce(nonce);
+        let err = match self.inner.send_transaction(tx.clone(), block).await {
+            Ok(pending) => return Ok(pending),
+            Err(err) => err,
+        };
+        let message = err.to_string();
+
+        if is_known_message(&message) {
+            // Sent before, such as by a retry whose response was lost.
+            return match self.pending_hash(nonce).await? {
+                Some(hash) => {
+                    Ok(PendingTransaction::new(hash, self.provider()))
+                }
+                None => Err(FromErr::from(err)),
+            };
+        }
+
+        if !is_nonce_message(&message) {
+            // The nonce may not have been used, leaving a gap.
+            self.state.invalidate().await;
+            return Err(FromErr::from(err));
+        }
+
+        tx.set_nonce(self.resync().await?);
+        match self.inner.send_transaction(tx, block).await {
+            Ok(pending) => Ok(pending),
+            Err(err) => {
+                self.state.invalidate().await;
+                Err(FromErr::from(err))
+            }
+        }
+    }
+}
+
+///
+/// Nonce Manager Middleware Factory
+pub struct NonceManagerFactory<IF: MiddlewareFactory> {
+    current: MiddlewareSlot<Arc<NonceManager<IF::Middleware>>>,
+    inner_factory: Arc<IF>,
+    address: Address,
+    state: Arc<NonceState>,
+}
+
+impl<IF: MiddlewareFactory + Send + Sync> NonceManagerFactory<IF> {
+    /// `address` is the account transactions are sent from.
+    pub async fn new(
+        inner_factory: Arc<IF>,
+        address: Address,
+    ) -> Result<Arc<Self>> {
+        let inner = inner_factory.current();
+        let state = Arc::new(NonceState::default());
+
+        Ok(Arc::new(Self {
+            current: MiddlewareSlot::with_inner(
+                Arc::new(NonceManager::new(
+                    inner.middleware,
+                    address,
+                    Arc::clone(&state),
+                )),
+                inner.generation,
+            ),
+            inner_factory,
+            address,
+            state,
+        }))
+    }
+
+    pub fn state(&self) -> &Arc<NonceState> {
+        &self.state
+    }
+}
+
+#[async_trait]
+impl<IF> MiddlewareFactory for NonceManagerFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type Middleware = Arc<NonceManager<IF::Middleware>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.current
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        match err {
+            NonceManagerError::MiddlewareError { source } => {
+                IF::classify(source)
+            }
+        }
+    }
+
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        self.rebuild_layer().await
+    }
+}
+
+#[async_trait]
+impl<IF> LayerFactory for NonceManagerFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type InnerFactory = IF;
+
+    fn inner_factory(&self) -> &Self::InnerFactory {
+        &self.inner_factory
+    }
+
+    fn build(&self, inner_middleware: IF::Middleware) -> Self::Middleware {
+        Arc::new(NonceManager::new(
+            inner_middleware,
+            self.address,
+            Arc::clone(&self.state),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_server, HttpProviderFactory};
+    use offchain_core::ethers::providers::{Http, Provider};
+    use offchain_core::ethers::types::{
+        Block, Transaction, TransactionRequest,
+    };
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    #[tokio::test]
+    async fn shared_state_test() {
+        let address = Address::from_low_u64_be(1);
+        let state = Arc::new(NonceState::default());
+        let (provider, mock) = Provider::mocked();
+        let tx = TransactionRequest::new()
+            .from(address)
+            .to(address)
+            .gas(21000)
+            .gas_price(1);
+
+        // Responses are popped from the back.
+        mock.push(H256::from_low_u64_be(1)).unwrap();
+        mock.push(U256::from(5)).unwrap();
+        let m =
+            NonceManager::new(provider.clone(), address, Arc::clone(&state));
+        m.send_transaction(tx.clone(), None).await.unwrap();
+
+        // A rebuilt middleware keeps counting from the shared state.
+        mock.push(H256::from_low_u64_be(2)).unwrap();
+        let m =
+            NonceManager::new(provider.clone(), address, Arc::clone(&state));
+        m.send_transaction(tx.clone(), None).await.unwrap();
+
+        // Failing to send forces a resync.
+        assert!(m.send_transaction(tx.clone(), None).await.is_err());
+        mock.push(H256::from_low_u64_be(3)).unwrap();
+        mock.push(U256::from(9)).unwrap();
+        m.send_transaction(tx.clone(), None).await.unwrap();
+
+        let pending: BlockId = BlockNumber::Pending.into();
+        let sent = |nonce: u64| -> [TypedTransaction; 1] {
+            [tx.clone().nonce(nonce).into()]
+        };
+        mock.assert_request("eth_getTransactionCount", (address, pending))
+            .unwrap();
+        mock.assert_request("eth_sendTransaction", sent(5)).unwrap();
+        mock.assert_request("eth_sendTransaction", sent(6)).unwrap();
+        mock.assert_request("eth_sendTransaction", sent(7)).unwrap();
+        mock.assert_request("eth_getTransactionCount", (address, pending))
+            .unwrap();
+        mock.assert_request("eth_sendTransaction", sent(9)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn known_transaction_test() {
+        let address = Address::from_low_u64_be(1);
+        let hash = H256::from_low_u64_be(7);
+        let url = test_server::serve(move |request| {
+            let response = match request.method() {
+                "eth_getTransactionCount" => request.result(json!("0x5")),
+                "eth_sendTransaction" => request.error(-32000, "already known"),
+                "eth_getBlockByNumber" => {
+                    let tx = Transaction {
+                        hash,
+                        nonce: 5.into(),
+                        from: address,
+                        ..Default::default()
+                    };
+                    let block = Block {
+                        transactions: vec![tx],
+                        ..Default::default()
+                    };
+                    request.result(json!(block))
+                }
+                method => panic!("unexpected method {}", method),
+            };
+            async move { Some(response) }
+        })
+        .await;
+
+        let provider = Provider::<Http>::try_from(url.as_str()).unwrap();
+        let state = Arc::new(NonceState::default());
+        let m = NonceManager::new(provider, address, Arc::clone(&state));
+        let tx = TransactionRequest::new()
+            .from(address)
+            .to(address)
+            .gas(21000)
+            .gas_price(1);
+
+        // The node already has the transaction, so it counts as sent.
+        let pending = m.send_transaction(tx, None).await.unwrap();
+        assert_eq!(*pending, hash);
+        assert_eq!(*state.next.lock().await, Some(6.into()));
+    }
+
+    #[tokio::test]
+    async fn resync_test() {
+        let address = Address::from_low_u64_be(1);
+        let nonces = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sent = Arc::clone(&nonces);
+        let url = test_server::serve(move |request| {
+            let response = match request.method() {
+                "eth_getTransactionCount" => request.result(json!("0x6")),
+                "eth_sendTransaction" => {
+                    let mut sent = sent.lock().unwrap();
+                    sent.push(request.params()[0]["nonce"].clone());
+                    if sent.len() == 1 {
+                        request.error(-32000, "nonce too low")
+                    } else {
+                        request.result(json!(H256::from_low_u64_be(1)))
+                    }
+                }
+                method => panic!("unexpected method {}", method),
+            };
+            async move { Some(response) }
+        })
+        .await;
+
+        let provider = Provider::<Http>::try_from(url.as_str()).unwrap();
+        let state = Arc::new(NonceState::default());
+        *state.next.lock().await = Some(8.into());
+        let m = NonceManager::new(provider, address, Arc::clone(&state));
+        let tx = TransactionRequest::new()
+            .from(address)
+            .to(address)
+            .gas(21000)
+            .gas_price(1);
+
+        // The node lags behind the nonces handed out, which the resync
+        // doesn't reuse.
+        m.send_transaction(tx, None).await.unwrap();
+        assert_eq!(*nonces.lock().unwrap(), [json!("0x8"), json!("0x9")]);
+        assert_eq!(*state.next.lock().await, Some(10.into()));
+    }
+
+    #[tokio::test]
+    async fn known_without_pending_block_test() {
+        let address = Address::from_low_u64_be(1);
+        let url = test_server::serve(move |request| {
+            let response = match request.method() {
+                "eth_getTransactionCount" => request.result(json!("0x5")),
+                "eth_sendTransaction" => request.error(-32000, "already known"),
+                "eth_getBlockByNumber" => request.result(json!(null)),
+                method => panic!("unexpected method {}", method),
+            };
+            async move { Some(response) }
+        })
+        .await;
+
+        let provider = Provider::<Http>::try_from(url.as_str()).unwrap();
+        let state = Arc::new(NonceState::default());
+        let m = NonceManager::new(provider, address, Arc::clone(&state));
+        let tx = TransactionRequest::new()
+            .from(address)
+            .to(address)
+            .gas(21000)
+            .gas_price(1);
+
+        // The hash can't be told, but the nonce is used.
+        let err = m.send_transaction(tx, None).await.unwrap_err();
+        assert!(err.to_string().contains("already known"));
+        assert_eq!(*state.next.lock().await, Some(6.into()));
+    }
+
+    #[tokio::test]
+    async fn nonce_manager_factory_test() {
+        let root_factory =
+            HttpProviderFactory::new("http://localhost:8545".to_string())
+                .unwrap();
+        let factory = NonceManagerFactory::new(root_factory, Address::zero())
+            .await
+            .unwrap();
+
+        let m = factory.new_middleware(None).await.unwrap();
+        let m2 = factory.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+        assert!(Arc::ptr_eq(&m.middleware.state, &m2.middleware.state));
+    }
+}
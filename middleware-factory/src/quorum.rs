@@ -0,0 +1,494 @@
+use crate::failover::Connect;
+use crate::{
+    classify_provider_error, verify_chain_id, Error, ErrorClass,
+    MiddlewareFactory, MiddlewareSlot, NoEndpoints, QuorumTooLow,
+    QuorumUnreachable, RebuildReason, Result, RetryLimitReached,
+};
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use offchain_core::ethers::providers::{
+    JsonRpcClient, Middleware, Provider, ProviderError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use snafu::{ensure, ResultExt, Snafu};
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Weight required for a response to be accepted.
+#[derive(Clone, Copy, Debug)]
+pub enum Quorum {
+    /// More than half of the total weight.
+    Majority,
+    /// The total weight.
+    All,
+    /// A fixed weight.
+    Weight(u64),
+}
+
+impl Quorum {
+    fn threshold(&self, total_weight: u64) -> u64 {
+        match self {
+            Quorum::Majority => total_weight / 2 + 1,
+            Quorum::All => total_weight,
+            Quorum::Weight(weight) => *weight,
+        }
+    }
+
+    /// Threshold for `total_weight`, which must be more than half of it so
+    /// that no two responses can both reach it.
+    fn checked_threshold(&self, total_weight: u64) -> Result<u64> {
+        let threshold = self.threshold(total_weight);
+        ensure!(
+            threshold > total_weight / 2,
+            QuorumTooLow {
+                weight: total_weight,
+                quorum: threshold,
+            }
+        );
+
+        Ok(threshold)
+    }
+}
+
+#[derive(Debug)]
+pub struct WeightedProvider<T> {
+    pub provider: Provider<T>,
+    pub weight: u64,
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum QuorumError {
+    #[snafu(display(
+        "Providers disagree on `{}`, responses and weights: {:?}",
+        method,
+        responses
+    ))]
+    Disagreement {
+        method: String,
+        responses: Vec<(Value, u64)>,
+    },
+
+    #[snafu(display(
+        "Quorum of {} not reached on `{}` with weight {}, errors: {:?}",
+        quorum,
+        method,
+        weight,
+        errors
+    ))]
+    NoQuorum {
+        method: String,
+        weight: u64,
+        quorum: u64,
+        errors: Vec<String>,
+    },
+
+    #[snafu(display("Provider error: {}", source))]
+    MemberError { source: ProviderError },
+
+    #[snafu(display("Serialization error: {}", source))]
+    SerdeJson { source: serde_json::Error },
+}
+
+impl QuorumError {
+    /// Retrieves the `QuorumError` from the error of a `QuorumTransport`
+    /// provider, if that is where it came from.
+    pub fn from_provider_error(err: &ProviderError) -> Option<&Self> {
+        match err {
+            ProviderError::JsonRpcClientError(err) => err.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl From<QuorumError> for ProviderError {
+    fn from(err: QuorumError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(err))
+    }
+}
+
+/// Transport that sends read requests to every member, returning a response
+/// only when members adding up to the quorum weight agree on it. Requests
+/// that send or sign transactions go to the first member only, so they are
+/// not duplicated, and so do filter requests, as filter ids are only known to
+/// the member that installed them.
+#[derive(Debug)]
+pub struct QuorumTransport<T> {
+    members: Vec<WeightedProvider<T>>,
+    quorum: u64,
+}
+
+impl<T: JsonRpcClient> QuorumTransport<T> {
+    pub fn new(
+        members: Vec<WeightedProvider<T>>,
+        quorum: Quorum,
+    ) -> Result<Self> {
+        ensure!(!members.is_empty(), NoEndpoints);
+        let total_weight = members.iter().map(|m| m.weight).sum();
+
+        Ok(Self {
+            members,
+            quorum: quorum.checked_threshold(total_weight)?,
+        })
+    }
+
+    async fn read(
+        &self,
+        method: &str,
+        params: &Value,
+    ) -> std::result::Result<Value, QuorumError> {
+        let responses =
+            join_all(self.members.iter().map(|member| async move {
+                let res: std::result::Result<Value, _> =
+                    member.provider.as_ref().request(method, params).await;
+                (member.weight, res.map_err(Into::<ProviderError>::into))
+            }))
+            .await;
+
+        let mut groups: Vec<(Value, u64)> = Vec::new();
+        let mut errors = Vec::new();
+        for (weight, res) in responses {
+            match res {
+                Ok(value) => {
+                    match groups.iter_mut().find(|(v, _)| *v == value) {
+                        Some((_, total)) => *total += weight,
+                        None => groups.push((value, weight)),
+                    }
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+
+        if let Some(index) = groups.iter().position(|(_, w)| *w >= self.quorum)
+        {
+            return Ok(groups.swap_remove(index).0);
+        }
+
+        ensure!(
+            groups.len() <= 1,
+            Disagreement {
+                method,
+                responses: groups,
+            }
+        );
+
+        NoQuorum {
+            method,
+            weight: groups.first().map(|(_, w)| *w).unwrap_or_default(),
+            quorum: self.quorum,
+            errors,
+        }
+        .fail()
+    }
+}
+
+#[async_trait]
+impl<T: JsonRpcClient> JsonRpcClient for QuorumTransport<T> {
+    type Error = QuorumError;
+
+    async fn request<P, R>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> std::result::Result<R, Self::Error>
+    where
+        P: Debug + Serialize + Send + Sync,
+        R: Serialize + DeserializeOwned,
+    {
+        let params = serde_json::to_value(params).context(SerdeJson)?;
+
+        let value = if is_single_member(method) {
+            self.members[0]
+                .provider
+                .as_ref()
+                .request(method, &params)
+                .await
+                .map_err(Into::into)
+                .context(MemberError)?
+        } else {
+            self.read(method, &params).await?
+        };
+
+        serde_json::from_value(value).context(SerdeJson)
+    }
+}
+
+fn is_single_member(method: &str) -> bool {
+    method.starts_with("eth_send")
+        || method.starts_with("eth_sign")
+        || method.starts_with("personal_")
+        || matches!(
+            method,
+            "eth_newFilter"
+                | "eth_newBlockFilter"
+                | "eth_newPendingTransactionFilter"
+                | "eth_getFilterChanges"
+                | "eth_getFilterLogs"
+                | "eth_uninstallFilter"
+        )
+}
+
+///
+/// "Root" Quorum Middleware Factory
+///
+/// Connects to every endpoint, each with its own weight. Endpoints that fail
+/// to connect, or that are on a chain other than the expected one, are left
+/// out, as long as the remaining ones can still reach the quorum. The quorum
+/// must be more than half of the total weight.
+pub struct QuorumFactory<T: Connect> {
+    provider: MiddlewareSlot<Arc<Provider<QuorumTransport<T>>>>,
+    endpoints: Vec<(String, u64)>,
+    options: T::Options,
+    quorum: Quorum,
+    expected_chain_id: Option<u64>,
+    max_retries: usize,
+    max_delay: Duration,
+}
+
+impl<T: Connect> QuorumFactory<T> {
+    pub async fn new(
+        endpoints: Vec<(String, u64)>,
+        options: T::Options,
+        quorum: Quorum,
+        expected_chain_id: Option<u64>,
+        max_retries: usize,
+        max_delay: Duration,
+    ) -> Result<Arc<Self>> {
+        ensure!(!endpoints.is_empty(), NoEndpoints);
+        let total_weight = endpoints.iter().map(|(_, w)| w).sum();
+        let threshold = quorum.checked_threshold(total_weight)?;
+        ensure!(
+            threshold <= total_weight,
+            QuorumUnreachable {
+                weight: total_weight,
+                quorum: threshold,
+            }
+        );
+
+        let provider = Self::connect(
+            &endpoints,
+            &options,
+            quorum,
+            expected_chain_id,
+            max_retries,
+            max_delay,
+        )
+        .await?;
+
+        Ok(Arc::new(Self {
+            provider: MiddlewareSlot::new(Arc::new(provider)),
+            endpoints,
+            options,
+            quorum,
+            expected_chain_id,
+            max_retries,
+            max_delay,
+        }))
+    }
+
+    async fn connect(
+        endpoints: &[(String, u64)],
+        options: &T::Options,
+        quorum: Quorum,
+        expected_chain_id: Option<u64>,
+        max_retries: usize,
+        max_delay: Duration,
+    ) -> Result<Provider<QuorumTransport<T>>> {
+        let total_weight = endpoints.iter().map(|(_, w)| w).sum();
+        let threshold = quorum.threshold(total_weight);
+        let mut backoff = backoff::Backoff::new(max_retries, max_delay);
+
+        loop {
+            let mut members = Vec::new();
+            let mut last_error = None;
+
+            for (url, weight) in endpoints {
+                let connected = match T::connect(url, options).await {
+                    Ok(p) => verify_chain_id(p, expected_chain_id).await,
+                    Err(e) => Err(e),
+                };
+
+                match connected {
+                    Ok(provider) => members.push(WeightedProvider {
+                        provider,
+                        weight: *weight,
+                    }),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+
+            let weight: u64 = members.iter().map(|m| m.weight).sum();
+            if !members.is_empty() && weight >= threshold {
+                // The threshold is computed from the configured endpoints,
+                // not only the connected ones.
+                return Ok(Provider::new(QuorumTransport {
+                    members,
+                    quorum: threshold,
+                }));
+            }
+
+            if backoff.wait().await.is_err() {
+                // Some endpoint failed, otherwise the weight would be enough.
+                let last_error: Error = last_error.unwrap();
+                return RetryLimitReached {
+                    retries: max_retries,
+                    last_error: Box::new(last_error),
+                }
+                .fail();
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Connect + 'static> MiddlewareFactory for QuorumFactory<T> {
+    type Middleware = Arc<Provider<QuorumTransport<T>>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.provider
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        classify_provider_error(err)
+    }
+
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        let provider = Self::connect(
+            &self.endpoints,
+            &self.options,
+            self.quorum,
+            self.expected_chain_id,
+            self.max_retries,
+            self.max_delay,
+        )
+        .await?;
+        Ok((Arc::new(provider), RebuildReason::Stale))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use offchain_core::ethers::providers::{FilterKind, Http, MockProvider};
+    use offchain_core::ethers::types::{H256, U256, U64};
+    use serde_json::json;
+
+    fn new_transport(
+        weights: &[u64],
+        quorum: Quorum,
+    ) -> (Provider<QuorumTransport<MockProvider>>, Vec<MockProvider>) {
+        let (members, mocks) = weights
+            .iter()
+            .map(|&weight| {
+                let (provider, mock) = Provider::mocked();
+                (WeightedProvider { provider, weight }, mock)
+            })
+            .unzip();
+
+        let transport = QuorumTransport::new(members, quorum).unwrap();
+        (Provider::new(transport), mocks)
+    }
+
+    #[tokio::test]
+    async fn quorum_test() {
+        let (provider, mocks) = new_transport(&[1, 1, 2], Quorum::Majority);
+
+        for (mock, number) in mocks.iter().zip([5u64, 6, 5]) {
+            mock.push(U64::from(number)).unwrap();
+        }
+        assert_eq!(provider.get_block_number().await.unwrap(), 5.into());
+
+        for (mock, number) in mocks.iter().zip([5u64, 5, 6]) {
+            mock.push(U64::from(number)).unwrap();
+        }
+        let err = provider.get_block_number().await.unwrap_err();
+        assert!(matches!(
+            QuorumError::from_provider_error(&err),
+            Some(QuorumError::Disagreement { responses, .. })
+                if responses.len() == 2
+        ));
+
+        // Mocks without responses fail the request.
+        mocks[2].push(U64::from(5)).unwrap();
+        let err = provider.get_block_number().await.unwrap_err();
+        assert!(matches!(
+            QuorumError::from_provider_error(&err),
+            Some(QuorumError::NoQuorum { weight: 2, quorum: 3, errors, .. })
+                if errors.len() == 2
+        ));
+    }
+
+    #[tokio::test]
+    async fn filter_test() {
+        let (provider, mocks) = new_transport(&[1, 1, 2], Quorum::Majority);
+
+        // Only the first member answers, which alone can't reach the quorum.
+        mocks[0].push(U256::from(7)).unwrap();
+        let id = provider.new_filter(FilterKind::NewBlocks).await.unwrap();
+        assert_eq!(id, U256::from(7));
+
+        mocks[0].push::<Vec<H256>, _>(vec![H256::zero()]).unwrap();
+        let changes: Vec<H256> = provider.get_filter_changes(id).await.unwrap();
+        assert_eq!(changes, vec![H256::zero()]);
+
+        mocks[0].push(true).unwrap();
+        assert!(provider.uninstall_filter(id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn quorum_factory_test() {
+        let mut endpoints = Vec::new();
+        for _ in 0..2 {
+            let url = test_server::serve(|request| async move {
+                Some(request.result(json!("0x1")))
+            })
+            .await;
+            endpoints.push((url, 1));
+        }
+
+        let new = |endpoints: Vec<(String, u64)>, quorum| {
+            QuorumFactory::<Http>::new(
+                endpoints,
+                (),
+                quorum,
+                None,
+                0,
+                Duration::from_secs(1),
+            )
+        };
+        assert!(matches!(
+            new(endpoints.clone(), Quorum::Weight(3)).await,
+            Err(Error::QuorumUnreachable { .. })
+        ));
+        assert!(matches!(
+            new(endpoints.clone(), Quorum::Weight(1)).await,
+            Err(Error::QuorumTooLow { .. })
+        ));
+        assert!(matches!(
+            new(Vec::new(), Quorum::Majority).await,
+            Err(Error::NoEndpoints {})
+        ));
+
+        let factory = QuorumFactory::<Http>::new(
+            endpoints,
+            (),
+            Quorum::All,
+            None,
+            0,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        let m = factory.new_middleware(None).await.unwrap();
+        let m_same = factory.new_middleware(None).await.unwrap();
+        assert!(Arc::ptr_eq(&m.middleware, &m_same.middleware));
+
+        let m2 = factory.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+    }
+}
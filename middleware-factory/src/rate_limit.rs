@@ -0,0 +1,638 @@
+use crate::{
+    ErrorClass, LayerFactory, MiddlewareFactory, MiddlewareSlot, RebuildReason,
+    Result,
+};
+
+use async_trait::async_trait;
+use offchain_core::ethers::providers::{
+    FeeHistory, FilterKind, FromErr, Middleware, PendingTransaction,
+};
+use offchain_core::ethers::types::{
+    transaction::{eip2718::TypedTransaction, eip2930::AccessListWithGasUsed},
+    Address, Block, BlockId, BlockNumber, BlockTrace, Bytes,
+    EIP1186ProofResponse, Filter, Log, NameOrAddress, Signature, Trace,
+    TraceFilter, TraceType, Transaction, TransactionReceipt, TxHash,
+    TxpoolContent, TxpoolInspect, TxpoolStatus, H256, U256, U64,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use snafu::Snafu;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Limits requests to `max_requests` per `period`, letting up to
+/// `max_requests` through at once after a quiet period. Shared by every
+/// `RateLimitMiddleware` built by the same `RateLimitFactory`, so rebuilds
+/// don't reset it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Time each request takes from the budget.
+    interval: Duration,
+    /// Budget that may be used at once.
+    burst: Duration,
+    /// Time at which the budget is fully spent.
+    spent_until: std::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests: u32, period: Duration) -> Self {
+        let max_requests = max_requests.max(1);
+
+        Self {
+            interval: period / max_requests,
+            burst: period,
+            spent_until: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until a request fits in the budget, and takes it.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut spent_until = self.spent_until.lock().unwrap();
+            let now = Instant::now();
+            *spent_until = (*spent_until).max(now) + self.interval;
+            spent_until
+                .saturating_duration_since(now)
+                .saturating_sub(self.burst)
+        };
+
+        if wait > Duration::ZERO {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Middleware holding back every request made through it until the rate
+/// limiter lets it through. Watchers and subscriptions poll the provider
+/// directly, so their streams go through unlimited.
+#[derive(Debug)]
+pub struct RateLimitMiddleware<M> {
+    inner: M,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<M> RateLimitMiddleware<M> {
+    pub fn new(inner: M, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl<M: Middleware + 'static> RateLimitMiddleware<M> {
+    async fn limit<T, F>(
+        &self,
+        request: F,
+    ) -> std::result::Result<T, RateLimitError<M>>
+    where
+        F: Future<Output = std::result::Result<T, M::Error>> + Send,
+    {
+        self.limiter.acquire().await;
+        request.await.map_err(FromErr::from)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum RateLimitError<M: Middleware + 'static> {
+    #[snafu(display("Middleware error: {}", source))]
+    MiddlewareError { source: M::Error },
+}
+
+impl<M: Middleware> FromErr<M::Error> for RateLimitError<M> {
+    fn from(src: M::Error) -> Self {
+        RateLimitError::MiddlewareError { source: src }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> Middleware for RateLimitMiddleware<M> {
+    type Error = RateLimitError<M>;
+    type Provider = M::Provider;
+    type Inner = M;
+
+    fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    async fn get_block_number(&self) -> std::result::Result<U64, Self::Error> {
+        self.limit(self.inner.get_block_number()).await
+    }
+
+    async fn get_block<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<Option<Block<TxHash>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let block = block_hash_or_number.into();
+        self.limit(self.inner.get_block(block)).await
+    }
+
+    async fn get_block_with_txs<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<Option<Block<Transaction>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let block = block_hash_or_number.into();
+        self.limit(self.inner.get_block_with_txs(block)).await
+    }
+
+    async fn get_transaction_count<T>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<U256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from = from.into();
+        self.limit(self.inner.get_transaction_count(from, block))
+            .await
+    }
+
+    async fn get_balance<T>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<U256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from = from.into();
+        self.limit(self.inner.get_balance(from, block)).await
+    }
+
+    async fn get_chainid(&self) -> std::result::Result<U256, Self::Error> {
+        self.limit(self.inner.get_chainid()).await
+    }
+
+    async fn get_transaction<T>(
+        &self,
+        transaction_hash: T,
+    ) -> std::result::Result<Option<Transaction>, Self::Error>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        let hash = transaction_hash.into();
+        self.limit(self.inner.get_transaction(hash)).await
+    }
+
+    async fn get_transaction_receipt<T>(
+        &self,
+        transaction_hash: T,
+    ) -> std::result::Result<Option<TransactionReceipt>, Self::Error>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        let hash = transaction_hash.into();
+        self.limit(self.inner.get_transaction_receipt(hash)).await
+    }
+
+    async fn get_logs(
+        &self,
+        filter: &Filter,
+    ) -> std::result::Result<Vec<Log>, Self::Error> {
+        self.limit(self.inner.get_logs(filter)).await
+    }
+
+    async fn get_code<T>(
+        &self,
+        at: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<Bytes, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let at = at.into();
+        self.limit(self.inner.get_code(at, block)).await
+    }
+
+    async fn get_storage_at<T>(
+        &self,
+        from: T,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> std::result::Result<H256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from = from.into();
+        self.limit(self.inner.get_storage_at(from, location, block))
+            .await
+    }
+
+    async fn get_gas_price(&self) -> std::result::Result<U256, Self::Error> {
+        self.limit(self.inner.get_gas_price()).await
+    }
+
+    async fn fee_history<T>(
+        &self,
+        block_count: T,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> std::result::Result<FeeHistory, Self::Error>
+    where
+        T: Into<U256> + Send + Sync,
+    {
+        let block_count = block_count.into();
+        self.limit(self.inner.fee_history(
+            block_count,
+            last_block,
+            reward_percentiles,
+        ))
+        .await
+    }
+
+    async fn estimate_gas(
+        &self,
+        tx: &TypedTransaction,
+    ) -> std::result::Result<U256, Self::Error> {
+        self.limit(self.inner.estimate_gas(tx)).await
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> std::result::Result<Bytes, Self::Error> {
+        self.limit(self.inner.call(tx, block)).await
+    }
+
+    async fn send_transaction<'a, T>(
+        &'a self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        let tx = tx.into();
+        self.limit(self.inner.send_transaction(tx, block)).await
+    }
+
+    async fn send_raw_transaction<'a>(
+        &'a self,
+        tx: Bytes,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    {
+        self.limit(self.inner.send_raw_transaction(tx)).await
+    }
+
+    async fn sign<T>(
+        &self,
+        data: T,
+        from: &Address,
+    ) -> std::result::Result<Signature, Self::Error>
+    where
+        T: Into<Bytes> + Send + Sync,
+    {
+        let data = data.into();
+        self.limit(self.inner.sign(data, from)).await
+    }
+
+    async fn client_version(&self) -> std::result::Result<String, Self::Error> {
+        self.limit(self.inner.client_version()).await
+    }
+
+    async fn resolve_name(
+        &self,
+        ens_name: &str,
+    ) -> std::result::Result<Address, Self::Error> {
+        self.limit(self.inner.resolve_name(ens_name)).await
+    }
+
+    async fn lookup_address(
+        &self,
+        address: Address,
+    ) -> std::result::Result<String, Self::Error> {
+        self.limit(self.inner.lookup_address(address)).await
+    }
+
+    async fn get_uncle_count<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<U256, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let block = block_hash_or_number.into();
+        self.limit(self.inner.get_uncle_count(block)).await
+    }
+
+    async fn get_uncle<T>(
+        &self,
+        block_hash_or_number: T,
+        idx: U64,
+    ) -> std::result::Result<Option<Block<H256>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let block = block_hash_or_number.into();
+        self.limit(self.inner.get_uncle(block, idx)).await
+    }
+
+    async fn get_block_receipts<T>(
+        &self,
+        block: T,
+    ) -> std::result::Result<Vec<TransactionReceipt>, Self::Error>
+    where
+        T: Into<BlockNumber> + Send + Sync,
+    {
+        let block = block.into();
+        self.limit(self.inner.get_block_receipts(block)).await
+    }
+
+    async fn estimate_eip1559_fees(
+        &self,
+        estimator: Option<fn(U256, Vec<Vec<U256>>) -> (U256, U256)>,
+    ) -> std::result::Result<(U256, U256), Self::Error> {
+        self.limit(self.inner.estimate_eip1559_fees(estimator))
+            .await
+    }
+
+    async fn get_accounts(
+        &self,
+    ) -> std::result::Result<Vec<Address>, Self::Error> {
+        self.limit(self.inner.get_accounts()).await
+    }
+
+    async fn new_filter(
+        &self,
+        filter: FilterKind<'_>,
+    ) -> std::result::Result<U256, Self::Error> {
+        self.limit(self.inner.new_filter(filter)).await
+    }
+
+    async fn uninstall_filter<T>(
+        &self,
+        id: T,
+    ) -> std::result::Result<bool, Self::Error>
+    where
+        T: Into<U256> + Send + Sync,
+    {
+        let id = id.into();
+        self.limit(self.inner.uninstall_filter(id)).await
+    }
+
+    async fn get_filter_changes<T, R>(
+        &self,
+        id: T,
+    ) -> std::result::Result<Vec<R>, Self::Error>
+    where
+        T: Into<U256> + Send + Sync,
+        R: Serialize + DeserializeOwned + Send + Sync + Debug,
+    {
+        let id = id.into();
+        self.limit(self.inner.get_filter_changes(id)).await
+    }
+
+    async fn get_proof<T>(
+        &self,
+        from: T,
+        locations: Vec<H256>,
+        block: Option<BlockId>,
+    ) -> std::result::Result<EIP1186ProofResponse, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from = from.into();
+        self.limit(self.inner.get_proof(from, locations, block))
+            .await
+    }
+
+    async fn txpool_content(
+        &self,
+    ) -> std::result::Result<TxpoolContent, Self::Error> {
+        self.limit(self.inner.txpool_content()).await
+    }
+
+    async fn txpool_inspect(
+        &self,
+    ) -> std::result::Result<TxpoolInspect, Self::Error> {
+        self.limit(self.inner.txpool_inspect()).await
+    }
+
+    async fn txpool_status(
+        &self,
+    ) -> std::result::Result<TxpoolStatus, Self::Error> {
+        self.limit(self.inner.txpool_status()).await
+    }
+
+    async fn trace_call<T>(
+        &self,
+        req: T,
+        trace_type: Vec<TraceType>,
+        block: Option<BlockNumber>,
+    ) -> std::result::Result<BlockTrace, Self::Error>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        let req = req.into();
+        self.limit(self.inner.trace_call(req, trace_type, block))
+            .await
+    }
+
+    async fn trace_raw_transaction(
+        &self,
+        data: Bytes,
+        trace_type: Vec<TraceType>,
+    ) -> std::result::Result<BlockTrace, Self::Error> {
+        self.limit(self.inner.trace_raw_transaction(data, trace_type))
+            .await
+    }
+
+    async fn trace_replay_transaction(
+        &self,
+        hash: H256,
+        trace_type: Vec<TraceType>,
+    ) -> std::result::Result<BlockTrace, Self::Error> {
+        self.limit(self.inner.trace_replay_transaction(hash, trace_type))
+            .await
+    }
+
+    async fn trace_replay_block_transactions(
+        &self,
+        block: BlockNumber,
+        trace_type: Vec<TraceType>,
+    ) -> std::result::Result<Vec<BlockTrace>, Self::Error> {
+        self.limit(
+            self.inner
+                .trace_replay_block_transactions(block, trace_type),
+        )
+        .await
+    }
+
+    async fn trace_block(
+        &self,
+        block: BlockNumber,
+    ) -> std::result::Result<Vec<Trace>, Self::Error> {
+        self.limit(self.inner.trace_block(block)).await
+    }
+
+    async fn trace_filter(
+        &self,
+        filter: TraceFilter,
+    ) -> std::result::Result<Vec<Trace>, Self::Error> {
+        self.limit(self.inner.trace_filter(filter)).await
+    }
+
+    async fn trace_get<T>(
+        &self,
+        hash: H256,
+        index: Vec<T>,
+    ) -> std::result::Result<Trace, Self::Error>
+    where
+        T: Into<U64> + Send + Sync,
+    {
+        let index: Vec<U64> = index.into_iter().map(Into::into).collect();
+        self.limit(self.inner.trace_get(hash, index)).await
+    }
+
+    async fn trace_transaction(
+        &self,
+        hash: H256,
+    ) -> std::result::Result<Vec<Trace>, Self::Error> {
+        self.limit(self.inner.trace_transaction(hash)).await
+    }
+
+    async fn parity_block_receipts<T>(
+        &self,
+        block: T,
+    ) -> std::result::Result<Vec<TransactionReceipt>, Self::Error>
+    where
+        T: Into<BlockNumber> + Send + Sync,
+    {
+        let block = block.into();
+        self.limit(self.inner.parity_block_receipts(block)).await
+    }
+
+    async fn create_access_list(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> std::result::Result<AccessListWithGasUsed, Self::Error> {
+        self.limit(self.inner.create_access_list(tx, block)).await
+    }
+}
+
+///
+/// Rate Limit Middleware Factory
+pub struct RateLimitFactory<IF: MiddlewareFactory> {
+    current: MiddlewareSlot<Arc<RateLimitMiddleware<IF::Middleware>>>,
+    inner_factory: Arc<IF>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<IF> RateLimitFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync,
+{
+    pub async fn new(
+        inner_factory: Arc<IF>,
+        limiter: RateLimiter,
+    ) -> Result<Arc<Self>> {
+        let inner = inner_factory.current();
+        let limiter = Arc::new(limiter);
+
+        Ok(Arc::new(Self {
+            current: MiddlewareSlot::with_inner(
+                Arc::new(RateLimitMiddleware::new(
+                    inner.middleware,
+                    Arc::clone(&limiter),
+                )),
+                inner.generation,
+            ),
+            inner_factory,
+            limiter,
+        }))
+    }
+}
+
+#[async_trait]
+impl<IF> MiddlewareFactory for RateLimitFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type Middleware = Arc<RateLimitMiddleware<IF::Middleware>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.current
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        match err {
+            RateLimitError::MiddlewareError { source } => IF::classify(source),
+        }
+    }
+
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        self.rebuild_layer().await
+    }
+}
+
+#[async_trait]
+impl<IF> LayerFactory for RateLimitFactory<IF>
+where
+    IF: MiddlewareFactory + Send + Sync + 'static,
+{
+    type InnerFactory = IF;
+
+    fn inner_factory(&self) -> &Self::InnerFactory {
+        &self.inner_factory
+    }
+
+    fn build(&self, inner_middleware: IF::Middleware) -> Self::Middleware {
+        Arc::new(RateLimitMiddleware::new(
+            inner_middleware,
+            Arc::clone(&self.limiter),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpProviderFactory;
+    use offchain_core::ethers::providers::Provider;
+
+    #[tokio::test]
+    async fn rate_limit_test() {
+        tokio::time::pause();
+        let limiter = Arc::new(RateLimiter::new(2, Duration::from_millis(400)));
+        let (provider, mock) = Provider::mocked();
+        let m = RateLimitMiddleware::new(provider, limiter);
+        mock.push(U64::from(1)).unwrap();
+        mock.push::<Vec<Address>, _>(vec![]).unwrap();
+        mock.push(U64::from(1)).unwrap();
+
+        // The first two requests fit in the burst, the third waits.
+        let start = Instant::now();
+        m.get_block_number().await.unwrap();
+        m.get_accounts().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        m.get_block_number().await.unwrap();
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(200));
+        assert!(waited < Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_factory_test() {
+        let root_factory =
+            HttpProviderFactory::new("http://localhost:8545".to_string())
+                .unwrap();
+        let limiter = RateLimiter::new(10, Duration::from_secs(1));
+        let factory =
+            RateLimitFactory::new(root_factory, limiter).await.unwrap();
+
+        // Rebuilds share the limiter.
+        let m = factory.new_middleware(None).await.unwrap();
+        let m2 = factory.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+        assert!(Arc::ptr_eq(&m.middleware.limiter, &m2.middleware.limiter));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{HttpTransport, Result};
+
+use async_trait::async_trait;
+use configuration::HttpOptions;
+use offchain_core::ethers::core::types::SignatureError;
+use offchain_core::ethers::providers::{JsonRpcClient, ProviderError};
+use offchain_core::ethers::signers::Signer;
+use offchain_core::ethers::types::{
+    transaction::eip2718::TypedTransaction, Address, Bytes, RecoveryMessage,
+    Signature, U256, U64,
+};
+use offchain_core::ethers::utils::rlp::{DecoderError, Rlp};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use std::convert::TryFrom;
+
+/// Signer that keeps the key out of this process, asking a remote signer
+/// for signatures over JSON-RPC. Messages are signed with `eth_sign` and
+/// transactions with `eth_signTransaction`, as in web3signer, clef and geth.
+///
+/// Every signature is recovered and checked against `address` before being
+/// used. Requests go through `HttpTransport`, so `HttpOptions` can set their
+/// timeout and authenticate them.
+#[derive(Clone, Debug)]
+pub struct RemoteSigner {
+    transport: HttpTransport,
+    address: Address,
+    chain_id: u64,
+}
+
+impl RemoteSigner {
+    pub fn new(url: &str, address: Address, chain_id: u64) -> Result<Self> {
+        Self::new_with_options(url, &HttpOptions::default(), address, chain_id)
+    }
+
+    pub fn new_with_options(
+        url: &str,
+        options: &HttpOptions,
+        address: Address,
+        chain_id: u64,
+    ) -> Result<Self> {
+        let transport = HttpTransport::new(url, options)?;
+
+        Ok(Self {
+            transport,
+            address,
+            chain_id,
+        })
+    }
+
+    fn check(
+        &self,
+        signature: Signature,
+        message: RecoveryMessage,
+    ) -> std::result::Result<Signature, RemoteSignerError> {
+        let recovered = signature.recover(message).context(InvalidSignature)?;
+        ensure!(
+            recovered == self.address,
+            WrongSigner {
+                expected: self.address,
+                recovered,
+            }
+        );
+
+        Ok(signature)
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum RemoteSignerError {
+    #[snafu(display("Remote signer request failed: {}", source))]
+    RequestError { source: ProviderError },
+
+    #[snafu(display("Invalid signature: {}", source))]
+    InvalidSignature { source: SignatureError },
+
+    #[snafu(display("Invalid signed transaction: {}", reason))]
+    InvalidTransaction { reason: DecoderError },
+
+    #[snafu(display("Expected signature of {}, got {}", expected, recovered))]
+    WrongSigner {
+        expected: Address,
+        recovered: Address,
+    },
+}
+
+/// Response to `eth_signTransaction`. Geth and clef wrap the raw transaction
+/// in an object, web3signer returns it alone.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum SignedTransaction {
+    Raw(Bytes),
+    Wrapped { raw: Bytes },
+}
+
+impl SignedTransaction {
+    fn raw(&self) -> &[u8] {
+        match self {
+            SignedTransaction::Raw(raw) => raw.as_ref(),
+            SignedTransaction::Wrapped { raw } => raw.as_ref(),
+        }
+    }
+}
+
+/// Extracts the signature from a signed transaction, which ends in `v`, `r`
+/// and `s` for every transaction type. Typed transactions only carry the
+/// parity of `v`, which is turned back into the EIP-155 `v` that local
+/// signers return.
+fn decode_signature(
+    raw: &[u8],
+    chain_id: u64,
+) -> std::result::Result<Signature, DecoderError> {
+    // Typed transactions are prefixed by their type.
+    let (payload, typed) = match raw.first() {
+        Some(&first) if first < 0xc0 => (&raw[1..], true),
+        _ => (raw, false),
+    };
+
+    let rlp = Rlp::new(payload);
+    let count = rlp.item_count()?;
+    if count < 3 {
+        return Err(DecoderError::RlpIncorrectListLen);
+    }
+
+    let v: u64 = rlp.val_at(count - 3)?;
+    Ok(Signature {
+        v: if typed { v + chain_id * 2 + 35 } else { v },
+        r: rlp.val_at::<U256>(count - 2)?,
+        s: rlp.val_at::<U256>(count - 1)?,
+    })
+}
+
+#[async_trait]
+impl Signer for RemoteSigner {
+    type Error = RemoteSignerError;
+
+    async fn sign_message<S>(
+        &self,
+        message: S,
+    ) -> std::result::Result<Signature, Self::Error>
+    where
+        S: Send + Sync + AsRef<[u8]>,
+    {
+        let message = message.as_ref().to_vec();
+        let signature: Bytes = self
+            .transport
+            .request("eth_sign", (self.address, Bytes::from(message.clone())))
+            .await
+            .map_err(Into::into)
+            .context(RequestError)?;
+
+        let signature = Signature::try_from(signature.as_ref())
+            .context(InvalidSignature)?;
+        self.check(signature, RecoveryMessage::Data(message))
+    }
+
+    async fn sign_transaction(
+        &self,
+        tx: &TypedTransaction,
+    ) -> std::result::Result<Signature, Self::Error> {
+        let mut tx = tx.clone();
+        tx.set_from(self.address);
+
+        // Transaction requests don't carry the chain id.
+        let mut params =
+            serde_json::to_value(&tx).expect("transactions always serialize");
+        params["chainId"] = serde_json::to_value(U64::from(self.chain_id))
+            .expect("numbers always serialize");
+
+        let signed: SignedTransaction = self
+            .transport
+            .request("eth_signTransaction", [params])
+            .await
+            .map_err(Into::into)
+            .context(RequestError)?;
+
+        let signature = decode_signature(signed.raw(), self.chain_id).map_err(
+            |reason| RemoteSignerError::InvalidTransaction { reason },
+        )?;
+        self.check(signature, RecoveryMessage::Hash(tx.sighash(self.chain_id)))
+    }
+
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    fn with_chain_id<T: Into<u64>>(mut self, chain_id: T) -> Self {
+        self.chain_id = chain_id.into();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use offchain_core::ethers::signers::LocalWallet;
+    use offchain_core::ethers::types::{
+        Eip1559TransactionRequest, TransactionRequest,
+    };
+    use serde_json::json;
+
+    /// Stand-in remote signer, answering JSON-RPC over HTTP with `wallet`.
+    async fn serve(wallet: LocalWallet) -> String {
+        test_server::serve(move |request| {
+            let wallet = wallet.clone();
+            async move {
+                let params = request.params();
+                let result = match request.method() {
+                    "eth_sign" => {
+                        let message: Bytes =
+                            serde_json::from_value(params[1].clone()).unwrap();
+                        let signature = wallet
+                            .sign_message(message.as_ref())
+                            .await
+                            .unwrap();
+                        json!(Bytes::from(signature.to_vec()))
+                    }
+                    "eth_signTransaction" => {
+                        let tx: TypedTransaction =
+                            serde_json::from_value(params[0].clone()).unwrap();
+                        let signature = wallet.sign_transaction_sync(&tx);
+                        json!({
+                            "raw": tx.rlp_signed(wallet.chain_id(), &signature)
+                        })
+                    }
+                    method => panic!("unexpected method {}", method),
+                };
+                Some(request.result(result))
+            }
+        })
+        .await
+    }
+
+    fn wallet() -> LocalWallet {
+        "380eb0f3d505f087e438eca80bc4df9a7faa24f868e69fc0440261a0fc0567dc"
+            .parse::<LocalWallet>()
+            .unwrap()
+            .with_chain_id(1337u64)
+    }
+
+    #[tokio::test]
+    async fn remote_signer_test() {
+        let wallet = wallet();
+        let url = serve(wallet.clone()).await;
+        let signer = RemoteSigner::new(&url, wallet.address(), 1337).unwrap();
+
+        let message = b"hello";
+        assert_eq!(
+            signer.sign_message(message).await.unwrap(),
+            wallet.sign_message(message).await.unwrap()
+        );
+
+        let tx: TypedTransaction = TransactionRequest::new()
+            .from(wallet.address())
+            .to(Address::from_low_u64_be(1))
+            .value(100)
+            .gas(21000)
+            .gas_price(1)
+            .nonce(0)
+            .into();
+        assert_eq!(
+            signer.sign_transaction(&tx).await.unwrap(),
+            wallet.sign_transaction(&tx).await.unwrap()
+        );
+
+        // The signature is read from the end of typed transactions too.
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .from(wallet.address())
+            .to(Address::from_low_u64_be(1))
+            .value(100)
+            .gas(21000)
+            .max_fee_per_gas(2)
+            .max_priority_fee_per_gas(1)
+            .nonce(1)
+            .data(vec![1, 2, 3])
+            .into();
+        assert_eq!(
+            signer.sign_transaction(&tx).await.unwrap(),
+            wallet.sign_transaction(&tx).await.unwrap()
+        );
+
+        // Signatures of some other key are rejected.
+        let signer =
+            RemoteSigner::new(&url, Address::from_low_u64_be(2), 1337).unwrap();
+        assert!(matches!(
+            signer.sign_message(message).await,
+            Err(RemoteSignerError::WrongSigner { .. })
+        ));
+    }
+}
@@ -0,0 +1,484 @@
+use crate::error_class::is_known_message;
+use crate::MiddlewareFactory;
+
+use async_trait::async_trait;
+use offchain_core::ethers::providers::{
+    FeeHistory, FromErr, Middleware, PendingTransaction,
+};
+use offchain_core::ethers::types::{
+    transaction::eip2718::TypedTransaction, Address, Block, BlockId,
+    BlockNumber, Bytes, Filter, Log, NameOrAddress, Transaction,
+    TransactionReceipt, TxHash, H256, U256, U64,
+};
+use offchain_core::ethers::utils::keccak256;
+use snafu::Snafu;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Middleware that runs every call on the latest middleware of a factory.
+/// When a call fails with an error the factory's `should_retry` accepts, the
+/// middleware chain is rebuilt with `new_middleware_if_stale` and the call is
+/// replayed, waiting between attempts with exponential backoff.
+///
+/// Read methods and `send_raw_transaction` are retried, since replaying them
+/// is harmless. `send_transaction` is not, as its inner layers may assign a
+/// new nonce on every attempt; it only rebuilds the chain on failure, so the
+/// next call gets a working middleware.
+///
+/// `inner` can only lend a reference, so it is the middleware this one was
+/// created with, which also backs `provider` and the pending transactions
+/// returned here. Methods not overridden here go through it; use `current`
+/// for the latest middleware.
+pub struct RetryMiddleware<F: MiddlewareFactory> {
+    factory: Arc<F>,
+    initial: F::Middleware,
+    max_retries: usize,
+    max_delay: Duration,
+}
+
+impl<F> RetryMiddleware<F>
+where
+    F: MiddlewareFactory + Send + Sync + 'static,
+    F::Middleware: Clone + 'static,
+{
+    pub async fn new(
+        factory: Arc<F>,
+        max_retries: usize,
+        max_delay: Duration,
+    ) -> crate::Result<Self> {
+        let initial = factory.current().middleware;
+
+        Ok(Self {
+            factory,
+            initial,
+            max_retries,
+            max_delay,
+        })
+    }
+
+    /// Middleware calls are currently made on.
+    pub fn current(&self) -> F::Middleware {
+        self.factory.current().middleware
+    }
+
+    /// Runs `call` on the current middleware, rebuilding it and trying again
+    /// while it fails with retryable errors.
+    pub async fn retry<T, C, Fut>(
+        &self,
+        call: C,
+    ) -> std::result::Result<T, RetryError<F::Middleware>>
+    where
+        C: Fn(F::Middleware) -> Fut + Send + Sync,
+        Fut: Future<
+                Output = std::result::Result<
+                    T,
+                    <F::Middleware as Middleware>::Error,
+                >,
+            > + Send,
+        T: Send,
+    {
+        let mut backoff =
+            backoff::Backoff::new(self.max_retries, self.max_delay);
+
+        loop {
+            let current = self.factory.current();
+
+            let err = match call(current.middleware).await {
+                Ok(res) => return Ok(res),
+                Err(err) => err,
+            };
+
+            if !F::should_retry(&err) || backoff.wait().await.is_err() {
+                return Err(FromErr::from(err));
+            }
+
+            self.rebuild(current.generation).await?;
+        }
+    }
+
+    async fn rebuild(
+        &self,
+        generation: u64,
+    ) -> std::result::Result<(), RetryError<F::Middleware>> {
+        self.factory
+            .new_middleware_if_stale(generation)
+            .await
+            .map_err(|source| RetryError::FactoryError { source })?;
+
+        Ok(())
+    }
+}
+
+impl<F: MiddlewareFactory> fmt::Debug for RetryMiddleware<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryMiddleware")
+            .field("current", &self.factory.current().middleware)
+            .field("max_retries", &self.max_retries)
+            .field("max_delay", &self.max_delay)
+            .finish()
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum RetryError<M: Middleware + 'static> {
+    #[snafu(display("Middleware error: {}", source))]
+    MiddlewareError { source: M::Error },
+
+    #[snafu(display("Factory error: {}", source))]
+    FactoryError { source: crate::Error },
+}
+
+impl<M: Middleware> FromErr<M::Error> for RetryError<M> {
+    fn from(src: M::Error) -> Self {
+        RetryError::MiddlewareError { source: src }
+    }
+}
+
+#[async_trait]
+impl<F> Middleware for RetryMiddleware<F>
+where
+    F: MiddlewareFactory + Send + Sync + 'static,
+    F::Middleware: Clone + 'static,
+{
+    type Error = RetryError<F::Middleware>;
+    type Provider = <F::Middleware as Middleware>::Provider;
+    type Inner = F::Middleware;
+
+    fn inner(&self) -> &F::Middleware {
+        &self.initial
+    }
+
+    async fn client_version(&self) -> std::result::Result<String, Self::Error> {
+        self.retry(|m| async move { m.client_version().await })
+            .await
+    }
+
+    async fn get_block_number(&self) -> std::result::Result<U64, Self::Error> {
+        self.retry(|m| async move { m.get_block_number().await })
+            .await
+    }
+
+    async fn send_transaction<'a, T>(
+        &'a self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        let current = self.factory.current();
+
+        let hash = match current.middleware.send_transaction(tx, block).await {
+            Ok(pending) => *pending,
+            Err(err) => {
+                if F::should_retry(&err) {
+                    self.rebuild(current.generation).await?;
+                }
+                return Err(FromErr::from(err));
+            }
+        };
+
+        Ok(PendingTransaction::new(hash, self.provider()))
+    }
+
+    async fn get_block<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<Option<Block<TxHash>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let block: BlockId = block_hash_or_number.into();
+        self.retry(|m| async move { m.get_block(block).await })
+            .await
+    }
+
+    async fn get_block_with_txs<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> std::result::Result<Option<Block<Transaction>>, Self::Error>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        let block: BlockId = block_hash_or_number.into();
+        self.retry(|m| async move { m.get_block_with_txs(block).await })
+            .await
+    }
+
+    async fn get_transaction_count<T>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<U256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from: NameOrAddress = from.into();
+        self.retry(|m| {
+            let from = from.clone();
+            async move { m.get_transaction_count(from, block).await }
+        })
+        .await
+    }
+
+    async fn estimate_gas(
+        &self,
+        tx: &TypedTransaction,
+    ) -> std::result::Result<U256, Self::Error> {
+        self.retry(|m| {
+            let tx = tx.clone();
+            async move { m.estimate_gas(&tx).await }
+        })
+        .await
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> std::result::Result<Bytes, Self::Error> {
+        self.retry(|m| {
+            let tx = tx.clone();
+            async move { m.call(&tx, block).await }
+        })
+        .await
+    }
+
+    async fn get_chainid(&self) -> std::result::Result<U256, Self::Error> {
+        self.retry(|m| async move { m.get_chainid().await }).await
+    }
+
+    async fn get_balance<T>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<U256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from: NameOrAddress = from.into();
+        self.retry(|m| {
+            let from = from.clone();
+            async move { m.get_balance(from, block).await }
+        })
+        .await
+    }
+
+    async fn get_transaction<T>(
+        &self,
+        transaction_hash: T,
+    ) -> std::result::Result<Option<Transaction>, Self::Error>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        let hash: TxHash = transaction_hash.into();
+        self.retry(|m| async move { m.get_transaction(hash).await })
+            .await
+    }
+
+    async fn get_transaction_receipt<T>(
+        &self,
+        transaction_hash: T,
+    ) -> std::result::Result<Option<TransactionReceipt>, Self::Error>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        let hash: TxHash = transaction_hash.into();
+        self.retry(|m| async move { m.get_transaction_receipt(hash).await })
+            .await
+    }
+
+    async fn get_block_receipts<T>(
+        &self,
+        block: T,
+    ) -> std::result::Result<Vec<TransactionReceipt>, Self::Error>
+    where
+        T: Into<BlockNumber> + Send + Sync,
+    {
+        let block: BlockNumber = block.into();
+        self.retry(|m| async move { m.get_block_receipts(block).await })
+            .await
+    }
+
+    async fn get_gas_price(&self) -> std::result::Result<U256, Self::Error> {
+        self.retry(|m| async move { m.get_gas_price().await }).await
+    }
+
+    async fn get_accounts(
+        &self,
+    ) -> std::result::Result<Vec<Address>, Self::Error> {
+        self.retry(|m| async move { m.get_accounts().await }).await
+    }
+
+    /// A retry of a transaction that already reached the node is rejected as
+    /// known, which counts as sent.
+    async fn send_raw_transaction<'a>(
+        &'a self,
+        tx: Bytes,
+    ) -> std::result::Result<PendingTransaction<'a, Self::Provider>, Self::Error>
+    {
+        let hash = H256::from(keccak256(tx.as_ref()));
+        let sent = self
+            .retry(|m| {
+                let tx = tx.clone();
+                async move {
+                    match m.send_raw_transaction(tx).await {
+                        Ok(_) => Ok(()),
+                        Err(err) if is_known_message(&err.to_string()) => {
+                            Ok(())
+                        }
+                        Err(err) => Err(err),
+                    }
+                }
+            })
+            .await;
+
+        sent.map(|_| PendingTransaction::new(hash, self.provider()))
+    }
+
+    async fn get_logs(
+        &self,
+        filter: &Filter,
+    ) -> std::result::Result<Vec<Log>, Self::Error> {
+        self.retry(|m| {
+            let filter = filter.clone();
+            async move { m.get_logs(&filter).await }
+        })
+        .await
+    }
+
+    async fn get_code<T>(
+        &self,
+        at: T,
+        block: Option<BlockId>,
+    ) -> std::result::Result<Bytes, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let at: NameOrAddress = at.into();
+        self.retry(|m| {
+            let at = at.clone();
+            async move { m.get_code(at, block).await }
+        })
+        .await
+    }
+
+    async fn get_storage_at<T>(
+        &self,
+        from: T,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> std::result::Result<H256, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from: NameOrAddress = from.into();
+        self.retry(|m| {
+            let from = from.clone();
+            async move { m.get_storage_at(from, location, block).await }
+        })
+        .await
+    }
+
+    async fn fee_history<T>(
+        &self,
+        block_count: T,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> std::result::Result<FeeHistory, Self::Error>
+    where
+        T: Into<U256> + serde::Serialize + Send + Sync,
+    {
+        let block_count: U256 = block_count.into();
+        self.retry(|m| {
+            let reward_percentiles = reward_percentiles.to_vec();
+            async move {
+                m.fee_history(block_count, last_block, &reward_percentiles)
+                    .await
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        classify_provider_error, ErrorClass, MiddlewareSlot, RebuildReason,
+        Result,
+    };
+    use offchain_core::ethers::providers::{
+        MockProvider, Provider, ProviderError,
+    };
+
+    type MockMiddleware = Arc<Provider<MockProvider>>;
+
+    /// Root factory handing out the mocks in order, one per rebuild.
+    struct MockFactory {
+        mocks: std::sync::Mutex<Vec<MockMiddleware>>,
+        current: MiddlewareSlot<MockMiddleware>,
+    }
+
+    impl MockFactory {
+        fn new(count: usize) -> (Arc<Self>, Vec<MockProvider>) {
+            let (mut providers, mocks): (Vec<_>, Vec<_>) = (0..count)
+                .map(|_| {
+                    let (provider, mock) = Provider::mocked();
+                    (Arc::new(provider), mock)
+                })
+                .unzip();
+            providers.reverse();
+
+            let current = MiddlewareSlot::new(providers.pop().unwrap());
+            let factory = Arc::new(Self {
+                mocks: std::sync::Mutex::new(providers),
+                current,
+            });
+
+            (factory, mocks)
+        }
+    }
+
+    #[async_trait]
+    impl MiddlewareFactory for MockFactory {
+        type Middleware = MockMiddleware;
+
+        fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+            &self.current
+        }
+
+        fn classify(err: &ProviderError) -> ErrorClass {
+            classify_provider_error(err)
+        }
+
+        async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+            let next = self.mocks.lock().unwrap().pop().unwrap();
+            Ok((next, RebuildReason::Stale))
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_test() {
+        let (factory, mocks) = MockFactory::new(3);
+        let m = RetryMiddleware::new(factory, 1, Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        // Mocks without responses fail the request.
+        mocks[1].push(U64::from(7)).unwrap();
+        assert_eq!(m.get_block_number().await.unwrap(), 7.into());
+        let current = m.current();
+        assert!(!Arc::ptr_eq(&current, m.inner()));
+        assert!(Arc::ptr_eq(&current, &m.current()));
+
+        // Gives up once out of retries.
+        assert!(matches!(
+            m.get_block_number().await,
+            Err(RetryError::MiddlewareError { .. })
+        ));
+        mocks[2].assert_request("eth_blockNumber", ()).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{
+    ErrorClass, LayerFactory, MiddlewareFactory, MiddlewareSlot, RebuildReason,
+    Result,
+};
+
+use async_trait::async_trait;
+use offchain_core::ethers::middleware::{
+    signer::SignerMiddlewareError, SignerMiddleware,
+};
+use offchain_core::ethers::providers::Middleware;
+use offchain_core::ethers::signers::{LocalWallet, Signer};
+use std::sync::Arc;
+
+/// Signer factory holding the key in this process.
+pub type LocalSignerFactory<IF> = SignerFactory<IF, LocalWallet>;
+
+///
+/// Signer Middleware Factory
+///
+/// Signs transactions with any ethers `Signer`, such as a `LocalWallet` or a
+/// `RemoteSigner`.
+pub struct SignerFactory<IF: MiddlewareFactory, S: Signer> {
+    current: MiddlewareSlot<Arc<SignerMiddleware<IF::Middleware, S>>>,
+    inner_factory: Arc<IF>,
+    signer: S,
+}
+
+impl<IF, S> SignerFactory<IF, S>
+where
+    IF: MiddlewareFactory + Sync + Send,
+    S: Signer + Clone,
+{
+    pub async fn new(inner_factory: Arc<IF>, signer: S) -> Result<Arc<Self>> {
+        let inner = inner_factory.current();
+
+        Ok(Arc::new(Self {
+            current: MiddlewareSlot::with_inner(
+                Arc::new(SignerMiddleware::new(
+                    inner.middleware,
+                    signer.clone(),
+                )),
+                inner.generation,
+            ),
+            inner_factory,
+            signer,
+        }))
+    }
+}
+
+#[async_trait]
+impl<IF, S> MiddlewareFactory for SignerFactory<IF, S>
+where
+    IF: MiddlewareFactory + Sync + Send,
+    S: Signer + Clone + 'static,
+{
+    type Middleware = Arc<SignerMiddleware<IF::Middleware, S>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.current
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        match err {
+            SignerMiddlewareError::MiddlewareError(m_err) => {
+                IF::classify(m_err)
+            }
+            // Signing failures and incomplete transactions.
+            _ => ErrorClass::Permanent,
+        }
+    }
+
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        self.rebuild_layer().await
+    }
+}
+
+#[async_trait]
+impl<IF, S> LayerFactory for SignerFactory<IF, S>
+where
+    IF: MiddlewareFactory + Sync + Send,
+    S: Signer + Clone + 'static,
+{
+    type InnerFactory = IF;
+
+    fn inner_factory(&self) -> &Self::InnerFactory {
+        &self.inner_factory
+    }
+
+    fn build(&self, inner_middleware: IF::Middleware) -> Self::Middleware {
+        Arc::new(SignerMiddleware::new(inner_middleware, self.signer.clone()))
+    }
+}
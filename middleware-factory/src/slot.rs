@@ -0,0 +1,226 @@
+use crate::{
+    Generation, MiddlewareFactory, RebuildFailed, RebuildReason, Result,
+    Versioned,
+};
+
+use arc_swap::ArcSwap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Current middleware of a factory, stored along with its generation.
+///
+/// Reading the current middleware never waits. Rebuilds are single-flight:
+/// one task rebuilds while the others asking to replace the same generation
+/// wait for it and share its result, and readers keep getting the old
+/// middleware until it is swapped.
+///
+/// Slots of layers also keep the generation of the inner middleware the
+/// current one was built over, so that rebuilding it only rebuilds the inner
+/// middleware if nobody did since.
+pub struct MiddlewareSlot<M> {
+    current: ArcSwap<Versioned<M>>,
+    inner_generation: AtomicU64,
+    generation: Generation,
+    flight: Mutex<()>,
+    failures: AtomicU64,
+    last_error: std::sync::Mutex<String>,
+}
+
+impl<M: Clone> MiddlewareSlot<M> {
+    pub fn new(middleware: M) -> Self {
+        Self::with_inner(middleware, 0)
+    }
+
+    /// Slot of a layer, whose middleware was built over the inner middleware
+    /// of generation `inner_generation`.
+    pub fn with_inner(middleware: M, inner_generation: u64) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(Versioned {
+                generation: 0,
+                middleware,
+            }),
+            inner_generation: AtomicU64::new(inner_generation),
+            generation: Generation::new(),
+            flight: Mutex::new(()),
+            failures: AtomicU64::new(0),
+            last_error: std::sync::Mutex::new(String::new()),
+        }
+    }
+
+    pub fn load(&self) -> Versioned<M> {
+        Versioned::clone(&self.current.load())
+    }
+
+    pub fn generation(&self) -> &Generation {
+        &self.generation
+    }
+
+    /// Generation of the inner middleware the current one was built over.
+    pub fn inner_generation(&self) -> u64 {
+        self.inner_generation.load(Ordering::SeqCst)
+    }
+
+    /// Rebuilds the inner middleware of the layer of this slot with
+    /// `inner_factory`, unless it was already rebuilt since the current
+    /// middleware was built over it, returning it along with the reason of
+    /// its rebuild. Only called while rebuilding, so at most once at a time.
+    pub(crate) async fn rebuild_inner<IF: MiddlewareFactory + Sync>(
+        &self,
+        inner_factory: &IF,
+    ) -> Result<(IF::Middleware, RebuildReason)> {
+        let inner = inner_factory
+            .new_middleware_if_stale(self.inner_generation())
+            .await?;
+        self.inner_generation
+            .store(inner.generation, Ordering::SeqCst);
+
+        let reason = inner_factory.generation().latest().reason;
+        Ok((inner.middleware, reason))
+    }
+
+    /// Replaces the middleware with the one `build` returns, if `generation`
+    /// is still the current one. If another task is already replacing it,
+    /// waits for that task instead, returning its middleware, or an error if
+    /// it failed.
+    pub async fn rebuild<F, Fut>(
+        &self,
+        generation: u64,
+        build: F,
+    ) -> Result<Versioned<M>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(M, RebuildReason)>>,
+    {
+        let failures = self.failures.load(Ordering::SeqCst);
+        let _flight = self.flight.lock().await;
+
+        let current = self.load();
+        if current.generation != generation {
+            return Ok(current);
+        }
+
+        if self.failures.load(Ordering::SeqCst) != failures {
+            let reason = self.last_error.lock().unwrap().clone();
+            return RebuildFailed { reason }.fail();
+        }
+
+        match build().await {
+            Ok((middleware, reason)) => Ok(self.store(middleware, reason)),
+
+            Err(e) => {
+                *self.last_error.lock().unwrap() = e.to_string();
+                self.failures.fetch_add(1, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+
+    /// Replaces the current middleware with the one `update` returns, if
+    /// any. Skipped while a rebuild is in flight.
+    pub async fn try_update<F, Fut>(&self, update: F)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<(M, RebuildReason)>>,
+    {
+        let _flight = match self.flight.try_lock() {
+            Ok(flight) => flight,
+            Err(_) => return,
+        };
+
+        if let Some((middleware, reason)) = update().await {
+            self.store(middleware, reason);
+        }
+    }
+
+    // Only called during a flight, so generations are stored in order.
+    fn store(&self, middleware: M, reason: RebuildReason) -> Versioned<M> {
+        let new = Versioned {
+            generation: self.current.load().generation + 1,
+            middleware,
+        };
+
+        // Stored before being published, so subscribers see the new one.
+        self.current.store(Arc::new(new.clone()));
+        self.generation.advance(reason);
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NoEndpoints;
+    use futures_util::future::join_all;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn single_flight_test() {
+        let slot = Arc::new(MiddlewareSlot::new(Arc::new(0)));
+        let builds = Arc::new(AtomicUsize::new(0));
+
+        let rebuilds = (0..4).map(|_| {
+            let (slot, builds) = (Arc::clone(&slot), Arc::clone(&builds));
+            tokio::spawn(async move {
+                slot.rebuild(0, || async {
+                    builds.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok((Arc::new(1), RebuildReason::Stale))
+                })
+                .await
+                .unwrap()
+            })
+        });
+        let rebuilds = tokio::spawn(join_all(rebuilds));
+
+        // Readers don't wait for the rebuild.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(*slot.load().middleware, 0);
+
+        let new: Vec<_> = rebuilds
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|new| new.unwrap())
+            .collect();
+        assert_eq!(builds.load(Ordering::SeqCst), 1);
+        assert!(new.iter().all(|rebuilt| rebuilt.generation == 1
+            && Arc::ptr_eq(&rebuilt.middleware, &new[0].middleware)));
+        assert_eq!(slot.generation().current(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_flight_test() {
+        let slot = Arc::new(MiddlewareSlot::new(Arc::new(0)));
+
+        let failing = {
+            let slot = Arc::clone(&slot);
+            tokio::spawn(async move {
+                slot.rebuild(0, || async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    NoEndpoints.fail()
+                })
+                .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Waiting on the failed rebuild doesn't try again.
+        let rebuilt = || async { Ok((Arc::new(1), RebuildReason::Stale)) };
+        let err = slot.rebuild(0, rebuilt).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Rebuild in flight failed: No endpoints given"
+        );
+        assert!(failing.await.unwrap().is_err());
+        assert_eq!(*slot.load().middleware, 0);
+
+        // Later rebuilds do, and stale generations are not rebuilt again.
+        let new = slot.rebuild(0, rebuilt).await.unwrap();
+        assert_eq!((new.generation, *new.middleware), (1, 1));
+        let same = slot.rebuild(0, rebuilt).await.unwrap();
+        assert!(Arc::ptr_eq(&new.middleware, &same.middleware));
+    }
+}
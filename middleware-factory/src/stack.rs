@@ -0,0 +1,1408 @@
+use crate::caching::{CacheConfig, CachingFactory};
+use crate::gas_escalator::{EscalatorConfig, GasEscalatorFactory};
+use crate::gas_oracle::{GasOracle, GasOracleFactory};
+use crate::metrics::{MetricsFactory, MetricsSink};
+use crate::rate_limit::{RateLimitFactory, RateLimiter};
+use crate::transport::{DynTransport, TransportOptions};
+use crate::{
+    classify_provider_error, ErrorClass, FailoverProviderFactory,
+    MiddlewareFactory, MiddlewareSlot, NoPubsubEndpoints, NonceManagerFactory,
+    RebuildReason, Result, SignerFactory,
+};
+
+use async_trait::async_trait;
+use configuration::Config;
+use futures_util::future::BoxFuture;
+use offchain_core::ethers::providers::{
+    self, FeeHistory, FilterKind, FromErr, JsonRpcClient, Middleware,
+    PendingTransaction, Provider,
+};
+use offchain_core::ethers::signers::{LocalWallet, Signer};
+use offchain_core::ethers::types::{
+    transaction::{eip2718::TypedTransaction, eip2930::AccessListWithGasUsed},
+    Address, Block, BlockId, BlockNumber, BlockTrace, Bytes,
+    EIP1186ProofResponse, Filter, Log, NameOrAddress, Signature, Trace,
+    TraceFilter, TraceType, Transaction, TransactionReceipt, TxHash,
+    TxpoolContent, TxpoolInspect, TxpoolStatus, H256, U256, U64,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use snafu::{ensure, Snafu};
+use std::any::Any;
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_MAX_RETRIES: usize = 5;
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_RETRY_PREFERRED_AFTER: Duration = Duration::from_secs(60);
+
+type DynResult<T> = std::result::Result<T, DynMiddlewareError>;
+type Eip1559Estimator = fn(U256, Vec<Vec<U256>>) -> (U256, U256);
+
+#[derive(Debug, Snafu)]
+pub enum DynMiddlewareError {
+    /// Error of the erased stack, along with its class according to the
+    /// factory of the stack.
+    #[snafu(display("{}", source))]
+    StackError {
+        source: Box<dyn std::error::Error + Send + Sync>,
+        class: ErrorClass,
+    },
+
+    #[snafu(display("Provider error: {}", source))]
+    ProviderError { source: providers::ProviderError },
+}
+
+impl FromErr<providers::ProviderError> for DynMiddlewareError {
+    fn from(src: providers::ProviderError) -> Self {
+        DynMiddlewareError::ProviderError { source: src }
+    }
+}
+
+/// Object safe counterpart of `Middleware`, so stacks of different types over the
+/// same transport can sit behind the same `DynMiddleware`.
+#[async_trait]
+trait ErasedMiddleware<P: JsonRpcClient>: Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn provider(&self) -> &Provider<P>;
+    fn default_sender(&self) -> Option<Address>;
+
+    async fn get_block_number(&self) -> DynResult<U64>;
+    async fn get_block(
+        &self,
+        block: BlockId,
+    ) -> DynResult<Option<Block<TxHash>>>;
+    async fn get_block_with_txs(
+        &self,
+        block: BlockId,
+    ) -> DynResult<Option<Block<Transaction>>>;
+    async fn get_transaction_count(
+        &self,
+        from: NameOrAddress,
+        block: Option<BlockId>,
+    ) -> DynResult<U256>;
+    async fn get_balance(
+        &self,
+        from: NameOrAddress,
+        block: Option<BlockId>,
+    ) -> DynResult<U256>;
+    async fn get_chainid(&self) -> DynResult<U256>;
+    async fn get_transaction(
+        &self,
+        hash: TxHash,
+    ) -> DynResult<Option<Transaction>>;
+    async fn get_transaction_receipt(
+        &self,
+        hash: TxHash,
+    ) -> DynResult<Option<TransactionReceipt>>;
+    async fn get_logs(&self, filter: &Filter) -> DynResult<Vec<Log>>;
+    async fn get_code(
+        &self,
+        at: NameOrAddress,
+        block: Option<BlockId>,
+    ) -> DynResult<Bytes>;
+    async fn get_storage_at(
+        &self,
+        from: NameOrAddress,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> DynResult<H256>;
+    async fn get_gas_price(&self) -> DynResult<U256>;
+    async fn fee_history(
+        &self,
+        block_count: U256,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> DynResult<FeeHistory>;
+    async fn estimate_gas(&self, tx: &TypedTransaction) -> DynResult<U256>;
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<Bytes>;
+    async fn fill_transaction(
+        &self,
+        tx: &mut TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<()>;
+    async fn send_transaction(
+        &self,
+        tx: TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<TxHash>;
+    async fn send_raw_transaction(&self, tx: Bytes) -> DynResult<TxHash>;
+    async fn is_signer(&self) -> bool;
+    async fn sign(&self, data: Bytes, from: &Address) -> DynResult<Signature>;
+    async fn client_version(&self) -> DynResult<String>;
+    async fn resolve_name(&self, ens_name: &str) -> DynResult<Address>;
+    async fn lookup_address(&self, address: Address) -> DynResult<String>;
+    async fn get_uncle_count(&self, block: BlockId) -> DynResult<U256>;
+    async fn get_uncle(
+        &self,
+        block: BlockId,
+        idx: U64,
+    ) -> DynResult<Option<Block<H256>>>;
+    async fn get_block_receipts(
+        &self,
+        block: BlockNumber,
+    ) -> DynResult<Vec<TransactionReceipt>>;
+    async fn estimate_eip1559_fees(
+        &self,
+        estimator: Option<Eip1559Estimator>,
+    ) -> DynResult<(U256, U256)>;
+    async fn get_accounts(&self) -> DynResult<Vec<Address>>;
+    async fn new_filter(&self, filter: FilterKind<'_>) -> DynResult<U256>;
+    async fn uninstall_filter(&self, id: U256) -> DynResult<bool>;
+    async fn get_proof(
+        &self,
+        from: NameOrAddress,
+        locations: Vec<H256>,
+        block: Option<BlockId>,
+    ) -> DynResult<EIP1186ProofResponse>;
+    async fn txpool_content(&self) -> DynResult<TxpoolContent>;
+    async fn txpool_inspect(&self) -> DynResult<TxpoolInspect>;
+    async fn txpool_status(&self) -> DynResult<TxpoolStatus>;
+    async fn trace_call(
+        &self,
+        req: TypedTransaction,
+        trace_type: Vec<TraceType>,
+        block: Option<BlockNumber>,
+    ) -> DynResult<BlockTrace>;
+    async fn trace_raw_transaction(
+        &self,
+        data: Bytes,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<BlockTrace>;
+    async fn trace_replay_transaction(
+        &self,
+        hash: H256,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<BlockTrace>;
+    async fn trace_replay_block_transactions(
+        &self,
+        block: BlockNumber,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<Vec<BlockTrace>>;
+    async fn trace_block(&self, block: BlockNumber) -> DynResult<Vec<Trace>>;
+    async fn trace_filter(&self, filter: TraceFilter) -> DynResult<Vec<Trace>>;
+    async fn trace_get(&self, hash: H256, index: Vec<U64>) -> DynResult<Trace>;
+    async fn trace_transaction(&self, hash: H256) -> DynResult<Vec<Trace>>;
+    async fn parity_block_receipts(
+        &self,
+        block: BlockNumber,
+    ) -> DynResult<Vec<TransactionReceipt>>;
+    async fn create_access_list(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<AccessListWithGasUsed>;
+    async fn get_filter_changes(&self, id: U256) -> DynResult<Vec<Value>>;
+}
+
+/// A concrete stack, with the error classification of the factory that built
+/// it.
+struct Erased<M: Middleware> {
+    middleware: M,
+    classify: fn(&M::Error) -> ErrorClass,
+}
+
+impl<M: Middleware + 'static> Erased<M> {
+    fn erase(&self, err: M::Error) -> DynMiddlewareError {
+        DynMiddlewareError::StackError {
+            class: (self.classify)(&err),
+            source: Box::new(err),
+        }
+    }
+}
+
+impl<M: Middleware> Debug for Erased<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.middleware.fmt(f)
+    }
+}
+
+#[async_trait]
+impl<M> ErasedMiddleware<M::Provider> for Erased<M>
+where
+    M: Middleware + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn provider(&self) -> &Provider<M::Provider> {
+        self.middleware.provider()
+    }
+
+    fn default_sender(&self) -> Option<Address> {
+        self.middleware.default_sender()
+    }
+
+    async fn get_block_number(&self) -> DynResult<U64> {
+        let res = self.middleware.get_block_number().await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_block(
+        &self,
+        block: BlockId,
+    ) -> DynResult<Option<Block<TxHash>>> {
+        let res = self.middleware.get_block(block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_block_with_txs(
+        &self,
+        block: BlockId,
+    ) -> DynResult<Option<Block<Transaction>>> {
+        let res = self.middleware.get_block_with_txs(block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_transaction_count(
+        &self,
+        from: NameOrAddress,
+        block: Option<BlockId>,
+    ) -> DynResult<U256> {
+        let res = self.middleware.get_transaction_count(from, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_balance(
+        &self,
+        from: NameOrAddress,
+        block: Option<BlockId>,
+    ) -> DynResult<U256> {
+        let res = self.middleware.get_balance(from, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_chainid(&self) -> DynResult<U256> {
+        let res = self.middleware.get_chainid().await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_transaction(
+        &self,
+        hash: TxHash,
+    ) -> DynResult<Option<Transaction>> {
+        let res = self.middleware.get_transaction(hash).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_transaction_receipt(
+        &self,
+        hash: TxHash,
+    ) -> DynResult<Option<TransactionReceipt>> {
+        let res = self.middleware.get_transaction_receipt(hash).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> DynResult<Vec<Log>> {
+        let res = self.middleware.get_logs(filter).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_code(
+        &self,
+        at: NameOrAddress,
+        block: Option<BlockId>,
+    ) -> DynResult<Bytes> {
+        let res = self.middleware.get_code(at, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_storage_at(
+        &self,
+        from: NameOrAddress,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> DynResult<H256> {
+        let res = self.middleware.get_storage_at(from, location, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_gas_price(&self) -> DynResult<U256> {
+        let res = self.middleware.get_gas_price().await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn fee_history(
+        &self,
+        block_count: U256,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> DynResult<FeeHistory> {
+        let res = self
+            .middleware
+            .fee_history(block_count, last_block, reward_percentiles)
+            .await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn estimate_gas(&self, tx: &TypedTransaction) -> DynResult<U256> {
+        let res = self.middleware.estimate_gas(tx).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<Bytes> {
+        let res = self.middleware.call(tx, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn fill_transaction(
+        &self,
+        tx: &mut TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<()> {
+        let res = self.middleware.fill_transaction(tx, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn send_transaction(
+        &self,
+        tx: TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<TxHash> {
+        match self.middleware.send_transaction(tx, block).await {
+            Ok(pending) => Ok(*pending),
+            Err(e) => Err(self.erase(e)),
+        }
+    }
+
+    async fn send_raw_transaction(&self, tx: Bytes) -> DynResult<TxHash> {
+        match self.middleware.send_raw_transaction(tx).await {
+            Ok(pending) => Ok(*pending),
+            Err(e) => Err(self.erase(e)),
+        }
+    }
+
+    async fn is_signer(&self) -> bool {
+        self.middleware.is_signer().await
+    }
+
+    async fn sign(&self, data: Bytes, from: &Address) -> DynResult<Signature> {
+        let res = self.middleware.sign(data, from).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn client_version(&self) -> DynResult<String> {
+        let res = self.middleware.client_version().await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn resolve_name(&self, ens_name: &str) -> DynResult<Address> {
+        let res = self.middleware.resolve_name(ens_name).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn lookup_address(&self, address: Address) -> DynResult<String> {
+        let res = self.middleware.lookup_address(address).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_uncle_count(&self, block: BlockId) -> DynResult<U256> {
+        let res = self.middleware.get_uncle_count(block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_uncle(
+        &self,
+        block: BlockId,
+        idx: U64,
+    ) -> DynResult<Option<Block<H256>>> {
+        let res = self.middleware.get_uncle(block, idx).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_block_receipts(
+        &self,
+        block: BlockNumber,
+    ) -> DynResult<Vec<TransactionReceipt>> {
+        let res = self.middleware.get_block_receipts(block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn estimate_eip1559_fees(
+        &self,
+        estimator: Option<Eip1559Estimator>,
+    ) -> DynResult<(U256, U256)> {
+        let res = self.middleware.estimate_eip1559_fees(estimator).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_accounts(&self) -> DynResult<Vec<Address>> {
+        let res = self.middleware.get_accounts().await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn new_filter(&self, filter: FilterKind<'_>) -> DynResult<U256> {
+        let res = self.middleware.new_filter(filter).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn uninstall_filter(&self, id: U256) -> DynResult<bool> {
+        let res = self.middleware.uninstall_filter(id).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_proof(
+        &self,
+        from: NameOrAddress,
+        locations: Vec<H256>,
+        block: Option<BlockId>,
+    ) -> DynResult<EIP1186ProofResponse> {
+        let res = self.middleware.get_proof(from, locations, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn txpool_content(&self) -> DynResult<TxpoolContent> {
+        let res = self.middleware.txpool_content().await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn txpool_inspect(&self) -> DynResult<TxpoolInspect> {
+        let res = self.middleware.txpool_inspect().await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn txpool_status(&self) -> DynResult<TxpoolStatus> {
+        let res = self.middleware.txpool_status().await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn trace_call(
+        &self,
+        req: TypedTransaction,
+        trace_type: Vec<TraceType>,
+        block: Option<BlockNumber>,
+    ) -> DynResult<BlockTrace> {
+        let res = self.middleware.trace_call(req, trace_type, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn trace_raw_transaction(
+        &self,
+        data: Bytes,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<BlockTrace> {
+        let res = self
+            .middleware
+            .trace_raw_transaction(data, trace_type)
+            .await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn trace_replay_transaction(
+        &self,
+        hash: H256,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<BlockTrace> {
+        let res = self
+            .middleware
+            .trace_replay_transaction(hash, trace_type)
+            .await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn trace_replay_block_transactions(
+        &self,
+        block: BlockNumber,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<Vec<BlockTrace>> {
+        let res = self
+            .middleware
+            .trace_replay_block_transactions(block, trace_type)
+            .await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn trace_block(&self, block: BlockNumber) -> DynResult<Vec<Trace>> {
+        let res = self.middleware.trace_block(block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn trace_filter(&self, filter: TraceFilter) -> DynResult<Vec<Trace>> {
+        let res = self.middleware.trace_filter(filter).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn trace_get(&self, hash: H256, index: Vec<U64>) -> DynResult<Trace> {
+        let res = self.middleware.trace_get(hash, index).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn trace_transaction(&self, hash: H256) -> DynResult<Vec<Trace>> {
+        let res = self.middleware.trace_transaction(hash).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn parity_block_receipts(
+        &self,
+        block: BlockNumber,
+    ) -> DynResult<Vec<TransactionReceipt>> {
+        let res = self.middleware.parity_block_receipts(block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn create_access_list(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<AccessListWithGasUsed> {
+        let res = self.middleware.create_access_list(tx, block).await;
+        res.map_err(|e| self.erase(e))
+    }
+
+    async fn get_filter_changes(&self, id: U256) -> DynResult<Vec<Value>> {
+        let res = self.middleware.get_filter_changes(id).await;
+        res.map_err(|e| self.erase(e))
+    }
+}
+
+/// Middleware of a `FactoryStack`, of the same type whatever layers the
+/// stack has, for a given transport. Every request goes through the whole
+/// stack, while watchers and subscriptions, whose streams poll the provider,
+/// go straight to the provider at its root.
+#[derive(Debug)]
+pub struct DynMiddleware<P: JsonRpcClient = DynTransport> {
+    stack: Box<dyn ErasedMiddleware<P>>,
+}
+
+impl<P: JsonRpcClient + 'static> DynMiddleware<P> {
+    fn new<M>(middleware: M, classify: fn(&M::Error) -> ErrorClass) -> Self
+    where
+        M: Middleware<Provider = P> + 'static,
+    {
+        Self {
+            stack: Box::new(Erased {
+                middleware,
+                classify,
+            }),
+        }
+    }
+
+    /// Middleware this one erases, if it is an `M`.
+    pub fn downcast<M: Middleware + 'static>(&self) -> Option<&M> {
+        self.stack
+            .as_any()
+            .downcast_ref::<Erased<M>>()
+            .map(|erased| &erased.middleware)
+    }
+}
+
+#[async_trait]
+impl<P: JsonRpcClient + 'static> Middleware for DynMiddleware<P> {
+    type Error = DynMiddlewareError;
+    type Provider = P;
+    type Inner = Provider<P>;
+
+    fn inner(&self) -> &Provider<P> {
+        self.stack.provider()
+    }
+
+    fn default_sender(&self) -> Option<Address> {
+        self.stack.default_sender()
+    }
+
+    async fn get_block_number(&self) -> DynResult<U64> {
+        self.stack.get_block_number().await
+    }
+
+    async fn get_block<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> DynResult<Option<Block<TxHash>>>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        self.stack.get_block(block_hash_or_number.into()).await
+    }
+
+    async fn get_block_with_txs<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> DynResult<Option<Block<Transaction>>>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        self.stack
+            .get_block_with_txs(block_hash_or_number.into())
+            .await
+    }
+
+    async fn get_transaction_count<T>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> DynResult<U256>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        self.stack.get_transaction_count(from.into(), block).await
+    }
+
+    async fn get_balance<T>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> DynResult<U256>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        self.stack.get_balance(from.into(), block).await
+    }
+
+    async fn get_chainid(&self) -> DynResult<U256> {
+        self.stack.get_chainid().await
+    }
+
+    async fn get_transaction<T>(
+        &self,
+        transaction_hash: T,
+    ) -> DynResult<Option<Transaction>>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        self.stack.get_transaction(transaction_hash.into()).await
+    }
+
+    async fn get_transaction_receipt<T>(
+        &self,
+        transaction_hash: T,
+    ) -> DynResult<Option<TransactionReceipt>>
+    where
+        T: Send + Sync + Into<TxHash>,
+    {
+        self.stack
+            .get_transaction_receipt(transaction_hash.into())
+            .await
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> DynResult<Vec<Log>> {
+        self.stack.get_logs(filter).await
+    }
+
+    async fn get_code<T>(
+        &self,
+        at: T,
+        block: Option<BlockId>,
+    ) -> DynResult<Bytes>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        self.stack.get_code(at.into(), block).await
+    }
+
+    async fn get_storage_at<T>(
+        &self,
+        from: T,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> DynResult<H256>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        self.stack
+            .get_storage_at(from.into(), location, block)
+            .await
+    }
+
+    async fn get_gas_price(&self) -> DynResult<U256> {
+        self.stack.get_gas_price().await
+    }
+
+    async fn fee_history<T>(
+        &self,
+        block_count: T,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> DynResult<FeeHistory>
+    where
+        T: Into<U256> + serde::Serialize + Send + Sync,
+    {
+        self.stack
+            .fee_history(block_count.into(), last_block, reward_percentiles)
+            .await
+    }
+
+    async fn estimate_gas(&self, tx: &TypedTransaction) -> DynResult<U256> {
+        self.stack.estimate_gas(tx).await
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<Bytes> {
+        self.stack.call(tx, block).await
+    }
+
+    async fn fill_transaction(
+        &self,
+        tx: &mut TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<()> {
+        self.stack.fill_transaction(tx, block).await
+    }
+
+    async fn send_transaction<'a, T>(
+        &'a self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> DynResult<PendingTransaction<'a, P>>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        let hash = self.stack.send_transaction(tx.into(), block).await?;
+        Ok(PendingTransaction::new(hash, self.provider()))
+    }
+
+    async fn send_raw_transaction<'a>(
+        &'a self,
+        tx: Bytes,
+    ) -> DynResult<PendingTransaction<'a, P>> {
+        let hash = self.stack.send_raw_transaction(tx).await?;
+        Ok(PendingTransaction::new(hash, self.provider()))
+    }
+
+    async fn is_signer(&self) -> bool {
+        self.stack.is_signer().await
+    }
+
+    async fn sign<T>(&self, data: T, from: &Address) -> DynResult<Signature>
+    where
+        T: Into<Bytes> + Send + Sync,
+    {
+        self.stack.sign(data.into(), from).await
+    }
+
+    async fn client_version(&self) -> DynResult<String> {
+        self.stack.client_version().await
+    }
+
+    async fn resolve_name(&self, ens_name: &str) -> DynResult<Address> {
+        self.stack.resolve_name(ens_name).await
+    }
+
+    async fn lookup_address(&self, address: Address) -> DynResult<String> {
+        self.stack.lookup_address(address).await
+    }
+
+    async fn get_uncle_count<T>(
+        &self,
+        block_hash_or_number: T,
+    ) -> DynResult<U256>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        self.stack
+            .get_uncle_count(block_hash_or_number.into())
+            .await
+    }
+
+    async fn get_uncle<T>(
+        &self,
+        block_hash_or_number: T,
+        idx: U64,
+    ) -> DynResult<Option<Block<H256>>>
+    where
+        T: Into<BlockId> + Send + Sync,
+    {
+        self.stack.get_uncle(block_hash_or_number.into(), idx).await
+    }
+
+    async fn get_block_receipts<T>(
+        &self,
+        block: T,
+    ) -> DynResult<Vec<TransactionReceipt>>
+    where
+        T: Into<BlockNumber> + Send + Sync,
+    {
+        self.stack.get_block_receipts(block.into()).await
+    }
+
+    async fn estimate_eip1559_fees(
+        &self,
+        estimator: Option<Eip1559Estimator>,
+    ) -> DynResult<(U256, U256)> {
+        self.stack.estimate_eip1559_fees(estimator).await
+    }
+
+    async fn get_accounts(&self) -> DynResult<Vec<Address>> {
+        self.stack.get_accounts().await
+    }
+
+    async fn new_filter(&self, filter: FilterKind<'_>) -> DynResult<U256> {
+        self.stack.new_filter(filter).await
+    }
+
+    async fn uninstall_filter<T>(&self, id: T) -> DynResult<bool>
+    where
+        T: Into<U256> + Send + Sync,
+    {
+        self.stack.uninstall_filter(id.into()).await
+    }
+
+    async fn get_proof<T>(
+        &self,
+        from: T,
+        locations: Vec<H256>,
+        block: Option<BlockId>,
+    ) -> DynResult<EIP1186ProofResponse>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        self.stack.get_proof(from.into(), locations, block).await
+    }
+
+    async fn txpool_content(&self) -> DynResult<TxpoolContent> {
+        self.stack.txpool_content().await
+    }
+
+    async fn txpool_inspect(&self) -> DynResult<TxpoolInspect> {
+        self.stack.txpool_inspect().await
+    }
+
+    async fn txpool_status(&self) -> DynResult<TxpoolStatus> {
+        self.stack.txpool_status().await
+    }
+
+    async fn trace_call<T>(
+        &self,
+        req: T,
+        trace_type: Vec<TraceType>,
+        block: Option<BlockNumber>,
+    ) -> DynResult<BlockTrace>
+    where
+        T: Into<TypedTransaction> + Send + Sync,
+    {
+        self.stack.trace_call(req.into(), trace_type, block).await
+    }
+
+    async fn trace_raw_transaction(
+        &self,
+        data: Bytes,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<BlockTrace> {
+        self.stack.trace_raw_transaction(data, trace_type).await
+    }
+
+    async fn trace_replay_transaction(
+        &self,
+        hash: H256,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<BlockTrace> {
+        self.stack.trace_replay_transaction(hash, trace_type).await
+    }
+
+    async fn trace_replay_block_transactions(
+        &self,
+        block: BlockNumber,
+        trace_type: Vec<TraceType>,
+    ) -> DynResult<Vec<BlockTrace>> {
+        self.stack
+            .trace_replay_block_transactions(block, trace_type)
+            .await
+    }
+
+    async fn trace_block(&self, block: BlockNumber) -> DynResult<Vec<Trace>> {
+        self.stack.trace_block(block).await
+    }
+
+    async fn trace_filter(&self, filter: TraceFilter) -> DynResult<Vec<Trace>> {
+        self.stack.trace_filter(filter).await
+    }
+
+    async fn trace_get<T>(&self, hash: H256, index: Vec<T>) -> DynResult<Trace>
+    where
+        T: Into<U64> + Send + Sync,
+    {
+        self.stack
+            .trace_get(hash, index.into_iter().map(Into::into).collect())
+            .await
+    }
+
+    async fn trace_transaction(&self, hash: H256) -> DynResult<Vec<Trace>> {
+        self.stack.trace_transaction(hash).await
+    }
+
+    async fn parity_block_receipts<T>(
+        &self,
+        block: T,
+    ) -> DynResult<Vec<TransactionReceipt>>
+    where
+        T: Into<BlockNumber> + Send + Sync,
+    {
+        self.stack.parity_block_receipts(block.into()).await
+    }
+
+    async fn create_access_list(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> DynResult<AccessListWithGasUsed> {
+        self.stack.create_access_list(tx, block).await
+    }
+
+    /// Changes are read as JSON through the stack, then parsed as `R`.
+    async fn get_filter_changes<T, R>(&self, id: T) -> DynResult<Vec<R>>
+    where
+        T: Into<U256> + Send + Sync,
+        R: Serialize + DeserializeOwned + Send + Sync + Debug,
+    {
+        let changes = self.stack.get_filter_changes(id.into()).await?;
+        changes
+            .into_iter()
+            .map(|change| {
+                serde_json::from_value(change).map_err(|e| {
+                    FromErr::from(providers::ProviderError::SerdeJson(e))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Object safe part of a chain of factories, rebuilding its middleware.
+#[async_trait]
+trait ErasedFactory<P: JsonRpcClient>: Send + Sync {
+    async fn rebuild(
+        &self,
+        slot: &MiddlewareSlot<Arc<DynMiddleware<P>>>,
+    ) -> Result<(Arc<DynMiddleware<P>>, RebuildReason)>;
+}
+
+type ProviderOf<F> =
+    <<F as MiddlewareFactory>::Middleware as Middleware>::Provider;
+
+struct ErasedFactoryImpl<F: MiddlewareFactory> {
+    factory: Arc<F>,
+}
+
+#[async_trait]
+impl<F> ErasedFactory<ProviderOf<F>> for ErasedFactoryImpl<F>
+where
+    F: MiddlewareFactory + Send + Sync + 'static,
+    F::Middleware: 'static,
+{
+    /// Rebuilds the middleware of the chain over `slot`, as layers do.
+    async fn rebuild(
+        &self,
+        slot: &MiddlewareSlot<Arc<DynMiddleware<ProviderOf<F>>>>,
+    ) -> Result<(Arc<DynMiddleware<ProviderOf<F>>>, RebuildReason)> {
+        let (inner, reason) = slot.rebuild_inner(self.factory.as_ref()).await?;
+        Ok((Arc::new(DynMiddleware::new(inner, F::classify)), reason))
+    }
+}
+
+type Layer = Box<
+    dyn FnOnce(
+            Arc<FactoryStack>,
+        ) -> BoxFuture<'static, Result<Arc<FactoryStack>>>
+        + Send,
+>;
+
+///
+/// Type erased Middleware Factory
+///
+/// Wraps a chain of factories behind a single type, whatever layers it has,
+/// so it can be held without naming them. Stacks over other transports than
+/// `DynTransport`, such as one over a `WsProviderFactory`, are erased into a
+/// `FactoryStack` of that transport. Its middleware works with
+/// `BlockSubscriber`, as long as the transport supports subscriptions, and
+/// with contract bindings.
+pub struct FactoryStack<P: JsonRpcClient = DynTransport> {
+    current: MiddlewareSlot<Arc<DynMiddleware<P>>>,
+    factory: Box<dyn ErasedFactory<P>>,
+}
+
+impl FactoryStack {
+    /// Builds the stack `config` describes, with the default options.
+    pub async fn from_config(config: &Config) -> Result<Arc<Self>> {
+        Self::builder(config).build().await
+    }
+
+    pub fn builder(config: &Config) -> FactoryStackBuilder {
+        FactoryStackBuilder::new(config)
+    }
+}
+
+impl<P: JsonRpcClient + 'static> FactoryStack<P> {
+    /// Erases the type of a chain of factories over transport `P`.
+    pub fn erase<F>(factory: Arc<F>) -> Arc<Self>
+    where
+        F: MiddlewareFactory + Send + Sync + 'static,
+        F::Middleware: Middleware<Provider = P> + 'static,
+    {
+        let inner = factory.current();
+        let current = DynMiddleware::new(inner.middleware, F::classify);
+
+        Arc::new(Self {
+            current: MiddlewareSlot::with_inner(
+                Arc::new(current),
+                inner.generation,
+            ),
+            factory: Box::new(ErasedFactoryImpl { factory }),
+        })
+    }
+}
+
+#[async_trait]
+impl<P: JsonRpcClient + 'static> MiddlewareFactory for FactoryStack<P> {
+    type Middleware = Arc<DynMiddleware<P>>;
+
+    fn slot(&self) -> &MiddlewareSlot<Self::Middleware> {
+        &self.current
+    }
+
+    fn classify(err: &<Self::Middleware as Middleware>::Error) -> ErrorClass {
+        match err {
+            DynMiddlewareError::StackError { class, .. } => *class,
+            DynMiddlewareError::ProviderError { source } => {
+                classify_provider_error(source)
+            }
+        }
+    }
+
+    /// Rebuilds along the erased chain of factories.
+    async fn rebuild(&self) -> Result<(Self::Middleware, RebuildReason)> {
+        self.factory.rebuild(&self.current).await
+    }
+}
+
+/// Builds a `FactoryStack` from a `Config`, layering, from the root up:
+///
+/// - a provider over the IPC socket and websocket endpoints configured,
+///   failing over in that order, or over the HTTP endpoint if there are
+///   none;
+/// - metrics of the requests made to it, when enabled;
+/// - a rate limit on the requests made to it, when enabled;
+/// - a signer, when a wallet is configured;
+/// - a gas escalator, a gas oracle, a nonce manager and a cache, when
+///   enabled;
+/// - any extra layers, in the order they were added.
+pub struct FactoryStackBuilder {
+    pubsub_endpoints: Vec<String>,
+    http_endpoint: String,
+    subscriptions: bool,
+    options: TransportOptions,
+    expected_chain_id: Option<u64>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    rate_limit: Option<RateLimiter>,
+    wallet: Option<LocalWallet>,
+    gas_escalator: Option<EscalatorConfig>,
+    gas_oracle: Option<Layer>,
+    nonce_manager: bool,
+    cache: Option<CacheConfig>,
+    layers: Vec<Layer>,
+    max_retries: usize,
+    max_delay: Duration,
+}
+
+impl FactoryStackBuilder {
+    fn new(config: &Config) -> Self {
+        let pubsub_endpoints = config
+            .ipc_path
+            .iter()
+            .chain(&config.ws_url)
+            .cloned()
+            .collect();
+
+        Self {
+            pubsub_endpoints,
+            http_endpoint: config.url.clone(),
+            subscriptions: false,
+            options: TransportOptions {
+                http: config.http.clone(),
+                ws: config.ws.clone(),
+            },
+            expected_chain_id: config.expected_chain_id,
+            metrics: None,
+            rate_limit: None,
+            wallet: config.wallet.clone(),
+            gas_escalator: None,
+            gas_oracle: None,
+            nonce_manager: false,
+            cache: None,
+            layers: Vec::new(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+
+    /// Retry policy when connecting to the endpoints.
+    pub fn retries(mut self, max_retries: usize, max_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Records the requests that reach the provider and the rebuilds of the
+    /// stack.
+    pub fn metrics(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = Some(sink);
+        self
+    }
+
+    /// Requires an IPC socket or websocket endpoint, so the stack supports
+    /// subscriptions, such as those of `BlockSubscriber`.
+    pub fn subscriptions(mut self) -> Self {
+        self.subscriptions = true;
+        self
+    }
+
+    /// Limits the requests that reach the provider.
+    pub fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(limiter);
+        self
+    }
+
+    /// Assigns nonces locally. Only added along with the signer.
+    pub fn nonce_manager(mut self) -> Self {
+        self.nonce_manager = true;
+        self
+    }
+
+    pub fn gas_oracle<O: GasOracle + 'static>(mut self, oracle: O) -> Self {
+        self.gas_oracle = Some(Box::new(|stack| {
+            Box::pin(async move {
+                let factory = GasOracleFactory::new(stack, oracle).await?;
+                Ok(FactoryStack::erase(factory))
+            })
+        }));
+        self
+    }
+
+    pub fn gas_escalator(mut self, config: EscalatorConfig) -> Self {
+        self.gas_escalator = Some(config);
+        self
+    }
+
+    /// Caches immutable responses, above the other built-in layers.
+    pub fn cache(mut self, config: CacheConfig) -> Self {
+        self.cache = Some(config);
+        self
+    }
+
+    /// Adds a layer on top of the others.
+    pub fn layer<L, Fut>(mut self, layer: L) -> Self
+    where
+        L: FnOnce(Arc<FactoryStack>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Arc<FactoryStack>>> + Send + 'static,
+    {
+        self.layers.push(Box::new(|stack| Box::pin(layer(stack))));
+        self
+    }
+
+    pub async fn build(self) -> Result<Arc<FactoryStack>> {
+        // Subscriptions would fail after a failover to HTTP, so endpoints
+        // supporting them only fail over among themselves.
+        let endpoints = if self.pubsub_endpoints.is_empty() {
+            ensure!(!self.subscriptions, NoPubsubEndpoints);
+            vec![self.http_endpoint]
+        } else {
+            self.pubsub_endpoints
+        };
+
+        let root = FailoverProviderFactory::<DynTransport>::new_with_options(
+            endpoints,
+            self.options,
+            self.expected_chain_id,
+            DEFAULT_RETRY_PREFERRED_AFTER,
+            self.max_retries,
+            self.max_delay,
+        )
+        .await?;
+        let mut stack = FactoryStack::erase(root);
+
+        if let Some(sink) = self.metrics {
+            let factory = MetricsFactory::new(stack, sink).await?;
+            stack = FactoryStack::erase(factory);
+        }
+
+        if let Some(limiter) = self.rate_limit {
+            let factory = RateLimitFactory::new(stack, limiter).await?;
+            stack = FactoryStack::erase(factory);
+        }
+
+        let address = self.wallet.as_ref().map(|wallet| wallet.address());
+        if let Some(wallet) = self.wallet {
+            let factory = SignerFactory::new(stack, wallet).await?;
+            stack = FactoryStack::erase(factory);
+        }
+
+        if let Some(config) = self.gas_escalator {
+            let factory = GasEscalatorFactory::new(stack, config).await?;
+            stack = FactoryStack::erase(factory);
+        }
+
+        if let Some(gas_oracle) = self.gas_oracle {
+            stack = gas_oracle(stack).await?;
+        }
+
+        if let (true, Some(address)) = (self.nonce_manager, address) {
+            let factory = NonceManagerFactory::new(stack, address).await?;
+            stack = FactoryStack::erase(factory);
+        }
+
+        if let Some(config) = self.cache {
+            let factory = CachingFactory::new(stack, config).await?;
+            stack = FactoryStack::erase(factory);
+        }
+
+        for layer in self.layers {
+            stack = layer(stack).await?;
+        }
+
+        Ok(stack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::RateLimitMiddleware;
+    use crate::{
+        Error, HttpProviderFactory, LocalSignerFactory, PrometheusSink,
+    };
+    use offchain_core::ethers::providers::Http;
+
+    fn config(wallet: Option<LocalWallet>) -> Config {
+        Config {
+            contracts: Default::default(),
+            url: "http://localhost:8545".to_string(),
+            ws_url: None,
+            ipc_path: None,
+            expected_chain_id: None,
+            http: Default::default(),
+            ws: Default::default(),
+            wallet,
+        }
+    }
+
+    #[tokio::test]
+    async fn from_config_test() {
+        let stack = FactoryStack::from_config(&config(None)).await.unwrap();
+        let m = stack.new_middleware(None).await.unwrap();
+        assert_eq!(m.middleware.default_sender(), None);
+        assert!(m
+            .middleware
+            .downcast::<Arc<Provider<DynTransport>>>()
+            .is_some());
+
+        let m_same = stack.new_middleware(None).await.unwrap();
+        assert!(Arc::ptr_eq(&m.middleware, &m_same.middleware));
+        assert!(stack.middleware_eq(&m).await);
+
+        let m2 = stack.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+        assert!(!stack.middleware_eq(&m).await);
+    }
+
+    #[tokio::test]
+    async fn layers_test() {
+        let wallet: LocalWallet =
+            "380eb0f3d505f087e438eca80bc4df9a7faa24f868e69fc0440261a0fc0567dc"
+                .parse()
+                .unwrap();
+        let address = wallet.address();
+        let sink = Arc::new(PrometheusSink::new("offchain"));
+
+        let stack = FactoryStack::builder(&config(Some(wallet)))
+            .metrics(sink.clone())
+            .rate_limit(RateLimiter::new(100, Duration::from_secs(1)))
+            .nonce_manager()
+            .cache(CacheConfig::default())
+            .layer(move |stack| async move {
+                let m = stack.new_middleware(None).await?;
+                assert_eq!(m.middleware.default_sender(), Some(address));
+                Ok(stack)
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let m = stack.new_middleware(None).await.unwrap();
+        let m2 = stack.new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+
+        // Rebuilding reaches the root provider.
+        let root = |m: &DynMiddleware| m.provider() as *const _;
+        assert_ne!(root(&m.middleware), root(&m2.middleware));
+        assert!(sink.export().contains("offchain_factory_rebuilds_total 1"));
+    }
+
+    #[tokio::test]
+    async fn subscriptions_test() {
+        let res = FactoryStack::builder(&config(None))
+            .subscriptions()
+            .build()
+            .await;
+        assert!(matches!(res, Err(Error::NoPubsubEndpoints {})));
+
+        // Subscription endpoints don't fail over to HTTP.
+        let mut config = config(None);
+        config.ws_url = Some("ws://localhost:8546".to_string());
+        let builder = FactoryStack::builder(&config).subscriptions();
+        assert_eq!(builder.pubsub_endpoints, ["ws://localhost:8546"]);
+    }
+
+    #[tokio::test]
+    async fn forwarding_test() {
+        tokio::time::pause();
+        let (provider, mock) = Provider::mocked();
+        let limiter = Arc::new(RateLimiter::new(1, Duration::from_secs(1)));
+        let m = DynMiddleware::new(
+            RateLimitMiddleware::new(provider, limiter),
+            |_| ErrorClass::Permanent,
+        );
+
+        // Requests go through the layers of the stack, so the second one is
+        // held back by the rate limit. Responses are popped from the back.
+        let hash = H256::from_low_u64_be(1);
+        mock.push::<Vec<H256>, _>(vec![hash]).unwrap();
+        mock.push::<&str, _>("v1").unwrap();
+        let start = tokio::time::Instant::now();
+        assert_eq!(m.client_version().await.unwrap(), "v1");
+        let changes: Vec<H256> = m.get_filter_changes(1).await.unwrap();
+        assert_eq!(changes, [hash]);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn erase_test() {
+        let wallet: LocalWallet =
+            "380eb0f3d505f087e438eca80bc4df9a7faa24f868e69fc0440261a0fc0567dc"
+                .parse()
+                .unwrap();
+        let address = wallet.address();
+        let root =
+            HttpProviderFactory::new("http://localhost:8545".to_string())
+                .unwrap();
+
+        // Stacks with different layers, held as the same type.
+        let signer = LocalSignerFactory::new(Arc::clone(&root), wallet)
+            .await
+            .unwrap();
+        let stacks: Vec<Arc<FactoryStack<Http>>> = vec![
+            FactoryStack::erase(Arc::clone(&root)),
+            FactoryStack::erase(signer),
+        ];
+
+        let m = stacks[0].new_middleware(None).await.unwrap();
+        assert_eq!(m.middleware.default_sender(), None);
+
+        let m = stacks[1].new_middleware(None).await.unwrap();
+        assert_eq!(m.middleware.default_sender(), Some(address));
+
+        let m2 = stacks[1].new_middleware(Some(&m)).await.unwrap();
+        assert!(!Arc::ptr_eq(&m.middleware, &m2.middleware));
+        assert_eq!(root.generation().current(), 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! Stand-in JSON-RPC server over HTTP, shared by the tests of this crate.
+
+use serde_json::{json, Value};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Request received by the server.
+pub struct Request {
+    /// Raw header lines, lowercased.
+    pub headers: String,
+    pub body: Value,
+}
+
+impl Request {
+    pub fn method(&self) -> &str {
+        self.body["method"].as_str().unwrap_or_default()
+    }
+
+    pub fn params(&self) -> &Value {
+        &self.body["params"]
+    }
+
+    /// Successful response to this request.
+    pub fn result(&self, result: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": self.body["id"], "result": result })
+    }
+
+    /// Error response to this request.
+    pub fn error(&self, code: i64, message: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": self.body["id"],
+            "error": { "code": code, "message": message },
+        })
+    }
+}
+
+/// Serves JSON-RPC over HTTP on a local port, answering each request with
+/// the response `handler` returns, or closing the connection on `None`.
+/// Returns the url of the server.
+pub async fn serve<F, Fut>(handler: F) -> String
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<Value>> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(handle(stream, Arc::clone(&handler)));
+        }
+    });
+
+    url
+}
+
+async fn handle<F, Fut>(mut stream: TcpStream, handler: Arc<F>)
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Option<Value>>,
+{
+    let mut buffer = Vec::new();
+
+    loop {
+        let header_end = loop {
+            if let Some(i) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            if !read(&mut stream, &mut buffer).await {
+                return;
+            }
+        };
+
+        let headers =
+            String::from_utf8_lossy(&buffer[..header_end]).to_lowercase();
+        let length: usize = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map(|length| length.trim().parse().unwrap())
+            .unwrap_or(0);
+        while buffer.len() < header_end + length {
+            if !read(&mut stream, &mut buffer).await {
+                return;
+            }
+        }
+
+        let body =
+            serde_json::from_slice(&buffer[header_end..header_end + length])
+                .unwrap();
+        buffer.drain(..header_end + length);
+
+        let body = match handler(Request { headers, body }).await {
+            Some(response) => response.to_string(),
+            None => return,
+        };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads more of the stream into `buffer`, returning false once it closes.
+async fn read(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> bool {
+    let mut chunk = [0u8; 1024];
+    match stream.read(&mut chunk).await {
+        Ok(0) | Err(_) => false,
+        Ok(n) => {
+            buffer.extend_from_slice(&chunk[..n]);
+            true
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::failover::Connect;
+use crate::http::HttpTransport;
+#[cfg(unix)]
+use crate::ProviderError;
+use crate::Result;
+#[cfg(not(unix))]
+use crate::UnsupportedEndpoint;
+
+use async_trait::async_trait;
+use configuration::{HttpOptions, WsOptions};
+use futures_util::stream::BoxStream;
+#[cfg(unix)]
+use offchain_core::ethers::providers::Ipc;
+use offchain_core::ethers::providers::{
+    self, JsonRpcClient, Provider, PubsubClient, Ws,
+};
+use offchain_core::ethers::types::U256;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+#[cfg(unix)]
+use snafu::ResultExt;
+use std::fmt::Debug;
+
+/// Transport chosen at runtime, so stacks over HTTP, websockets and IPC share
+/// a single type. IPC is only available on unix.
+#[derive(Clone, Debug)]
+pub enum DynTransport {
+    Http(HttpTransport),
+    Ws(Ws),
+    #[cfg(unix)]
+    Ipc(Ipc),
+}
+
+/// Options of each kind of transport a `DynTransport` may connect with.
+#[derive(Clone, Debug, Default)]
+pub struct TransportOptions {
+    pub http: HttpOptions,
+    pub ws: WsOptions,
+}
+
+/// Picks the transport from the url scheme, taking urls without one as IPC
+/// socket paths.
+#[async_trait]
+impl Connect for DynTransport {
+    type Options = TransportOptions;
+
+    async fn connect(
+        url: &str,
+        options: &TransportOptions,
+    ) -> Result<Provider<Self>> {
+        let transport = if url.starts_with("ws://") || url.starts_with("wss://")
+        {
+            DynTransport::Ws(crate::ws::connect(url, &options.ws).await?)
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            DynTransport::Http(HttpTransport::new(url, &options.http)?)
+        } else {
+            connect_ipc(url).await?
+        };
+
+        Ok(Provider::new(transport))
+    }
+}
+
+#[cfg(unix)]
+async fn connect_ipc(path: &str) -> Result<DynTransport> {
+    let ipc = Ipc::connect(path)
+        .await
+        .map_err(Into::into)
+        .context(ProviderError)?;
+    Ok(DynTransport::Ipc(ipc))
+}
+
+#[cfg(not(unix))]
+async fn connect_ipc(path: &str) -> Result<DynTransport> {
+    UnsupportedEndpoint { url: path }.fail()
+}
+
+#[async_trait]
+impl JsonRpcClient for DynTransport {
+    type Error = providers::ProviderError;
+
+    async fn request<T, R>(
+        &self,
+        method: &str,
+        params: T,
+    ) -> std::result::Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: Serialize + DeserializeOwned,
+    {
+        match self {
+            DynTransport::Http(http) => {
+                http.request(method, params).await.map_err(Into::into)
+            }
+            DynTransport::Ws(ws) => {
+                ws.request(method, params).await.map_err(Into::into)
+            }
+            #[cfg(unix)]
+            DynTransport::Ipc(ipc) => {
+                ipc.request(method, params).await.map_err(Into::into)
+            }
+        }
+    }
+}
+
+impl PubsubClient for DynTransport {
+    type NotificationStream = BoxStream<'static, Value>;
+
+    fn subscribe<T: Into<U256>>(
+        &self,
+        id: T,
+    ) -> std::result::Result<Self::NotificationStream, Self::Error> {
+        match self {
+            DynTransport::Http(_) => Err(pubsub_unsupported()),
+            DynTransport::Ws(ws) => Ok(Box::pin(
+                ws.subscribe(id).map_err(Into::<Self::Error>::into)?,
+            )),
+            #[cfg(unix)]
+            DynTransport::Ipc(ipc) => Ok(Box::pin(
+                ipc.subscribe(id).map_err(Into::<Self::Error>::into)?,
+            )),
+        }
+    }
+
+    fn unsubscribe<T: Into<U256>>(
+        &self,
+        id: T,
+    ) -> std::result::Result<(), Self::Error> {
+        match self {
+            DynTransport::Http(_) => Err(pubsub_unsupported()),
+            DynTransport::Ws(ws) => ws.unsubscribe(id).map_err(Into::into),
+            #[cfg(unix)]
+            DynTransport::Ipc(ipc) => ipc.unsubscribe(id).map_err(Into::into),
+        }
+    }
+}
+
+fn pubsub_unsupported() -> providers::ProviderError {
+    providers::ProviderError::CustomError(
+        "HTTP transports don't support subscriptions".to_string(),
+    )
+}
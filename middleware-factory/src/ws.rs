@@ -0,0 +1,273 @@
+use crate::auth::authorization;
+use crate::{AuthenticationError, Error, Result, TlsConfigError, WsError};
+
+use configuration::{TlsOptions, WsOptions};
+use offchain_core::ethers::providers::Ws;
+use rustls::internal::pemfile;
+use rustls::{Certificate, ClientConfig, PrivateKey};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::http::header::{
+    HeaderName, HeaderValue, AUTHORIZATION,
+};
+use tokio_tungstenite::tungstenite::{
+    self, client::IntoClientRequest, error::UrlError,
+};
+use tokio_tungstenite::{client_async_tls_with_config, Connector};
+
+/// Connects to the websocket endpoint at `url`, sending the headers and
+/// credentials of `options` with the handshake. Credentials and certificates
+/// are read again on every call, so reconnections pick up rotated ones.
+pub async fn connect(url: &str, options: &WsOptions) -> Result<Ws> {
+    let mut request = url
+        .into_client_request()
+        .map_err(Box::new)
+        .context(WsError)?;
+
+    let headers = request.headers_mut();
+    for (name, value) in &options.headers {
+        let invalid = || Error::InvalidHeader { name: name.clone() };
+        let name =
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+        let value = HeaderValue::from_str(value).map_err(|_| invalid())?;
+        headers.insert(name, value);
+    }
+    if let Some(auth) = &options.auth {
+        let value = authorization(auth).context(AuthenticationError)?;
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&value)
+                .expect("authorization is a valid header value"),
+        );
+    }
+
+    let uri = request.uri();
+    let host = match uri.host() {
+        Some(host) => host.to_string(),
+        None => {
+            let error = tungstenite::Error::Url(UrlError::NoHostName);
+            return Err(Box::new(error)).context(WsError);
+        }
+    };
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("wss") => 443,
+        _ => 80,
+    });
+    let stream = TcpStream::connect((host.as_str(), port))
+        .await
+        .map_err(|e| Box::new(e.into()))
+        .context(WsError)?;
+
+    let config = tls_config(&options.tls)?;
+    let (ws, _) = client_async_tls_with_config(
+        request,
+        stream,
+        None,
+        Some(Connector::Rustls(Arc::new(config))),
+    )
+    .await
+    .map_err(Box::new)
+    .context(WsError)?;
+
+    Ok(Ws::new(ws))
+}
+
+fn tls_config(options: &TlsOptions) -> Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+    for path in &options.ca_certs {
+        for cert in read_certs(path)? {
+            config.root_store.add(&cert).map_err(|e| {
+                Error::TlsConfigError {
+                    reason: format!("Invalid CA certificate {}: {}", path, e),
+                }
+            })?;
+        }
+    }
+
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert), Some(key)) => {
+            config
+                .set_single_client_cert(read_certs(cert)?, read_key(key)?)
+                .map_err(|e| Error::TlsConfigError {
+                    reason: format!("Invalid client certificate: {}", e),
+                })?;
+        }
+        (None, None) => {}
+        _ => {
+            return TlsConfigError {
+                reason: "Client certificate and key must be set together",
+            }
+            .fail()
+        }
+    }
+
+    Ok(config)
+}
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    let file = File::open(path).map_err(|e| Error::TlsConfigError {
+        reason: format!("Failed to open {}: {}", path, e),
+    })?;
+    Ok(BufReader::new(file))
+}
+
+fn invalid_pem(path: &str) -> Error {
+    Error::TlsConfigError {
+        reason: format!("Invalid PEM in {}", path),
+    }
+}
+
+fn read_certs(path: &str) -> Result<Vec<Certificate>> {
+    let certs =
+        pemfile::certs(&mut open(path)?).map_err(|()| invalid_pem(path))?;
+    ensure!(
+        !certs.is_empty(),
+        TlsConfigError {
+            reason: format!("No certificates in {}", path),
+        }
+    );
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?)
+        .map_err(|()| invalid_pem(path))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?)
+            .map_err(|()| invalid_pem(path))?;
+    }
+
+    keys.pop().context(TlsConfigError {
+        reason: format!("No private key in {}", path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MiddlewareFactory, WsProviderFactory};
+    use configuration::HttpAuth;
+    use futures_util::{SinkExt, StreamExt};
+    use offchain_core::ethers::providers::Middleware;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::handshake::server::{
+        Request, Response,
+    };
+    use tokio_tungstenite::tungstenite::Message;
+
+    /// Stand-in node, sending the headers of every handshake to `headers`.
+    // The handshake callback returns tungstenite's error response.
+    #[allow(clippy::result_large_err)]
+    async fn serve(headers: mpsc::UnboundedSender<(String, String)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let headers = headers.clone();
+                let callback = move |request: &Request, response: Response| {
+                    let header = |name| {
+                        request.headers()[name].to_str().unwrap().to_string()
+                    };
+                    headers
+                        .send((header("authorization"), header("x-client")))
+                        .unwrap();
+                    Ok(response)
+                };
+                let mut ws =
+                    tokio_tungstenite::accept_hdr_async(stream, callback)
+                        .await
+                        .unwrap();
+
+                tokio::spawn(async move {
+                    while let Some(Ok(Message::Text(text))) = ws.next().await {
+                        let request: Value =
+                            serde_json::from_str(&text).unwrap();
+                        let response = json!({
+                            "jsonrpc": "2.0",
+                            "id": request["id"],
+                            "result": "0x2a",
+                        });
+                        ws.send(Message::Text(response.to_string()))
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn ws_options_test() {
+        let (sender, mut headers) = mpsc::unbounded_channel();
+        let url = serve(sender).await;
+
+        let secret_path = std::env::temp_dir()
+            .join(format!("ws-options-test-{}.hex", std::process::id()));
+        std::fs::write(&secret_path, hex::encode([7u8; 32])).unwrap();
+
+        let options = WsOptions {
+            headers: vec![("x-client".to_string(), "offchain".to_string())]
+                .into_iter()
+                .collect(),
+            auth: Some(HttpAuth::Jwt {
+                secret_path: secret_path.to_str().unwrap().to_string(),
+            }),
+            ..Default::default()
+        };
+        let factory = WsProviderFactory::new_with_options(
+            url,
+            options,
+            None,
+            0,
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+
+        let m = factory.new_middleware(None).await.unwrap();
+        assert_eq!(m.middleware.get_block_number().await.unwrap(), 42.into());
+
+        // Reconnections send the headers and a freshly issued token again.
+        factory.new_middleware(Some(&m)).await.unwrap();
+        for _ in 0..2 {
+            let (authorization, client) = headers.recv().await.unwrap();
+            assert!(authorization.starts_with("Bearer ey"));
+            assert_eq!(client, "offchain");
+        }
+
+        std::fs::remove_file(secret_path).unwrap();
+    }
+
+    #[test]
+    fn invalid_pem_test() {
+        let path = std::env::temp_dir()
+            .join(format!("invalid-pem-test-{}.pem", std::process::id()));
+        std::fs::write(
+            &path,
+            "-----BEGIN CERTIFICATE-----\n!!!\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+
+        let path = path.to_str().unwrap();
+        assert!(matches!(
+            read_certs(path),
+            Err(Error::TlsConfigError { reason })
+                if reason.starts_with("Invalid")
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use ethers::abi::ethereum_types::BloomInput;
+use ethers::contract::EthEvent;
+use ethers::types::{Address, Bloom, Filter, ValueOrArray, H256};
+use serde::Deserialize;
+
+/// Set of log filters checked against a block's logs bloom. A block matches
+/// the spec if it may contain logs matching any of its filters, so blocks that
+/// don't match can skip `eth_getLogs` altogether. An empty spec matches no
+/// block.
+///
+/// # Examples
+/// ```
+/// # use offchain_core::bloom::BloomFilterSpec;
+/// # use offchain_core::ethers::types::{
+/// #     Address, Bloom, Filter, ValueOrArray, H256,
+/// # };
+/// let address = Address::from_low_u64_be(1);
+/// let spec = BloomFilterSpec::new()
+///     .with_filter(&Filter::new().address(ValueOrArray::Value(address)))
+///     .with_event_signature(None, H256::from_low_u64_be(2));
+///
+/// assert!(!spec.may_match(&Bloom::zero()));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct BloomFilterSpec {
+    filters: Vec<BloomFilter>,
+}
+
+/// `ValueOrArray<Address>` as `Filter` serializes it, since ethers only
+/// implements `Serialize` for it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FilterAddress {
+    Value(Address),
+    Array(Vec<Address>),
+}
+
+impl From<FilterAddress> for ValueOrArray<Address> {
+    fn from(address: FilterAddress) -> Self {
+        match address {
+            FilterAddress::Value(address) => ValueOrArray::Value(address),
+            FilterAddress::Array(addresses) => ValueOrArray::Array(addresses),
+        }
+    }
+}
+
+/// Addresses and topics of a single filter. Empty lists are wildcards, and
+/// non-empty ones match if any of their values is in the bloom.
+#[derive(Clone, Debug, Default)]
+struct BloomFilter {
+    addresses: Vec<Address>,
+    topics: [Vec<H256>; 4],
+}
+
+impl BloomFilterSpec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the addresses and topics of an ethers `Filter`. Block options are
+    /// ignored.
+    pub fn with_filter(mut self, filter: &Filter) -> Self {
+        // `Filter` doesn't expose its address, only its serialization does.
+        let address: Option<FilterAddress> = serde_json::to_value(filter)
+            .and_then(|mut filter| {
+                serde_json::from_value(filter["address"].take())
+            })
+            .expect("filters serialize their address as a value or array");
+        let addresses = address
+            .map(|address| flatten(address.into()))
+            .unwrap_or_default();
+
+        let mut topics: [Vec<H256>; 4] = Default::default();
+        for (topic, values) in filter.topics.iter().zip(topics.iter_mut()) {
+            *values = topic.clone().map(flatten).unwrap_or_default();
+        }
+
+        self.filters.push(BloomFilter { addresses, topics });
+        self
+    }
+
+    /// Adds an event of generated contract bindings, optionally restricted to
+    /// a contract address.
+    pub fn with_event<E: EthEvent>(self, address: Option<Address>) -> Self {
+        self.with_event_signature(address, E::signature())
+    }
+
+    /// Adds an event by its signature (the first topic of its logs),
+    /// optionally restricted to a contract address.
+    pub fn with_event_signature(
+        mut self,
+        address: Option<Address>,
+        signature: H256,
+    ) -> Self {
+        let mut topics: [Vec<H256>; 4] = Default::default();
+        topics[0] = vec![signature];
+
+        self.filters.push(BloomFilter {
+            addresses: address.into_iter().collect(),
+            topics,
+        });
+        self
+    }
+
+    /// Returns false if the bloom certainly has no log matching the spec.
+    pub fn may_match(&self, bloom: &Bloom) -> bool {
+        self.filters.iter().any(|filter| filter.may_match(bloom))
+    }
+}
+
+impl BloomFilter {
+    fn may_match(&self, bloom: &Bloom) -> bool {
+        any_or_empty(bloom, &self.addresses, |a| a.as_bytes())
+            && self
+                .topics
+                .iter()
+                .all(|topic| any_or_empty(bloom, topic, |t| t.as_bytes()))
+    }
+}
+
+/// Returns false if the bloom certainly has no log emitted by `address` with
+/// all of the `topics`.
+pub fn may_contain(bloom: &Bloom, address: &Address, topics: &[H256]) -> bool {
+    bloom.contains_input(BloomInput::Raw(address.as_bytes()))
+        && topics.iter().all(|topic| {
+            bloom.contains_input(BloomInput::Raw(topic.as_bytes()))
+        })
+}
+
+fn any_or_empty<T>(
+    bloom: &Bloom,
+    values: &[T],
+    bytes: fn(&T) -> &[u8],
+) -> bool {
+    values.is_empty()
+        || values
+            .iter()
+            .any(|value| bloom.contains_input(BloomInput::Raw(bytes(value))))
+}
+
+fn flatten<T>(value: ValueOrArray<T>) -> Vec<T> {
+    match value {
+        ValueOrArray::Value(value) => vec![value],
+        ValueOrArray::Array(values) => values,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_bloom(address: &Address, topics: &[H256]) -> Bloom {
+        let mut bloom = Bloom::zero();
+        bloom.accrue(BloomInput::Raw(address.as_bytes()));
+        for topic in topics {
+            bloom.accrue(BloomInput::Raw(topic.as_bytes()));
+        }
+        bloom
+    }
+
+    #[test]
+    fn may_contain_test() {
+        let address = Address::from_low_u64_be(1);
+        let topic = H256::from_low_u64_be(2);
+        let bloom = new_bloom(&address, &[topic]);
+
+        assert!(may_contain(&bloom, &address, &[topic]));
+        assert!(may_contain(&bloom, &address, &[]));
+        assert!(!may_contain(&bloom, &Address::from_low_u64_be(3), &[topic]));
+        assert!(!may_contain(&bloom, &address, &[H256::from_low_u64_be(3)]));
+    }
+
+    #[test]
+    fn spec_test() {
+        let address = Address::from_low_u64_be(1);
+        let other = Address::from_low_u64_be(3);
+        let topic = H256::from_low_u64_be(2);
+        let bloom = new_bloom(&address, &[topic]);
+
+        assert!(!BloomFilterSpec::new().may_match(&bloom));
+
+        let filter = Filter::new()
+            .address(ValueOrArray::Array(vec![other, address]))
+            .topic0(topic);
+        assert!(BloomFilterSpec::new()
+            .with_filter(&filter)
+            .may_match(&bloom));
+
+        let filter = Filter::new()
+            .address(ValueOrArray::Value(other))
+            .topic0(topic);
+        assert!(!BloomFilterSpec::new()
+            .with_filter(&filter)
+            .may_match(&bloom));
+
+        // Filters without addresses match any of them.
+        let filter = Filter::new().topic0(topic);
+        assert!(BloomFilterSpec::new()
+            .with_filter(&filter)
+            .may_match(&bloom));
+
+        let spec = BloomFilterSpec::new()
+            .with_event_signature(Some(other), topic)
+            .with_event_signature(None, topic);
+        assert!(spec.may_match(&bloom));
+    }
+}
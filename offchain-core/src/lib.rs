@@ -1,3 +1,4 @@
+pub mod bloom;
 pub mod contract;
 
 pub use ethabi;
@@ -27,4 +28,21 @@ pub mod types {
             })
         }
     }
+
+    impl Block {
+        /// Returns false if the block certainly has no log emitted by
+        /// `address` with all of the `topics`.
+        pub fn may_contain(
+            &self,
+            address: &ethers::types::Address,
+            topics: &[ethers::types::H256],
+        ) -> bool {
+            crate::bloom::may_contain(&self.logs_bloom, address, topics)
+        }
+
+        /// Returns false if the block certainly has no log matching `spec`.
+        pub fn may_match(&self, spec: &crate::bloom::BloomFilterSpec) -> bool {
+            spec.may_match(&self.logs_bloom)
+        }
+    }
 }